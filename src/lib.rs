@@ -1,7 +1,11 @@
 mod repos;
 
 pub use repos::{setup, roll_back};
-pub use repos::install;
+pub use repos::{install, upgrade, ConfChoice, ScriptMode};
 pub use repos::{update, clear};
 pub use repos::{list_installed, search};
-pub use repos::remove;
\ No newline at end of file
+pub use repos::remove;
+pub use repos::{files, owner, policy};
+pub use repos::recover;
+pub use repos::{alternatives, divert, AlternativesAction, DivertAction};
+pub use repos::lock::{Lock, Wait};
\ No newline at end of file
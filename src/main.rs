@@ -1,5 +1,81 @@
-use clap::{Arg, App, SubCommand, AppSettings};
-use std::process;
+use clap::{Arg, ArgMatches, App, SubCommand, AppSettings};
+use std::{path::PathBuf, process, time::Duration};
+use opm::{AlternativesAction, ConfChoice, DivertAction, Lock, ScriptMode, Wait};
+
+///
+/// How conffiles changed both locally and by the package get settled, prompting if none given
+///
+fn conf_args() -> Vec<Arg<'static, 'static>> {
+	vec![
+		Arg::with_name("conf-new")
+			.long("conf-new")
+			.conflicts_with_all(&["conf-old", "conf-def"])
+			.help("Always install the package's version of a modified conffile, keeping yours as .dpkg-old"),
+		Arg::with_name("conf-old")
+			.long("conf-old")
+			.conflicts_with("conf-def")
+			.help("Always keep your version of a modified conffile, putting the package's one at .dpkg-dist"),
+		Arg::with_name("conf-def")
+			.long("conf-def")
+			.help("Take the default answer instead of asking about modified conffiles"),
+	]
+}
+
+fn conf_choice(matches: &ArgMatches) -> ConfChoice {
+	match (matches.is_present("conf-new"), matches.is_present("conf-old"), matches.is_present("conf-def")) {
+		(true, _, _) => ConfChoice::New,
+		(_, true, _) => ConfChoice::Old,
+		(_, _, true) => ConfChoice::Default,
+		_ => ConfChoice::Ask,
+	}
+}
+
+fn arg(name: &'static str, index: u64) -> Arg<'static, 'static> {
+	Arg::with_name(name)
+		.takes_value(true)
+		.index(index)
+		.required(true)
+}
+
+fn alternatives_action(matches: &ArgMatches) -> AlternativesAction {
+	let value = |m: &ArgMatches, name: &str| m.value_of(name).unwrap().to_owned();
+
+	match matches.subcommand() {
+		("install", Some(m)) => AlternativesAction::Install {
+			link: PathBuf::from(value(m, "link")),
+			name: value(m, "name"),
+			path: PathBuf::from(value(m, "path")),
+			priority: value(m, "priority").parse::<i32>().unwrap_or_else(|_| {
+				eprintln!("The priority has to be a number");
+				process::exit(1);
+			}),
+			slaves: m.values_of("slave")
+				.map(|values| values.collect::<Vec<_>>()
+					.chunks(3)
+					.map(|slave| (PathBuf::from(slave[0]), slave[1].to_owned(), PathBuf::from(slave[2])))
+					.collect())
+				.unwrap_or_default(),
+		},
+		("remove", Some(m)) => AlternativesAction::Remove { name: value(m, "name"), path: PathBuf::from(value(m, "path")) },
+		("auto", Some(m)) => AlternativesAction::Auto(value(m, "name")),
+		("set", Some(m)) => AlternativesAction::Set { name: value(m, "name"), path: PathBuf::from(value(m, "path")) },
+		("display", Some(m)) => AlternativesAction::Display(value(m, "name")),
+		_ => AlternativesAction::List,
+	}
+}
+
+fn divert_action(matches: &ArgMatches) -> DivertAction {
+	match matches.subcommand() {
+		("add", Some(m)) => DivertAction::Add {
+			from: PathBuf::from(m.value_of("file").unwrap()),
+			to: m.value_of("divert").map(PathBuf::from),
+			package: m.value_of("package").map(str::to_owned),
+			rename: m.is_present("rename"),
+		},
+		("remove", Some(m)) => DivertAction::Remove { from: PathBuf::from(m.value_of("file").unwrap()), rename: m.is_present("rename") },
+		_ => DivertAction::List,
+	}
+}
 
 fn main() {
 	let mut config = opm::setup().unwrap_or_else(|err| {
@@ -20,6 +96,30 @@ fn main() {
 					.takes_value(false)
 					.help("List all installed packages")
 				)
+				.arg(Arg::with_name("root")
+					.long("root")
+					.value_name("dir")
+					.takes_value(true)
+					.global(true)
+					.help("Install packages under <dir> instead of the configured root")
+				)
+				.arg(Arg::with_name("scripts")
+					.long("scripts")
+					.value_name("mode")
+					.takes_value(true)
+					.possible_values(&["run", "confirm", "sandbox"])
+					.global(true)
+					.help("Run maintainer scripts as they are, show them asking first, or sandboxed")
+				)
+				.arg(Arg::with_name("wait-lock")
+					.long("wait-lock")
+					.value_name("secs")
+					.takes_value(true)
+					.min_values(0)
+					.require_equals(true)
+					.global(true)
+					.help("Wait for other package managers to finish (at most <secs> if given) instead of failing")
+				)
 				.subcommands( vec![
 					SubCommand::with_name("install")
 						.about("Install a package")
@@ -31,9 +131,20 @@ fn main() {
 							.short("f")
 							.long("force")
 							.takes_value(false)
-							.help("Force the installation of pacakages that may can break others")),
+							.help("Force the installation of pacakages that may can break others"))
+						.args(&conf_args()),
+					SubCommand::with_name("upgrade")
+						.about("Upgrade every installed package, never removing any")
+						.args(&conf_args()),
+					SubCommand::with_name("full-upgrade")
+						.about("Upgrade every installed package, removing or replacing others if needed")
+						.args(&conf_args()),
 					SubCommand::with_name("update")
-						.about("Update opm's packages cache"),
+						.about("Update opm's packages cache")
+						.arg(Arg::with_name("allow-insecure")
+							.long("allow-insecure")
+							.takes_value(false)
+							.help("Skip checking the repositories signatures and indices hashes")),
 					SubCommand::with_name("remove")
 						.about("Remove a package")
 						.arg(Arg::with_name("package")
@@ -53,10 +164,130 @@ fn main() {
 							.index(1)
 							.required(true)),
 					SubCommand::with_name("clear")
-						.about("Clear OPM's cache")
+						.about("Clear OPM's cache"),
+					SubCommand::with_name("recover")
+						.about("Undo an install/remove that was interrupted"),
+					SubCommand::with_name("files")
+						.about("List the files installed by a package")
+						.arg(Arg::with_name("package")
+							.takes_value(true)
+							.index(1)
+							.required(true)),
+					SubCommand::with_name("owner")
+						.about("Find out which package installed a file")
+						.arg(Arg::with_name("path")
+							.takes_value(true)
+							.index(1)
+							.required(true)),
+					SubCommand::with_name("policy")
+						.about("Show the priority of every version of a package and which one is the candidate")
+						.arg(Arg::with_name("package")
+							.takes_value(true)
+							.index(1)
+							.required(true)),
+					SubCommand::with_name("alternatives")
+						.about("Manage the /etc/alternatives link groups, as update-alternatives does")
+						.setting(AppSettings::SubcommandRequiredElseHelp)
+						.subcommands(vec![
+							SubCommand::with_name("install")
+								.about("Add <path> as an alternative for <name>, linked from <link>")
+								.args(&[arg("link", 1), arg("name", 2), arg("path", 3), arg("priority", 4)])
+								.arg(Arg::with_name("slave")
+									.long("slave")
+									.value_names(&["link", "name", "path"])
+									.number_of_values(3)
+									.multiple(true)
+									.help("A link that follows the master one, e.g.: its manpage")),
+							SubCommand::with_name("remove")
+								.about("Remove <path> from the alternatives for <name>")
+								.args(&[arg("name", 1), arg("path", 2)]),
+							SubCommand::with_name("auto")
+								.about("Let <name> follow the alternative with the highest priority")
+								.arg(arg("name", 1)),
+							SubCommand::with_name("set")
+								.about("Pick <path> for <name> by hand")
+								.args(&[arg("name", 1), arg("path", 2)]),
+							SubCommand::with_name("display")
+								.about("Show the alternatives for <name> and which one is in use")
+								.arg(arg("name", 1)),
+							SubCommand::with_name("list")
+								.about("List every link group"),
+						]),
+					SubCommand::with_name("divert")
+						.about("Manage file diversions, as dpkg-divert does")
+						.setting(AppSettings::SubcommandRequiredElseHelp)
+						.subcommands(vec![
+							SubCommand::with_name("add")
+								.about("Divert <file> so packages installing it put it somewhere else")
+								.arg(arg("file", 1))
+								.arg(Arg::with_name("divert")
+									.long("divert")
+									.value_name("to")
+									.takes_value(true)
+									.help("Where it goes, <file>.distrib by default"))
+								.arg(Arg::with_name("package")
+									.long("package")
+									.value_name("pkg")
+									.takes_value(true)
+									.help("The package still allowed to install it at <file>, none for a local diversion"))
+								.arg(Arg::with_name("rename")
+									.long("rename")
+									.help("Move the file already there along")),
+							SubCommand::with_name("remove")
+								.about("Stop diverting <file>")
+								.arg(arg("file", 1))
+								.arg(Arg::with_name("rename")
+									.long("rename")
+									.help("Move the diverted file back")),
+							SubCommand::with_name("list")
+								.about("List every diversion"),
+						])
 				])
 				.get_matches();
 
+	// Global args only propagate down, so `opm install --root <dir>` lands on the subcommand
+	let root = matches.value_of("root")
+		.or_else(|| matches.subcommand().1.and_then(|sub| sub.value_of("root")));
+
+	if let Some(root) = root {
+		config.root = root.to_owned();
+	}
+
+	let scripts = matches.value_of("scripts")
+		.or_else(|| matches.subcommand().1.and_then(|sub| sub.value_of("scripts")));
+
+	if let Some(scripts) = scripts {
+		config.scripts = scripts.parse::<ScriptMode>().unwrap();
+	}
+
+	let wait = match [Some(&matches), matches.subcommand().1].into_iter().flatten().find(|m| m.is_present("wait-lock")) {
+		Some(m) => match m.value_of("wait-lock").map(|secs| secs.parse::<u64>()) {
+			Some(Ok(secs)) => Wait::For(Duration::from_secs(secs)),
+			Some(Err(_)) => {
+				eprintln!("--wait-lock takes a number of seconds");
+				process::exit(1);
+			},
+			None => Wait::Forever,
+		},
+		None => Wait::No,
+	};
+
+	// Whatever changes the system holds the lock until it's done
+	let changes = match matches.subcommand() {
+		("install" | "upgrade" | "full-upgrade" | "update" | "remove" | "clear" | "recover", _) => true,
+		("alternatives" | "divert", Some(sub)) => !matches!(sub.subcommand_name(), Some("display" | "list")),
+		_ => false,
+	};
+	let _lock = match changes {
+		true => {
+			Some(Lock::acquire(&config, wait).unwrap_or_else(|err| {
+				eprintln!("LockError :: {}", err);
+				process::exit(1);
+			}))
+		},
+		false => None,
+	};
+
 	match matches.occurrences_of("list") {
 		0 => (),
 		1 => opm::list_installed(&config),
@@ -66,14 +297,21 @@ fn main() {
     if let Some(package) = matches.subcommand_matches("install") {
 		let force = !matches!(matches.occurrences_of("force"), 0);
 
-        opm::install(&mut config, package.value_of("package").unwrap(), force).unwrap_or_else(|err| {
+        opm::install(&mut config, package.value_of("package").unwrap(), force, conf_choice(package)).unwrap_or_else(|err| {
             eprintln!("InstallError :: {}", err);
             process::exit(1);
         });
     }
 
-    if matches.subcommand_matches("update").is_some() {
-        opm::update(&mut config).unwrap_or_else(|err| {
+    if let Some((name, upgrade)) = ["upgrade", "full-upgrade"].into_iter().find_map(|name| matches.subcommand_matches(name).map(|sub| (name, sub))) {
+        opm::upgrade(&mut config, name == "full-upgrade", conf_choice(upgrade)).unwrap_or_else(|err| {
+			eprintln!("UpgradeError :: {}", err);
+			process::exit(1);
+		})
+    }
+
+    if let Some(update) = matches.subcommand_matches("update") {
+        opm::update(&mut config, update.is_present("allow-insecure")).unwrap_or_else(|err| {
 			eprintln!("UpdateError :: {}", err);
 			process::exit(1);
 		})
@@ -110,4 +348,49 @@ fn main() {
 			process::exit(1);
 		});
     };
+
+    if matches.subcommand_matches("recover").is_some() {
+		opm::recover(&config).unwrap_or_else(|err| {
+			eprintln!("Failed to recover due {}", err);
+			process::exit(1);
+		});
+    };
+
+    if let Some(files) = matches.subcommand_matches("files") {
+		let pkg = files.value_of("package").unwrap();
+		opm::files(&config, pkg).unwrap_or_else(|err| {
+			eprintln!("Failed to list the files of {} due {}", pkg, err);
+			process::exit(1);
+		});
+    };
+
+    if let Some(owner) = matches.subcommand_matches("owner") {
+		let path = owner.value_of("path").unwrap();
+		opm::owner(&config, path).unwrap_or_else(|err| {
+			eprintln!("Failed to find the owner of {} due {}", path, err);
+			process::exit(1);
+		});
+    };
+
+    if let Some(policy) = matches.subcommand_matches("policy") {
+		let pkg = policy.value_of("package").unwrap();
+		opm::policy(&config, pkg).unwrap_or_else(|err| {
+			eprintln!("Failed to show the policy of {} due {}", pkg, err);
+			process::exit(1);
+		});
+    };
+
+    if let Some(alternatives) = matches.subcommand_matches("alternatives") {
+		opm::alternatives(&config, alternatives_action(alternatives)).unwrap_or_else(|err| {
+			eprintln!("AlternativesError :: {}", err);
+			process::exit(1);
+		});
+    };
+
+    if let Some(divert) = matches.subcommand_matches("divert") {
+		opm::divert(&config, divert_action(divert)).unwrap_or_else(|err| {
+			eprintln!("DivertError :: {}", err);
+			process::exit(1);
+		});
+    };
 }
@@ -0,0 +1,23 @@
+use anyhow::Result;
+use super::{config::Config, packages::PackageFormat};
+use super::deb::diversions::Action;
+
+pub fn divert(config: &Config, action: Action) -> Result<()> {
+    match config.os_info.default_package_format {
+        PackageFormat::Deb => {
+            use super::deb;
+            deb::diversions::divert(config, action)?;
+        }
+        PackageFormat::Rpm => {
+            println!("It's a RHEL(-based) distro");
+        }
+        PackageFormat::Pkg => {
+            println!("It's a Arch(-based) distro");
+        }
+        PackageFormat::Unknown => {
+            println!("Actually we do not have support for you distro!");
+        }
+    }
+
+    Ok(())
+}
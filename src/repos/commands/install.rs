@@ -1,18 +1,20 @@
 
-///
-/// Generic package install
-/// 
+//
+// Generic package install
+// 
 
 use anyhow::Result;
 use super::packages::PackageFormat;
 use super::config::Config;
+use super::deb::conffiles::ConfChoice;
 
 #[tokio::main]
-pub async fn install(config: &mut Config, name: &str, force: bool) -> Result<()> {
+pub async fn install(config: &mut Config, name: &str, force: bool, choice: ConfChoice) -> Result<()> {
     match config.os_info.default_package_format {
         PackageFormat::Deb => {
             use super::deb;
-            deb::install(config, name, force).await?; 
+            deb::install(config, name, force, choice).await?; 
+            deb::triggers::process(config)?;
         },
         PackageFormat::Rpm => {
             println!("It's a RHEL(-based) distro");
@@ -26,4 +28,25 @@ pub async fn install(config: &mut Config, name: &str, force: bool) -> Result<()>
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+#[tokio::main]
+pub async fn upgrade(config: &mut Config, full: bool, choice: ConfChoice) -> Result<()> {
+    match config.os_info.default_package_format {
+        PackageFormat::Deb => {
+            use super::deb;
+            deb::upgrade(config, full, choice).await?;
+            deb::triggers::process(config)?;
+        },
+        PackageFormat::Rpm => {
+            println!("It's a RHEL(-based) distro");
+        },
+        PackageFormat::Pkg => {
+            println!("It's a Arch(-based) distro");
+        },
+        PackageFormat::Unknown => {
+            println!("Actually we do not have support for you distro!");
+        },
+    }
+
+    Ok(())
+}
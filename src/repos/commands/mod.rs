@@ -1,6 +1,10 @@
 use super::*;
 
+pub mod alternatives;
+pub mod divert;
 pub mod install;
+pub mod query;
+pub mod recover;
 pub mod remove;
 pub mod search;
 pub mod update;
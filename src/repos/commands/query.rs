@@ -0,0 +1,97 @@
+use anyhow::Result;
+use std::path::Path;
+use super::packages::PackageFormat;
+use super::config::Config;
+
+pub fn files(config: &Config, name: &str) -> Result<()> {
+	match config.os_info.default_package_format {
+		PackageFormat::Deb => {
+			use super::deb;
+			deb::files::read(config, name)?
+				.iter()
+				.for_each(|path| println!("{}", path.display()));
+		},
+		PackageFormat::Rpm => {
+			println!("It's a RHEL(-based) distro");
+		},
+		PackageFormat::Pkg => {
+			println!("It's a Arch(-based) distro");
+		},
+		PackageFormat::Unknown => {
+			println!("Actually we do not have support for you distro!");
+		},
+	}
+
+	Ok(())
+}
+
+pub fn owner(config: &Config, path: &str) -> Result<()> {
+	match config.os_info.default_package_format {
+		PackageFormat::Deb => {
+			use super::deb;
+			let owners = deb::files::owners(config, Path::new(path))?;
+			if owners.is_empty() {
+				println!("No package owns {}", path);
+			} else {
+				println!("{}: {}", owners.join(", "), path);
+			}
+		},
+		PackageFormat::Rpm => {
+			println!("It's a RHEL(-based) distro");
+		},
+		PackageFormat::Pkg => {
+			println!("It's a Arch(-based) distro");
+		},
+		PackageFormat::Unknown => {
+			println!("Actually we do not have support for you distro!");
+		},
+	}
+
+	Ok(())
+}
+
+pub fn policy(config: &Config, name: &str) -> Result<()> {
+	match config.os_info.default_package_format {
+		PackageFormat::Deb => {
+			use super::deb;
+			let table = deb::cache::policy(config, name)?;
+			let version = |pkg: Option<&deb::package::ControlFile>| pkg.map_or("(none)", |pkg| pkg.version.as_str()).to_owned();
+
+			println!("{}:", name);
+			println!("  Installed: {}", version(table.installed.as_ref().map(|(pkg, _)| pkg)));
+			println!("  Candidate: {}", version(table.candidate.as_ref()));
+			println!("  Version table:");
+
+			for (pkg, priority) in table.versions.iter() {
+				let current = table.installed.as_ref().is_some_and(|(installed, _)| installed.version == pkg.version);
+				println!(" {} {} {}", if current { "***" } else { "   " }, pkg.version, priority);
+
+				match pkg.origin.as_deref() {
+					Some(origin) => println!("        {} {} {}/{} {}", priority, origin.site, origin.suite, origin.component, pkg.architecture),
+					None => println!("        {} (no release)", priority),
+				}
+
+				if current {
+					println!("        {} installed", table.installed.as_ref().unwrap().1);
+				}
+			}
+
+			// Only installed, no repository has it
+			if let Some((pkg, priority)) = table.installed.as_ref().filter(|(pkg, _)| !table.versions.iter().any(|(v, _)| v.version == pkg.version)) {
+				println!(" *** {} {}", pkg.version, priority);
+				println!("        {} installed", priority);
+			}
+		},
+		PackageFormat::Rpm => {
+			println!("It's a RHEL(-based) distro");
+		},
+		PackageFormat::Pkg => {
+			println!("It's a Arch(-based) distro");
+		},
+		PackageFormat::Unknown => {
+			println!("Actually we do not have support for you distro!");
+		},
+	}
+
+	Ok(())
+}
@@ -0,0 +1,22 @@
+use anyhow::Result;
+use super::{config::Config, packages::PackageFormat};
+
+pub fn recover(config: &Config) -> Result<()> {
+    match config.os_info.default_package_format {
+        PackageFormat::Deb => {
+            use super::deb;
+            deb::recover(config)?;
+        }
+        PackageFormat::Rpm => {
+            println!("It's a RHEL(-based) distro");
+        }
+        PackageFormat::Pkg => {
+            println!("It's a Arch(-based) distro");
+        }
+        PackageFormat::Unknown => {
+            println!("Actually we do not have support for you distro!");
+        }
+    }
+
+    Ok(())
+}
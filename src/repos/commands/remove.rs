@@ -6,6 +6,7 @@ pub fn remove(config: &Config, name: &str, purge: bool) -> Result<()> {
         PackageFormat::Deb => {
             use super::deb;
             deb::remove(config, name, purge)?;
+            deb::triggers::process(config)?;
         }
         PackageFormat::Rpm => {
             println!("It's a RHEL(-based) distro");
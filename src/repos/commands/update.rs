@@ -1,18 +1,18 @@
-///
-/// Generic package update
-/// 
+//
+// Generic package update
+// 
 
 use anyhow::Result;
 use super::packages::PackageFormat;
 use super::config::Config;
 
 #[tokio::main]
-pub async fn update(config: &mut Config) -> Result<()> {
+pub async fn update(config: &mut Config, insecure: bool) -> Result<()> {
     match config.os_info.default_package_format {
         PackageFormat::Deb => {
             use super::deb;
             let repos = deb::sources::DebianSource::new()?;
-            deb::update(config, &repos).await?;
+            deb::update(config, &repos, insecure).await?;
         }
         PackageFormat::Rpm => {
             println!("It's a RHEL(-based) distro");
@@ -3,6 +3,9 @@ use std::io::ErrorKind;
 use std::fs;
 
 use super::os_fingerprint::OsInfo;
+use super::deb::policy::Pin;
+use super::deb::scripts::ScriptMode;
+use super::deb::unpacker::UnpackPolicy;
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Config {
@@ -13,6 +16,18 @@ pub struct Config {
 	pub info: String,
 	pub tmp: String,
 	pub db: String,
+	#[serde(default = "Config::default_root")]
+	pub root: String,
+	#[serde(default = "Config::default_architecture")]
+	pub architecture: String,
+	#[serde(default)]
+	pub foreign_architectures: Vec<String>,
+	#[serde(default)]
+	pub pins: Vec<Pin>,
+	#[serde(default)]
+	pub scripts: ScriptMode,
+	#[serde(default)]
+	pub unpack: UnpackPolicy,
 
 	pub use_pre_existing_cache: bool,
 	pub use_pre_existing_db: bool
@@ -30,12 +45,32 @@ impl Config {
 				archive: dir.join("archive").to_str().unwrap().to_owned(),
 				info: dir.join("info").to_str().unwrap().to_owned(),
 				db: dir.join("db").to_str().unwrap().to_owned(),
+				root: Self::default_root(),
+				architecture: Self::default_architecture(),
+				foreign_architectures: vec![],
+				pins: vec![],
+				scripts: ScriptMode::default(),
+				unpack: UnpackPolicy::default(),
 				use_pre_existing_cache: false,
 				use_pre_existing_db: false,
 			}
 		)
 	}
 
+	///
+	/// Where packages get installed, `/` unless told otherwise
+	///
+	fn default_root() -> String {
+		String::from("/")
+	}
+
+	///
+	/// The architecture opm itself runs on
+	///
+	fn default_architecture() -> String {
+		super::deb::arch::native().to_owned()
+	}
+
 	pub fn from<P: AsRef<std::path::Path>>(file: P) -> Self {
 		let contents = fs::read_to_string(file).unwrap();
 		serde_json::from_str(&contents).unwrap()
@@ -87,7 +122,8 @@ impl Config {
 			}
 		}
 
-		match fs::File::create(&self.db) {
+		// Never truncate a database that's already there
+		match fs::OpenOptions::new().write(true).create_new(true).open(&self.db) {
 			Ok(_) => {
 				if let Some(db) = self.os_info.previous_db.clone() {
 					fs::copy(db, &self.db)?;
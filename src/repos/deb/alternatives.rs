@@ -0,0 +1,511 @@
+use anyhow::Result;
+use std::{fs, io::ErrorKind, os::unix::fs::symlink, path::{Path, PathBuf}};
+
+use crate::repos::{config::Config, errors::AlternativesError};
+
+//
+// Alternatives the `update-alternatives` way: a generic name (e.g.: `/usr/bin/editor`) links to `/etc/alternatives/<name>`,
+// which links to whichever of the installed choices is the best or the one picked by hand.
+// Link groups are kept in dpkg's own format, one file per name
+// https://man7.org/linux/man-pages/man1/update-alternatives.1.html
+//
+
+///
+/// Where the links to the chosen alternatives are, under `config.root`
+///
+const LINKS: &str = "etc/alternatives";
+
+///
+/// The directory holding the link groups, `<info>/alternatives` unless sharing dpkg's
+///
+pub fn admin_dir(config: &Config) -> PathBuf {
+    match config.use_pre_existing_db {
+        true => PathBuf::from(super::database::DEBIAN_ALTERNATIVES),
+        false => Path::new(&config.info).join("alternatives"),
+    }
+}
+
+///
+/// Whether the group follows the best choice or stays with the one picked
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Auto,
+    Manual,
+}
+
+///
+/// A link that follows the master one, e.g.: the manpage of the chosen editor
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Slave {
+    pub name: String,
+    pub link: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Choice {
+    pub path: PathBuf,
+    pub priority: i32,
+    ///
+    /// What each slave of the group points to with this choice, in the same order
+    ///
+    pub slaves: Vec<Option<PathBuf>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    pub name: String,
+    pub mode: Mode,
+    pub link: PathBuf,
+    pub slaves: Vec<Slave>,
+    pub choices: Vec<Choice>,
+}
+
+///
+/// What `opm alternatives` does
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    ///
+    /// `slaves` being `(link, name, path)`
+    ///
+    Install { link: PathBuf, name: String, path: PathBuf, priority: i32, slaves: Vec<(PathBuf, String, PathBuf)> },
+    Remove { name: String, path: PathBuf },
+    Auto ( String ),
+    Set { name: String, path: PathBuf },
+    Display ( String ),
+    List,
+}
+
+///
+/// Names end up as file names under the admin dir and `/etc/alternatives`, so they can't lead anywhere else
+///
+fn valid(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        anyhow::bail!(AlternativesError::InvalidName(name.to_owned()));
+    }
+
+    Ok(())
+}
+
+impl Group {
+    ///
+    /// `<mode>`, `<link>`, `<slave name>`/`<slave link>` pairs up to an empty line,
+    /// then `<path>`, `<priority>` and one line per slave for every choice up to another one
+    ///
+    fn parse(name: &str, file: &Path, contents: &str) -> Result<Self> {
+        let malformed = |why: &str| anyhow::anyhow!(AlternativesError::Malformed { file: file.display().to_string(), why: why.to_owned() });
+        let mut lines = contents.lines();
+        let mut next = |what: &str| lines.next().ok_or_else(|| malformed(&format!("missing {}", what)));
+
+        let mode = match next("mode")? {
+            "auto" => Mode::Auto,
+            "manual" => Mode::Manual,
+            mode => anyhow::bail!(malformed(&format!("unknown mode '{}'", mode))),
+        };
+        let link = PathBuf::from(next("link")?);
+
+        let mut slaves = vec![];
+        loop {
+            match next("slaves")? {
+                "" => break,
+                name => {
+                    valid(name)?;
+                    slaves.push(Slave { name: name.to_owned(), link: PathBuf::from(next("slave link")?) });
+                },
+            }
+        }
+
+        let mut choices = vec![];
+        loop {
+            let path = match next("choices")? {
+                "" => break,
+                path => PathBuf::from(path),
+            };
+            let priority = next("priority")?.parse::<i32>().map_err(|_| malformed("priority is not a number"))?;
+            let slaves = slaves.iter()
+                .map(|_| next("slave path").map(|path| Some(PathBuf::from(path)).filter(|path| !path.as_os_str().is_empty())))
+                .collect::<Result<Vec<_>>>()?;
+
+            choices.push(Choice { path, priority, slaves });
+        }
+
+        Ok(Self { name: name.to_owned(), mode, link, slaves, choices })
+    }
+
+    ///
+    /// The group `name`, if there is one
+    ///
+    pub fn load(config: &Config, name: &str) -> Result<Option<Self>> {
+        valid(name)?;
+        let file = admin_dir(config).join(name);
+        match fs::read_to_string(&file) {
+            Ok(contents) => Ok(Some(Self::parse(name, &file, &contents)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    ///
+    /// Writes the group back, or takes it away once there's no choice left
+    ///
+    pub fn save(&self, config: &Config) -> Result<()> {
+        let file = admin_dir(config).join(&self.name);
+        if self.choices.is_empty() {
+            return match fs::remove_file(&file) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+
+        fs::create_dir_all(admin_dir(config))?;
+        let new = file.with_extension("opm-new");
+        fs::write(&new, self.to_string())?;
+        fs::rename(&new, &file)?;
+        Ok(())
+    }
+
+    ///
+    /// What `/etc/alternatives/<name>` points to now
+    ///
+    pub fn current(&self, config: &Config) -> Option<PathBuf> {
+        fs::read_link(Path::new(&config.root).join(LINKS).join(&self.name)).ok()
+    }
+
+    ///
+    /// The choice with the highest priority, the first one among equals
+    ///
+    pub fn best(&self) -> Option<&Choice> {
+        self.choices.iter().rev().max_by_key(|choice| choice.priority)
+    }
+
+    ///
+    /// Points the links of the group to `choice`, or takes them away if none
+    ///
+    fn point(&self, config: &Config, choice: Option<&Choice>) -> Result<()> {
+        let root = Path::new(&config.root);
+        let links = Path::new("/").join(LINKS);
+
+        let mut all = vec![(&self.name, &self.link, choice.map(|choice| &choice.path))];
+        all.extend(self.slaves.iter()
+            .enumerate()
+            .map(|(i, slave)| (&slave.name, &slave.link, choice.and_then(|choice| choice.slaves.get(i)).and_then(|path| path.as_ref()))));
+
+        for (name, link, path) in all {
+            let alternative = links.join(name);
+            match path {
+                Some(path) => {
+                    replace(root, &alternative, path)?;
+                    replace(root, link, &alternative)?;
+                },
+                None => {
+                    unlink(root, &alternative)?;
+                    unlink(root, link)?;
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Points the links to the best choice, or keeps the one picked by hand if it's still there
+    ///
+    fn update(&mut self, config: &Config) -> Result<()> {
+        let current = self.current(config);
+        let picked = self.choices.iter()
+            .find(|choice| self.mode == Mode::Manual && Some(&choice.path) == current.as_ref())
+            .cloned();
+
+        let choice = match picked {
+            Some(choice) => Some(choice),
+            None => {
+                self.mode = Mode::Auto;
+                self.best().cloned()
+            },
+        };
+
+        if let Some(choice) = &choice {
+            if current.as_ref() != Some(&choice.path) {
+                println!("Using {} to provide {} ({}) in {} mode", choice.path.display(), self.link.display(), self.name, self.mode);
+            }
+        }
+
+        self.point(config, choice.as_ref())?;
+        self.save(config)
+    }
+}
+
+impl std::fmt::Display for Mode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mode::Auto => write!(f, "auto"),
+            Mode::Manual => write!(f, "manual"),
+        }
+    }
+}
+
+impl std::fmt::Display for Group {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.mode)?;
+        writeln!(f, "{}", self.link.display())?;
+        for slave in self.slaves.iter() {
+            writeln!(f, "{}\n{}", slave.name, slave.link.display())?;
+        }
+        writeln!(f)?;
+
+        for choice in self.choices.iter() {
+            writeln!(f, "{}\n{}", choice.path.display(), choice.priority)?;
+            for path in choice.slaves.iter() {
+                writeln!(f, "{}", path.as_ref().map(|path| path.display().to_string()).unwrap_or_default())?;
+            }
+        }
+        writeln!(f)
+    }
+}
+
+///
+/// Makes `at` (inside `root`) a symlink to `to`, never in place of something that isn't a symlink
+///
+fn replace(root: &Path, at: &Path, to: &Path) -> Result<()> {
+    let at = root.join(at.strip_prefix("/").unwrap_or(at));
+    match fs::symlink_metadata(&at) {
+        Ok(meta) if !meta.file_type().is_symlink() => {
+            eprintln!("Not replacing {} with a link, it's not one already", at.display());
+            return Ok(());
+        },
+        Ok(_) if fs::read_link(&at)? == to => return Ok(()),
+        _ => (),
+    }
+
+    if let Some(parent) = at.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Swapped with a single rename, so the link is never missing
+    let new = PathBuf::from(format!("{}.opm-new", at.display()));
+    let _ = fs::remove_file(&new);
+    symlink(to, &new)?;
+    fs::rename(&new, &at)?;
+    Ok(())
+}
+
+fn unlink(root: &Path, at: &Path) -> Result<()> {
+    let at = root.join(at.strip_prefix("/").unwrap_or(at));
+    match fs::symlink_metadata(&at) {
+        Ok(meta) if meta.file_type().is_symlink() => Ok(fs::remove_file(&at)?),
+        _ => Ok(()),
+    }
+}
+
+fn group(config: &Config, name: &str) -> Result<Group> {
+    match Group::load(config, name)? {
+        Some(group) => Ok(group),
+        None => anyhow::bail!(AlternativesError::NotFound(name.to_owned())),
+    }
+}
+
+///
+/// Does what `action` says to the link groups and the links themselves, under `config.root`
+///
+pub fn alternatives(config: &Config, action: Action) -> Result<()> {
+    match action {
+        Action::Install { link, name, path, priority, slaves } => {
+            for (_, name, _) in slaves.iter() {
+                valid(name)?;
+            }
+            if fs::symlink_metadata(Path::new(&config.root).join(path.strip_prefix("/").unwrap_or(&path))).is_err() {
+                anyhow::bail!(AlternativesError::Missing(path.display().to_string()));
+            }
+
+            let mut group = Group::load(config, &name)?.unwrap_or_else(|| Group {
+                name: name.clone(),
+                mode: Mode::Auto,
+                link: link.clone(),
+                slaves: vec![],
+                choices: vec![],
+            });
+            if group.link != link {
+                anyhow::bail!(AlternativesError::LinkMismatch { name, link: group.link.display().to_string(), wanted: link.display().to_string() });
+            }
+
+            // Slaves only this choice has are added to the group, the others don't point them anywhere
+            let mut paths = vec![None; group.slaves.len()];
+            for (link, name, path) in slaves {
+                let i = match group.slaves.iter().position(|slave| slave.name == name) {
+                    Some(i) => i,
+                    None => {
+                        group.slaves.push(Slave { name, link: link.clone() });
+                        group.choices.iter_mut().for_each(|choice| choice.slaves.push(None));
+                        paths.push(None);
+                        group.slaves.len() - 1
+                    },
+                };
+
+                group.slaves[i].link = link;
+                paths[i] = Some(path);
+            }
+
+            let choice = Choice { path: path.clone(), priority, slaves: paths };
+            match group.choices.iter_mut().find(|choice| choice.path == path) {
+                Some(existing) => *existing = choice,
+                None => group.choices.push(choice),
+            }
+
+            group.update(config)
+        },
+        Action::Remove { name, path } => {
+            let mut group = group(config, &name)?;
+            match group.choices.iter().position(|choice| choice.path == path) {
+                Some(i) => group.choices.remove(i),
+                None => anyhow::bail!(AlternativesError::NoSuchChoice { name, path: path.display().to_string() }),
+            };
+
+            println!("Removing {} from {}", path.display(), name);
+            group.update(config)
+        },
+        Action::Auto(name) => {
+            let mut group = group(config, &name)?;
+            group.mode = Mode::Auto;
+            group.update(config)
+        },
+        Action::Set { name, path } => {
+            let mut group = group(config, &name)?;
+            let choice = match group.choices.iter().find(|choice| choice.path == path) {
+                Some(choice) => choice.clone(),
+                None => anyhow::bail!(AlternativesError::NoSuchChoice { name, path: path.display().to_string() }),
+            };
+
+            group.mode = Mode::Manual;
+            println!("Using {} to provide {} ({}) in manual mode", path.display(), group.link.display(), name);
+            group.point(config, Some(&choice))?;
+            group.save(config)
+        },
+        Action::Display(name) => {
+            let group = group(config, &name)?;
+            let current = group.current(config);
+
+            println!("{} - {} mode", name, group.mode);
+            if let Some(best) = group.best() {
+                println!("  link best version is {}", best.path.display());
+            }
+            match &current {
+                Some(current) => println!("  link currently points to {}", current.display()),
+                None => println!("  link currently absent"),
+            }
+            println!("  link {} is {}", name, group.link.display());
+            for slave in group.slaves.iter() {
+                println!("  slave {} is {}", slave.name, slave.link.display());
+            }
+
+            for choice in group.choices.iter() {
+                println!("{} - priority {}", choice.path.display(), choice.priority);
+                for (slave, path) in group.slaves.iter().zip(choice.slaves.iter()) {
+                    if let Some(path) = path {
+                        println!("  slave {}: {}", slave.name, path.display());
+                    }
+                }
+            }
+
+            Ok(())
+        },
+        Action::List => {
+            let mut names = match fs::read_dir(admin_dir(config)) {
+                Ok(entries) => entries.map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned())).collect::<Result<Vec<_>, _>>()?,
+                Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+                Err(e) => return Err(e.into()),
+            };
+            names.sort();
+
+            for group in names.iter().filter_map(|name| Group::load(config, name).ok().flatten()) {
+                let current = group.current(config).map(|path| path.display().to_string()).unwrap_or_default();
+                println!("{:<24} {:<6} {}", group.name, group.mode, current);
+            }
+
+            Ok(())
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn alternatives_test() {
+        let dir = std::env::temp_dir().join(format!("opm-alternatives-{}", std::process::id()));
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        fs::write(root.join("usr/bin/nano"), "").unwrap();
+        fs::write(root.join("usr/bin/vim"), "").unwrap();
+
+        let mut config = crate::repos::setup().unwrap();
+        config.info = dir.join("info").display().to_string();
+        config.root = root.display().to_string();
+
+        let install = |path: &str, priority: i32, slave: Option<&str>| Action::Install {
+            link: PathBuf::from("/usr/bin/editor"),
+            name: String::from("editor"),
+            path: PathBuf::from(path),
+            priority,
+            slaves: slave.map(|slave| (PathBuf::from("/usr/share/man/man1/editor.1.gz"), String::from("editor.1.gz"), PathBuf::from(slave))).into_iter().collect(),
+        };
+        let points = |at: &str| fs::read_link(root.join(at)).ok();
+
+        alternatives(&config, install("/usr/bin/nano", 40, Some("/usr/share/man/man1/nano.1.gz"))).unwrap();
+        alternatives(&config, install("/usr/bin/vim", 50, None)).unwrap();
+        assert!(alternatives(&config, install("/usr/bin/emacs", 60, None)).is_err());
+
+        // Names are only ever file names
+        for name in ["../../usr/bin/vim", ".", "..", ""] {
+            let err = alternatives(&config, Action::Install {
+                link: PathBuf::from("/usr/bin/editor"),
+                name: name.to_owned(),
+                path: PathBuf::from("/usr/bin/nano"),
+                priority: 10,
+                slaves: vec![],
+            }).unwrap_err();
+            assert!(err.to_string().contains("not a valid alternative name"), "{}", err);
+        }
+        let mut evil = install("/usr/bin/nano", 40, Some("/usr/share/man/man1/nano.1.gz"));
+        if let Action::Install { slaves, .. } = &mut evil {
+            slaves[0].1 = String::from("../../usr/bin/vim");
+        }
+        assert!(alternatives(&config, evil).is_err());
+        assert!(Group::parse("editor", Path::new("editor"), "auto\n/usr/bin/editor\n..\n/usr/bin/vi\n\n").is_err());
+        assert!(fs::read_link(root.join("usr/bin/vim")).is_err());
+
+        // The best one wins in auto mode, and the slave goes away with a choice not having it
+        assert_eq!(points("usr/bin/editor"), Some(PathBuf::from("/etc/alternatives/editor")));
+        assert_eq!(points("etc/alternatives/editor"), Some(PathBuf::from("/usr/bin/vim")));
+        assert_eq!(points("usr/share/man/man1/editor.1.gz"), None);
+
+        alternatives(&config, Action::Set { name: String::from("editor"), path: PathBuf::from("/usr/bin/nano") }).unwrap();
+        assert_eq!(points("etc/alternatives/editor"), Some(PathBuf::from("/usr/bin/nano")));
+        assert_eq!(points("etc/alternatives/editor.1.gz"), Some(PathBuf::from("/usr/share/man/man1/nano.1.gz")));
+
+        // A manual choice stays, even over a better one
+        alternatives(&config, install("/usr/bin/vim", 70, None)).unwrap();
+        let group = Group::load(&config, "editor").unwrap().unwrap();
+        assert_eq!(group.mode, Mode::Manual);
+        assert_eq!(points("etc/alternatives/editor"), Some(PathBuf::from("/usr/bin/nano")));
+        assert_eq!(
+            fs::read_to_string(admin_dir(&config).join("editor")).unwrap(),
+            "manual\n/usr/bin/editor\neditor.1.gz\n/usr/share/man/man1/editor.1.gz\n\n/usr/bin/nano\n40\n/usr/share/man/man1/nano.1.gz\n/usr/bin/vim\n70\n\n\n"
+        );
+        assert_eq!(Group::parse("editor", Path::new("editor"), &group.to_string()).unwrap(), group);
+
+        // Unless it's taken away
+        alternatives(&config, Action::Remove { name: String::from("editor"), path: PathBuf::from("/usr/bin/nano") }).unwrap();
+        assert_eq!(Group::load(&config, "editor").unwrap().unwrap().mode, Mode::Auto);
+        assert_eq!(points("etc/alternatives/editor"), Some(PathBuf::from("/usr/bin/vim")));
+        assert_eq!(points("etc/alternatives/editor.1.gz"), None);
+
+        alternatives(&config, Action::Remove { name: String::from("editor"), path: PathBuf::from("/usr/bin/vim") }).unwrap();
+        assert_eq!(Group::load(&config, "editor").unwrap(), None);
+        assert_eq!(points("usr/bin/editor"), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
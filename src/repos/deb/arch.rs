@@ -0,0 +1,101 @@
+use std::fs;
+
+use crate::repos::config::Config;
+
+//
+// Debian architectures and multi-arch
+// https://wiki.debian.org/Multiarch/Implementation
+//
+
+const DPKG_ARCH: &str = "/var/lib/dpkg/arch";
+
+///
+/// The Debian name of the architecture opm was built for
+///
+pub fn native() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "x86" => "i386",
+        "aarch64" => "arm64",
+        "arm" => "armhf",
+        "powerpc64" if cfg!(target_endian = "little") => "ppc64el",
+        "powerpc64" => "ppc64",
+        "mips64" if cfg!(target_endian = "little") => "mips64el",
+        "loongarch64" => "loong64",
+        arch => arch, // s390x, riscv64, ...
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Architectures {
+    pub native: String,
+    pub foreign: Vec<String>,
+}
+
+impl Architectures {
+    pub fn new(native: &str, foreign: &[String]) -> Self {
+        let mut result = Self { native: native.to_owned(), foreign: vec![] };
+        foreign.iter().for_each(|arch| result.add(arch));
+        result
+    }
+
+    ///
+    /// The configured ones, plus whatever was added through `dpkg --add-architecture` when sharing its database
+    ///
+    pub fn from_config(config: &Config) -> Self {
+        let mut result = Self::new(&config.architecture, &config.foreign_architectures);
+
+        if config.use_pre_existing_db {
+            if let Ok(archs) = fs::read_to_string(DPKG_ARCH) {
+                archs.lines().map(|arch| arch.trim()).filter(|arch| !arch.is_empty()).for_each(|arch| result.add(arch));
+            }
+        }
+
+        result
+    }
+
+    fn add(&mut self, arch: &str) {
+        if arch != self.native && arch != "all" && !self.foreign.iter().any(|a| a == arch) {
+            self.foreign.push(arch.to_owned());
+        }
+    }
+
+    ///
+    /// Whether packages built for `arch` can be installed at all
+    ///
+    pub fn allowed(&self, arch: &str) -> bool {
+        arch == "all" || arch == self.native || self.foreign.iter().any(|a| a == arch)
+    }
+
+    ///
+    /// Every `binary-<arch>` index to fetch, `all` included
+    ///
+    pub fn indices(&self) -> Vec<&str> {
+        std::iter::once(self.native.as_str())
+            .chain(self.foreign.iter().map(|arch| arch.as_str()))
+            .chain(std::iter::once("all"))
+            .collect()
+    }
+
+    ///
+    /// `Architecture: all` packages behave as if they were native
+    ///
+    pub fn effective<'a>(&'a self, arch: &'a str) -> &'a str {
+        if arch == "all" { &self.native } else { arch }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn architectures_test() {
+        let archs = Architectures::new("amd64", &["i386".to_owned(), "amd64".to_owned(), "i386".to_owned()]);
+
+        assert_eq!(archs.foreign, vec!["i386"]);
+        assert_eq!(archs.indices(), vec!["amd64", "i386", "all"]);
+        assert!(archs.allowed("all") && archs.allowed("i386") && !archs.allowed("arm64"));
+        assert_eq!(archs.effective("all"), "amd64");
+    }
+}
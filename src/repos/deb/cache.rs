@@ -3,10 +3,15 @@ use crate::repos::{
 	config::Config,
 	errors::CacheError,
 };
-use std::{fs, io::prelude::*};
+use std::{collections::HashMap, fs, path::PathBuf};
 use regex::Regex;
 
 use super::package::{ControlFile, DebPackage, PkgKind};
+use super::pkgcache::PackageCache;
+use super::policy::Policy;
+use super::arch::Architectures;
+use super::deb822;
+use super::status::{self, Status, StatusDb};
 
 const DEBIAN_CACHE: &str = "/var/lib/apt/lists/";
 
@@ -47,17 +52,14 @@ impl<'a> Cache<'a> {
 	}
 }
 
-pub fn db_dump(config: &Config) -> Vec<DebPackage> {
-	let db = if config.use_pre_existing_db {
-		super::database::DEBIAN_DATABASE
-	} else {
-		&config.db
-	};
-	
-	let control = fs::read_to_string(db).unwrap();
+///
+/// Every package the database knows of, removed ones that left their conffiles behind included
+///
+pub fn db_entries(config: &Config) -> Vec<DebPackage> {
+	let control = fs::read_to_string(status::path(config)).unwrap();
 
-	let control = control
-		.split("\n\n")
+	let control = deb822::split(&control)
+		.into_iter()
 		.map(|contents| ControlFile::new(config, contents))
 		.filter_map(|ctrl| ctrl.ok())
 		.map(|ctrl| DebPackage { control: ctrl, kind: PkgKind::Binary } )
@@ -66,93 +68,150 @@ pub fn db_dump(config: &Config) -> Vec<DebPackage> {
 	control
 }
 
-fn cache_inter(config: &Config, name: &str, exact: bool) -> Result<CacheResult> {
+///
+/// The packages whose files are on the system. Those without a `Status` were written by older opm versions
+///
+pub fn db_dump(config: &Config) -> Vec<DebPackage> {
+	db_entries(config)
+		.into_iter()
+		.filter(|pkg| pkg.control.status.is_none_or(|status| status.is_installed()))
+		.collect()
+}
+
+///
+/// Where the `InRelease` files of the cached indices are
+///
+pub fn releases(config: &Config) -> &str {
+	if config.use_pre_existing_cache {
+		DEBIAN_CACHE
+	} else {
+		&config.rls
+	}
+}
+
+///
+/// The index files (`<repository>_Packages`) inside the cache
+///
+pub fn indices(config: &Config) -> Result<Vec<PathBuf>> {
 	let cache = Cache::get_cache(config)
 		.context("Failed to read the cache file")?;
 
+	let mut result = vec![];
 	for entry in fs::read_dir(cache.cache)? {
-		let entry = entry.unwrap();
-		let path = entry.path();
-		let path_str = path.clone().into_os_string().into_string().unwrap();
+		let path = entry?.path();
 
-		if path.is_dir() || !path_str.contains('_') {
+		if path.is_dir() || !path.to_string_lossy().ends_with("_Packages") {
 			continue
 		}
 
-		let control = match fs::read_to_string(&path) {
-			Ok(v) => v,
-			Err(e) => {
-				eprintln!("Unexpected error :: {}", e);
-				break;
-			}
-		};
+		result.push(path);
+	}
 
-		let mut control = control
-		.split("\n\n")
-		.map(|contents| ControlFile::new(config, contents))
-		.filter_map(|pkg| pkg.ok());
-
-		let entry = entry.path()
-		.into_os_string()
-		.into_string()
-		.unwrap();
-
-		let url =  &entry
-		.split('/')
-		.last()
-		.unwrap()
-		.replace("_", "/")
-		.split('/')
-		.collect::<Vec<_>>()[..2]
-		.join("/");
-
-		if exact {
-			let control = control.find(|pkg| pkg.package == name);
-
-			if let Some(mut pkg) = control {
-				let url = format!("{}/{}", url, &pkg.filename);
-				pkg.set_filename(&url);
-				return Ok(
-					CacheResult {
-						pkg: Some(
-							DebPackage {
-								control: pkg,
-								kind: PkgKind::Binary
-							}
-						),
-						pkgs: None
-					}
-				)
-			}
-		} else {
-			let re = Regex::new(name)?;
-
-			let pkgs = control
-				.filter(|pkg| re.is_match(&pkg.package))
-				.map(|mut pkg| {
-					let url = format!("{}/{}", url, &pkg.filename);
-					pkg.set_filename(&url);
-					DebPackage {
-						control: pkg,
-						kind: PkgKind::Binary
-					}
-				})
-				.collect::<Vec<_>>();
-
-			if !pkgs.is_empty() {
-				return Ok(
-					CacheResult {
-						pkg: None,
-						pkgs: Some(pkgs)
-					}
-				);
-			}
+	result.sort();
+	Ok(result)
+}
+
+fn cache_inter(config: &Config, name: &str, exact: bool) -> Result<CacheResult> {
+	let cache = PackageCache::open(config)?;
+	let (policy, archs) = (Policy::from_config(config)?, Architectures::from_config(config));
+	let installed = db_dump(config)
+		.into_iter()
+		.filter(|pkg| archs.effective(&pkg.control.architecture) == archs.native)
+		.map(|pkg| (pkg.control.package.clone(), pkg.control))
+		.collect::<HashMap<_, _>>();
+
+	// The version `opm install` would pick
+	let candidate = |name: &str| -> Result<Option<DebPackage>> {
+		let available = cache.lookup(name)?;
+		Ok(
+			policy.candidate(&versions(&available, &archs, None), installed.get(name))
+				.map(|control| DebPackage { control: control.clone(), kind: PkgKind::Binary })
+		)
+	};
+
+	if exact {
+		if let Some(pkg) = candidate(name)? {
+			return Ok(
+				CacheResult {
+					pkg: Some(pkg),
+					pkgs: None
+				}
+			)
+		}
+	} else {
+		let re = Regex::new(name)?;
+
+		let mut names = cache.names().filter(|pkg| re.is_match(pkg)).collect::<Vec<_>>();
+		names.sort_unstable();
+
+		let mut pkgs = vec![];
+		for name in names {
+			pkgs.extend(candidate(name)?);
+		}
+
+		if !pkgs.is_empty() {
+			return Ok(
+				CacheResult {
+					pkg: None,
+					pkgs: Some(pkgs)
+				}
+			);
 		}
 	}
 
 	anyhow::bail!(CacheError::NotFoundError { pkg: name.to_string(), cache: config.cache.clone() });
 }
 
+///
+/// The versions of `available` built for `arch`, or the native ones if there are any
+///
+fn versions<'a>(available: &'a [ControlFile], archs: &Architectures, arch: Option<&str>) -> Vec<&'a ControlFile> {
+	let pkgs = available.iter()
+		.filter(|pkg| archs.effective(&pkg.architecture) == arch.unwrap_or(&archs.native))
+		.collect::<Vec<_>>();
+
+	match pkgs.is_empty() && arch.is_none() {
+		true => available.iter().filter(|pkg| archs.allowed(&pkg.architecture)).collect(),
+		false => pkgs,
+	}
+}
+
+///
+/// What `opm policy` shows about a package
+///
+#[derive(Debug)]
+pub struct PolicyTable {
+	pub installed: Option<(ControlFile, i32)>,
+	pub candidate: Option<ControlFile>,
+	///
+	/// Every version known, highest priority first
+	///
+	pub versions: Vec<(ControlFile, i32)>,
+}
+
+///
+/// The priorities of every version of `name` (`pkg` or `pkg:arch`) and which one is the candidate
+///
+pub fn policy(config: &Config, name: &str) -> Result<PolicyTable> {
+	let (pkg, arch) = split_key(name);
+	let (policy, archs) = (Policy::from_config(config)?, Architectures::from_config(config));
+
+	let available = PackageCache::open(config)?.lookup(pkg)?;
+	let mut available = versions(&available, &archs, arch);
+	let installed = check_installed(config, name).map(|pkg| pkg.control);
+
+	policy.sort(&mut available);
+	let candidate = policy.candidate(&available, installed.as_ref()).cloned();
+
+	Ok(
+		PolicyTable {
+			installed: installed.as_ref().map(|pkg| (pkg.clone(), policy.installed_priority(pkg, available.iter().copied()))),
+			candidate,
+			versions: available.into_iter().map(|pkg| (pkg.clone(), policy.priority(pkg))).collect(),
+		}
+	)
+}
+
 ///
 /// Search for a package in the cache that `contains` `name`
 /// 
@@ -173,71 +232,90 @@ pub fn cache_lookup(config: &Config, name: &str) -> Result<Option<DebPackage>> {
 	)
 }
 
-#[inline]
-pub fn check_installed(config: &Config, name: &str) -> Option<DebPackage> {
-	db_dump(config).into_iter().find(|pkg| pkg.control.package == name)
+///
+/// Splits `pkg:arch` into its name and architecture
+///
+fn split_key(key: &str) -> (&str, Option<&str>) {
+	match key.split_once(':') {
+		Some((name, arch)) => (name, Some(arch)),
+		None => (key, None),
+	}
 }
 
-pub fn add_package(config: &Config, pkg: DebPackage) -> Result<()> {
-	let pkg = pkg.control;
-	let db = if config.use_pre_existing_db {
-		super::database::DEBIAN_DATABASE
-	} else {
-		&config.db
-	};
+///
+/// The first of `pkgs` called `name`, which can be qualified as `pkg:arch`
+///
+fn find(pkgs: Vec<DebPackage>, name: &str) -> Option<DebPackage> {
+	let (name, arch) = split_key(name);
 
-	let mut data = format!("Package: {}
-Version: {}
-Priority: {}
-Architecture: {}
-Maintainer: {}
-Description: {}", pkg.package, pkg.version, pkg.priority, pkg.architecture, pkg.maintainer, pkg.description);
-
-	if let Some(d) = pkg.depends {
-		let mut depends = String::new();
-		d.into_iter().for_each(|pkg| depends.push_str(&pkg));
-		data.push_str(&format!("\nDepends: {}", depends));
-	}
+	pkgs.into_iter().find(|pkg| pkg.control.package == name
+		&& arch.is_none_or(|arch| pkg.control.architecture == arch))
+}
 
-	if let Some(d) = pkg.breaks {
-		let breaks = d.join(", ");
-		data.push_str(&format!("\nBreaks: {}", breaks));
-	}
-	
-	if let Some(d) = pkg.conflicts {
-		let conflicts = d.join(", ");
-		data.push_str(&format!("\nConflicts: {}", conflicts));
-	}
+///
+/// The installed package `name`, which can be qualified as `pkg:arch`
+///
+#[inline]
+pub fn check_installed(config: &Config, name: &str) -> Option<DebPackage> {
+	find(db_dump(config), name)
+}
 
-	data.push('\n');
+///
+/// The database entry of `name` whatever its state
+///
+#[inline]
+pub fn find_package(config: &Config, name: &str) -> Option<DebPackage> {
+	find(db_entries(config), name)
+}
 
-	let mut file = fs::OpenOptions::new()
-		.write(true)
-		.append(true)
-		.open(db)?;
+pub fn add_package(config: &Config, pkg: DebPackage, status: Status) -> Result<()> {
+	let mut paragraph = pkg.control.paragraph;
 
-	if let Err(e) = writeln!(file, "{}", data) {
-		eprintln!("Couldn't write to db: {}", e);
-	}
+	// Those only make sense inside a repository index
+	["Filename", "Size", "MD5sum", "SHA1", "SHA256", "SHA512", "Description-md5"]
+		.iter()
+		.for_each(|field| paragraph.remove(field));
+	paragraph.set("Status", &status.to_string());
 
-	Ok(())
+	add_stanza(config, &paragraph.to_string())
 }
 
-pub fn rm_package(config: &Config, name: &str) -> Result<()> {
-	let db = if config.use_pre_existing_db {
-		super::database::DEBIAN_DATABASE
-	} else {
-		&config.db
-	};
+///
+/// Puts a raw paragraph in the database, in place of the one of the same package if any
+///
+pub fn add_stanza(config: &Config, stanza: &str) -> Result<()> {
+	let mut db = StatusDb::open(&status::path(config))?;
+	db.set(stanza);
+	db.commit()
+}
 
-	let data = fs::read_to_string(&db)?;
-	let mut db = fs::File::create(&db)?;
+///
+/// The raw database paragraph of `name`, if it's installed
+///
+pub fn get_stanza(config: &Config, name: &str) -> Result<Option<String>> {
+	Ok(
+		StatusDb::open(&status::path(config))?
+			.get(name)
+			.map(|stanza| stanza.to_owned())
+	)
+}
 
-	if let Some(index) = data.find(&format!("Package: {}", name)) {
-		db.write_all(data[..index].as_ref())?;
-	}
+///
+/// Changes the `Status` of `name` alone
+///
+pub fn set_status(config: &Config, name: &str, status: Status) -> Result<()> {
+	let mut db = StatusDb::open(&status::path(config))?;
+	db.set_status(name, status)?;
+	db.commit()
+}
 
-	Ok(())
+///
+/// Takes the paragraph of `name` (`pkg` or `pkg:arch`) out of the database, leaving every other one as it was
+///
+pub fn rm_package(config: &Config, name: &str) -> Result<()> {
+	let mut db = StatusDb::open(&status::path(config))?;
+	db.remove(name);
+	db.commit()
 }
 
 #[cfg(test)]
@@ -264,7 +342,7 @@ mod test {
 		let config = repos::setup().unwrap();
 		// THIS MAY NOT BE GOOD, IF YOU HAVE AN EMPTY DATABASED IT'LL FAIL
 		dbg!("[db_dump_test]", &config);
-		assert!(db_dump(&config).len() > 0);
+		assert!(!db_dump(&config).is_empty());
 	}
 
 	// This was crashing and idk why
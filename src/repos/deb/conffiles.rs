@@ -0,0 +1,263 @@
+use anyhow::Result;
+use std::{collections::HashMap, fs, io::{self, Write}, path::{Path, PathBuf}, process::Command};
+
+use crate::repos::{config::Config, errors::InstallError};
+use super::files;
+use super::deb822::Paragraph;
+use super::diversions::Diversions;
+use super::journal::{Journal, Step};
+
+//
+// Conffiles the dpkg way: the md5 of every one shipped is recorded in the status db (`Conffiles`),
+// so local edits survive upgrades and get settled instead of silently overwritten
+//
+
+///
+/// What dpkg records while a conffile is being unpacked, never a real hash
+///
+const NEW_CONFFILE: &str = "newconffile";
+
+///
+/// Copies next to a conffile that dpkg (or us) may leave behind, purged along with it
+///
+const LEFTOVERS: [&str; 4] = [".dpkg-old", ".dpkg-dist", ".dpkg-new", ".dpkg-tmp"];
+
+///
+/// How a conffile that was changed both locally and by the package gets settled
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConfChoice {
+    #[default]
+    Ask,
+    ///
+    /// `--conf-new`: the package's version, keeping the local one as `.dpkg-old`
+    ///
+    New,
+    ///
+    /// `--conf-old`: the local version, keeping the package's one as `.dpkg-dist`
+    ///
+    Old,
+    ///
+    /// `--conf-def`: whatever the prompt would default to
+    ///
+    Default,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Case {
+    ///
+    /// Nothing there yet, or it's already the same
+    ///
+    Install,
+    ///
+    /// Untouched since the last version, so it just gets replaced
+    ///
+    Unmodified,
+    ///
+    /// Edited locally but the package didn't change it
+    ///
+    Modified,
+    ///
+    /// Deleted locally, which is respected
+    ///
+    Deleted,
+    ///
+    /// Edited locally and by the package, so it's up to `ConfChoice`
+    ///
+    Conflict { install: bool },
+}
+
+///
+/// `<path> <md5>` of every conffile `stanza` records, obsolete ones included
+///
+pub fn recorded(stanza: &str) -> HashMap<PathBuf, String> {
+    let paragraph = match Paragraph::parse(stanza) {
+        Ok(paragraph) => paragraph,
+        Err(_) => return HashMap::new(),
+    };
+
+    paragraph.lines("Conffiles")
+        .iter()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some((PathBuf::from(fields.next()?), fields.next()?.to_owned()))
+        })
+        .collect()
+}
+
+///
+/// The `Conffiles` lines for `shipped`, plus the ones `recorded` that aren't shipped anymore as `obsolete`
+///
+pub fn field(shipped: &[(PathBuf, String)], recorded: &HashMap<PathBuf, String>) -> Vec<String> {
+    let mut obsolete = recorded.iter()
+        .filter(|(path, _)| !shipped.iter().any(|(p, _)| p == *path))
+        .map(|(path, md5)| format!("{} {} obsolete", path.display(), md5))
+        .collect::<Vec<_>>();
+    obsolete.sort();
+
+    shipped.iter()
+        .map(|(path, md5)| format!("{} {}", path.display(), md5))
+        .chain(obsolete)
+        .collect()
+}
+
+///
+/// Every copy of `path` that purging gets rid of along with it
+///
+pub fn leftovers(path: &Path) -> Vec<PathBuf> {
+    LEFTOVERS.iter()
+        .map(|ext| PathBuf::from(format!("{}{}", path.display(), ext)))
+        .collect()
+}
+
+fn md5(path: &Path) -> Result<Option<String>> {
+    match fs::read(path) {
+        Ok(contents) => Ok(Some(format!("{:x}", md5::compute(contents)))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+///
+/// `old` is what the last version shipped, `current` what is there now and `dist` what the new one ships
+///
+fn case(old: Option<&str>, current: Option<&str>, dist: &str) -> Case {
+    let old = old.filter(|old| *old != NEW_CONFFILE);
+
+    match (old, current) {
+        (Some(_), None) => Case::Deleted,
+        (None, None) => Case::Install,
+        (_, Some(current)) if current == dist => Case::Install,
+        // Someone else's file standing where ours goes
+        (None, Some(_)) => Case::Conflict { install: true },
+        (Some(old), Some(current)) if old == current => Case::Unmodified,
+        (Some(old), Some(_)) if old == dist => Case::Modified,
+        (Some(_), Some(_)) => Case::Conflict { install: false },
+    }
+}
+
+///
+/// Goes through the `conffiles` of the package unpacked at `data`, before it gets placed (wherever it's `diverted` to).
+/// The ones staying as they are get taken out of `data`; returns the md5 each one ships with
+///
+pub fn settle(config: &Config, journal: &mut Journal, data: &Path, conffiles: &[PathBuf], recorded: &HashMap<PathBuf, String>, choice: ConfChoice, diverted: &(Diversions, &str)) -> Result<Vec<(PathBuf, String)>> {
+    let mut shipped = vec![];
+
+    for conffile in conffiles.iter() {
+        let (rel, placed) = (conffile.strip_prefix("/").unwrap_or(conffile), diverted.0.target(conffile, diverted.1));
+        let src = data.join(rel);
+        let dst = files::locate(Path::new(&config.root), placed).ok_or_else(|| anyhow::anyhow!(InstallError::OutsideRoot(placed.display().to_string())))?;
+
+        // Only regular files can be conffiles
+        let dist = match fs::symlink_metadata(&src) {
+            Ok(meta) if meta.is_file() => md5(&src)?.unwrap_or_default(),
+            _ => continue,
+        };
+        let current = match fs::symlink_metadata(&dst) {
+            Ok(meta) if meta.is_file() => md5(&dst)?,
+            Ok(_) => Some(String::new()),
+            Err(_) => None,
+        };
+
+        let install = match case(recorded.get(conffile).map(|md5| md5.as_str()), current.as_deref(), &dist) {
+            Case::Install | Case::Unmodified => true,
+            Case::Modified => false,
+            Case::Deleted => {
+                println!("Not installing {}, it was deleted locally", conffile.display());
+                false
+            },
+            Case::Conflict { install } => {
+                let install = match choice {
+                    ConfChoice::New => true,
+                    ConfChoice::Old => false,
+                    ConfChoice::Default => install,
+                    ConfChoice::Ask => ask(conffile, &dst, &src, install)?,
+                };
+
+                // Whichever loses stays around, for the admin to merge by hand
+                let (from, ext) = if install { (&dst, ".dpkg-old") } else { (&src, ".dpkg-dist") };
+                let copy = PathBuf::from(format!("{}{}", dst.display(), ext));
+                if fs::symlink_metadata(&copy).is_ok() {
+                    journal.backup(&copy, false)?;
+                }
+
+                journal.log(Step::Placed { path: copy.clone() })?;
+                match fs::symlink_metadata(from) {
+                    // Kept as the link it is, whatever it points to isn't ours to copy
+                    Ok(meta) if meta.file_type().is_symlink() => std::os::unix::fs::symlink(fs::read_link(from)?, &copy)?,
+                    _ => { fs::copy(from, &copy)?; },
+                }
+                println!("{} {}, the other version is at {}", if install { "Replaced" } else { "Kept" }, conffile.display(), copy.display());
+                install
+            },
+        };
+
+        if !install {
+            fs::remove_file(&src)?;
+        }
+        shipped.push((conffile.clone(), dist));
+    }
+
+    Ok(shipped)
+}
+
+///
+/// Asks what to do about `conffile`, `install` being the default answer
+///
+fn ask(conffile: &Path, current: &Path, dist: &Path, install: bool) -> Result<bool> {
+    loop {
+        println!("Configuration file '{}'", conffile.display());
+        println!(" ==> Modified (by you or by a script) since installation.");
+        println!(" ==> Package distributor has shipped an updated version.");
+        println!("    Y or I  : install the package maintainer's version");
+        println!("    N or O  : keep your currently-installed version");
+        println!("      D     : show the differences between the versions");
+        print!("*** {} (Y/I/N/O/D) [default={}] ? ", conffile.display(), if install { "Y" } else { "N" });
+        io::stdout().flush()?;
+
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+
+        match answer.trim().to_ascii_lowercase().as_str() {
+            "" => return Ok(install),
+            "y" | "i" => return Ok(true),
+            "n" | "o" => return Ok(false),
+            "d" => {
+                if let Err(e) = Command::new("diff").arg("-u").arg(current).arg(dist).status() {
+                    eprintln!("Could not run diff :: {}", e);
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn case_test() {
+        let (a, b, c) = ("aaa", "bbb", "ccc");
+
+        assert_eq!(case(None, None, a), Case::Install);
+        assert_eq!(case(None, Some(a), a), Case::Install);
+        assert_eq!(case(None, Some(b), a), Case::Conflict { install: true });
+        assert_eq!(case(Some(NEW_CONFFILE), Some(b), a), Case::Conflict { install: true });
+        assert_eq!(case(Some(a), None, b), Case::Deleted);
+        assert_eq!(case(Some(a), Some(a), b), Case::Unmodified);
+        assert_eq!(case(Some(a), Some(b), a), Case::Modified);
+        assert_eq!(case(Some(a), Some(b), b), Case::Install);
+        assert_eq!(case(Some(a), Some(b), c), Case::Conflict { install: false });
+    }
+
+    #[test]
+    fn field_test() {
+        let stanza = "Package: opm-test\nConffiles:\n /etc/opm-test.conf aaa\n /etc/opm-test/gone bbb\n";
+        let recorded = recorded(stanza);
+        assert_eq!(recorded.get(Path::new("/etc/opm-test/gone")).map(|md5| md5.as_str()), Some("bbb"));
+
+        let shipped = vec![(PathBuf::from("/etc/opm-test.conf"), "ccc".to_owned())];
+        assert_eq!(field(&shipped, &recorded), vec!["/etc/opm-test.conf ccc", "/etc/opm-test/gone bbb obsolete"]);
+    }
+}
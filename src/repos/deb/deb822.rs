@@ -0,0 +1,225 @@
+use anyhow::Result;
+use std::fmt::{self, Display};
+
+use crate::repos::errors::ConfigError;
+
+//
+// RFC822-like (deb822) paragraphs, as used by control files, Packages indices and the status db
+//
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    ///
+    /// `name` and everything after its `:` as written, continuation lines included
+    ///
+    Field { name: String, value: String },
+    Comment(String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Paragraph {
+    entries: Vec<Entry>,
+}
+
+impl Paragraph {
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut entries: Vec<Entry> = vec![];
+
+        for (i, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                anyhow::bail!(ConfigError::UnexError { msg: format!("Blank line {} inside a paragraph", i + 1), err: None });
+            } else if line.starts_with('#') {
+                entries.push(Entry::Comment(line.to_owned()));
+            } else if line.starts_with(' ') || line.starts_with('\t') {
+                match entries.iter_mut().rev().find(|entry| matches!(entry, Entry::Field { .. })) {
+                    Some(Entry::Field { value, .. }) => {
+                        value.push('\n');
+                        value.push_str(line);
+                    },
+                    _ => anyhow::bail!(ConfigError::UnexError { msg: format!("Continuation line {} without a field", i + 1), err: None }),
+                }
+            } else {
+                match line.split_once(':') {
+                    Some((name, value)) if !name.is_empty() && !name.contains(char::is_whitespace) => {
+                        entries.push(Entry::Field { name: name.to_owned(), value: value.to_owned() });
+                    },
+                    _ => anyhow::bail!(ConfigError::UnexError { msg: format!("Invalid field at line {}: {:?}", i + 1, line), err: None }),
+                }
+            }
+        }
+
+        Ok(Self { entries })
+    }
+
+    ///
+    /// Raw value of `name` (case-insensitive), trimmed but keeping its continuation lines.
+    /// Fields starting on the next line (e.g.: `Conffiles`) keep an empty first line
+    ///
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries.iter().find_map(|entry| match entry {
+            Entry::Field { name: n, value } if n.eq_ignore_ascii_case(name) => Some(value.trim_start_matches([' ', '\t']).trim_end()),
+            _ => None,
+        })
+    }
+
+    ///
+    /// Continuation lines of `name`, without their leading space
+    ///
+    pub fn lines(&self, name: &str) -> Vec<&str> {
+        match self.get(name) {
+            Some(value) => value.lines().skip(1).map(|line| &line[1..]).collect(),
+            None => vec![],
+        }
+    }
+
+    ///
+    /// Sets `name` to `value` where the field stands, or at the end if it isn't there yet
+    ///
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.put(name, format!(" {}", value));
+    }
+
+    ///
+    /// Sets `name` to a field starting on the next line (e.g.: `Conffiles`), one continuation line each
+    ///
+    pub fn set_lines(&mut self, name: &str, lines: &[String]) {
+        self.put(name, lines.iter().map(|line| format!("\n {}", line)).collect());
+    }
+
+    fn put(&mut self, name: &str, value: String) {
+        let existing = self.entries.iter_mut().find_map(|entry| match entry {
+            Entry::Field { name: n, value } if n.eq_ignore_ascii_case(name) => Some(value),
+            _ => None,
+        });
+
+        match existing {
+            Some(existing) => *existing = value,
+            None => self.entries.push(Entry::Field { name: name.to_owned(), value }),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|entry| !matches!(entry, Entry::Field { name: n, .. } if n.eq_ignore_ascii_case(name)));
+    }
+}
+
+impl Display for Paragraph {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in self.entries.iter() {
+            match entry {
+                Entry::Field { name, value } => writeln!(f, "{}:{}", name, value)?,
+                Entry::Comment(comment) => writeln!(f, "{}", comment)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+///
+/// Splits `contents` on blank lines, every slice being a single paragraph
+///
+pub fn split(contents: &str) -> Vec<&str> {
+    let mut result = vec![];
+    let (mut start, mut offset) = (None, 0);
+
+    for line in contents.split_inclusive('\n') {
+        if line.trim().is_empty() {
+            if let Some(s) = start.take() {
+                result.push(&contents[s..offset]);
+            }
+        } else if start.is_none() {
+            start = Some(offset);
+        }
+
+        offset += line.len();
+    }
+
+    if let Some(s) = start {
+        result.push(&contents[s..]);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CONTROL: &str = "Package: opm-test
+Version: 1:2.0-1
+Architecture: amd64
+Maintainer: FallAngel <fallangel@protonmail.com>
+Installed-Size: 42
+Depends: libc6 (>= 2.34),
+ libssl3 (>= 3.0.0)
+Homepage: https://github.com/0xc0ffeec0de/opm
+Description: test package: with a colon
+ A longer description
+ .
+ that spans multiple lines.
+Conffiles:
+ /etc/opm-test.conf 87a0e27c83950d864d901ceca0f2b49c
+ /etc/opm-test/other 0d8b3bba5a8ff6e79ba06e7c9f7b3e1c obsolete
+X-Custom-Field:  keep   spacing\t
+";
+
+    #[test]
+    fn round_trip_test() {
+        let paragraph = Paragraph::parse(CONTROL).unwrap();
+        assert_eq!(paragraph.to_string(), CONTROL);
+    }
+
+    #[test]
+    fn fields_test() {
+        let paragraph = Paragraph::parse(CONTROL).unwrap();
+
+        assert_eq!(paragraph.get("version"), Some("1:2.0-1"));
+        assert_eq!(paragraph.get("Homepage"), Some("https://github.com/0xc0ffeec0de/opm"));
+        assert_eq!(paragraph.get("Depends"), Some("libc6 (>= 2.34),\n libssl3 (>= 3.0.0)"));
+        assert_eq!(paragraph.get("Description").unwrap().lines().next(), Some("test package: with a colon"));
+        assert_eq!(paragraph.lines("Description"), vec!["A longer description", ".", "that spans multiple lines."]);
+        assert_eq!(paragraph.lines("Conffiles").len(), 2);
+        assert_eq!(paragraph.get("Section"), None);
+    }
+
+    #[test]
+    fn remove_test() {
+        let mut paragraph = Paragraph::parse(CONTROL).unwrap();
+        paragraph.remove("conffiles");
+
+        assert_eq!(paragraph.get("Conffiles"), None);
+        assert!(paragraph.to_string().contains("that spans multiple lines.\nX-Custom-Field:  keep   spacing\t\n"));
+    }
+
+    #[test]
+    fn set_test() {
+        let mut paragraph = Paragraph::parse(CONTROL).unwrap();
+        paragraph.set("version", "1:2.0-2");
+        paragraph.set("Status", "install ok installed");
+
+        assert_eq!(paragraph.to_string(), CONTROL.replace("1:2.0-1", "1:2.0-2") + "Status: install ok installed\n");
+    }
+
+    #[test]
+    fn set_lines_test() {
+        let mut paragraph = Paragraph::parse(CONTROL).unwrap();
+        paragraph.set_lines("Conffiles", &["/etc/opm-test.conf 0d8b3bba5a8ff6e79ba06e7c9f7b3e1c".to_owned()]);
+
+        assert_eq!(paragraph.lines("Conffiles"), vec!["/etc/opm-test.conf 0d8b3bba5a8ff6e79ba06e7c9f7b3e1c"]);
+        assert!(paragraph.to_string().contains("Conffiles:\n /etc/opm-test.conf 0d8b3bba5a8ff6e79ba06e7c9f7b3e1c\nX-Custom-Field"));
+    }
+
+    #[test]
+    fn invalid_test() {
+        assert!(Paragraph::parse(" starts with a continuation\n").is_err());
+        assert!(Paragraph::parse("Package opm\n").is_err());
+        assert!(Paragraph::parse("Bad Name: opm\n").is_err());
+    }
+
+    #[test]
+    fn split_test() {
+        let contents = "Package: a\nVersion: 1\n\nPackage: b\n \n\n\nPackage: c\nVersion: 3";
+        assert_eq!(split(contents), vec!["Package: a\nVersion: 1\n", "Package: b\n", "Package: c\nVersion: 3"]);
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::Result;
+use std::{fs, io::ErrorKind, path::{Path, PathBuf}};
+
+use crate::repos::{config::Config, errors::DiversionError};
+
+//
+// Diversions, what `dpkg-divert` keeps at `/var/lib/dpkg/diversions`: a file some package ships
+// ends up somewhere else, unless it's the package that diverted it
+// https://man7.org/linux/man-pages/man1/dpkg-divert.1.html
+//
+
+///
+/// What dpkg writes instead of a package name for diversions made by hand
+///
+const LOCAL: &str = ":";
+
+///
+/// The database file in use, `<info>/diversions` unless sharing dpkg's
+///
+pub fn path(config: &Config) -> PathBuf {
+    match config.use_pre_existing_db {
+        true => PathBuf::from(super::database::DEBIAN_DIVERSIONS),
+        false => Path::new(&config.info).join("diversions"),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diversion {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    ///
+    /// The package allowed to keep its file at `from`, none for local diversions
+    ///
+    pub package: Option<String>,
+}
+
+///
+/// What `opm divert` does
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    ///
+    /// `to` defaults to `<from>.distrib`, `rename` moves the file already there along
+    ///
+    Add { from: PathBuf, to: Option<PathBuf>, package: Option<String>, rename: bool },
+    Remove { from: PathBuf, rename: bool },
+    List,
+}
+
+#[derive(Debug)]
+pub struct Diversions {
+    path: PathBuf,
+    pub list: Vec<Diversion>,
+}
+
+impl Diversions {
+    ///
+    /// Reads the diversions of `config`, none if there's no database yet
+    ///
+    pub fn load(config: &Config) -> Result<Self> {
+        let path = path(config);
+        let list = match fs::read_to_string(&path) {
+            Ok(contents) => Self::parse(&path, &contents)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self { path, list })
+    }
+
+    ///
+    /// Every three lines are a diversion: the path, where it goes and who diverted it
+    ///
+    fn parse(path: &Path, contents: &str) -> Result<Vec<Diversion>> {
+        let lines = contents.lines().collect::<Vec<_>>();
+        if lines.len() % 3 != 0 {
+            anyhow::bail!(DiversionError::Malformed { file: path.display().to_string(), why: String::from("truncated diversion") });
+        }
+
+        Ok(
+            lines.chunks(3)
+                .map(|entry| Diversion {
+                    from: PathBuf::from(entry[0]),
+                    to: PathBuf::from(entry[1]),
+                    package: Some(entry[2].to_owned()).filter(|package| package != LOCAL),
+                })
+                .collect()
+        )
+    }
+
+    ///
+    /// The diversion of `path`, or the one `path` is the target of
+    ///
+    pub fn find(&self, path: &Path) -> Option<&Diversion> {
+        self.list.iter().find(|diversion| diversion.from == path || diversion.to == path)
+    }
+
+    ///
+    /// Where the file `path` that `pkg` ships goes
+    ///
+    pub fn target<'a>(&'a self, path: &'a Path, pkg: &str) -> &'a Path {
+        match self.list.iter().find(|diversion| diversion.from == path) {
+            Some(diversion) if diversion.package.as_deref() != Some(pkg) => &diversion.to,
+            _ => path,
+        }
+    }
+
+    ///
+    /// Adds `diversion`, unless either of its paths is diverted already in some other way
+    ///
+    pub fn add(&mut self, diversion: Diversion) -> Result<()> {
+        match self.find(&diversion.from).or_else(|| self.find(&diversion.to)) {
+            Some(existing) if *existing == diversion => Ok(()),
+            Some(existing) => anyhow::bail!(DiversionError::Clash { path: diversion.from.display().to_string(), existing: existing.to_string() }),
+            None => {
+                self.list.push(diversion);
+                Ok(())
+            },
+        }
+    }
+
+    ///
+    /// Takes away the diversion of `from`
+    ///
+    pub fn remove(&mut self, from: &Path) -> Result<Diversion> {
+        match self.list.iter().position(|diversion| diversion.from == from) {
+            Some(index) => Ok(self.list.remove(index)),
+            None => anyhow::bail!(DiversionError::NotDiverted(from.display().to_string())),
+        }
+    }
+
+    ///
+    /// Writes the database back, replacing the old one at once
+    ///
+    pub fn save(&self) -> Result<()> {
+        let contents = self.list.iter()
+            .map(|diversion| format!("{}\n{}\n{}\n", diversion.from.display(), diversion.to.display(), diversion.package.as_deref().unwrap_or(LOCAL)))
+            .collect::<String>();
+
+        let new = self.path.with_extension("opm-new");
+        fs::write(&new, contents)?;
+        fs::rename(&new, &self.path)?;
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Diversion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.package {
+            Some(package) => write!(f, "diversion of {} to {} by {}", self.from.display(), self.to.display(), package),
+            None => write!(f, "local diversion of {} to {}", self.from.display(), self.to.display()),
+        }
+    }
+}
+
+///
+/// Adds, removes or lists diversions, moving the diverted file in `config.root` if asked to
+///
+pub fn divert(config: &Config, action: Action) -> Result<()> {
+    let mut diversions = Diversions::load(config)?;
+    let root = |path: &Path| Path::new(&config.root).join(path.strip_prefix("/").unwrap_or(path));
+
+    let (from, to, rename) = match action {
+        Action::List => {
+            diversions.list.iter().for_each(|diversion| println!("{}", diversion));
+            return Ok(());
+        },
+        Action::Add { from, to, package, rename } => {
+            let to = to.unwrap_or_else(|| PathBuf::from(format!("{}.distrib", from.display())));
+            let diversion = Diversion { from: from.clone(), to: to.clone(), package };
+
+            println!("Adding '{}'", diversion);
+            diversions.add(diversion)?;
+            (from, to, rename)
+        },
+        Action::Remove { from, rename } => {
+            let diversion = diversions.remove(&from)?;
+
+            println!("Removing '{}'", diversion);
+            (diversion.to, from, rename)
+        },
+    };
+
+    // Nothing gets moved over a file that's already there
+    let (from, to) = (root(&from), root(&to));
+    if rename && fs::symlink_metadata(&from).is_ok() {
+        match fs::symlink_metadata(&to) {
+            Ok(_) => anyhow::bail!(DiversionError::Exists(to.display().to_string())),
+            Err(_) => fs::rename(&from, &to)?,
+        }
+    }
+
+    diversions.save()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn diversions_test() {
+        let path = std::env::temp_dir().join(format!("opm-diversions-{}", std::process::id()));
+        let contents = "/usr/bin/opm\n/usr/bin/opm.distrib\nopm-wrapper\n/etc/opm.conf\n/etc/opm.conf.orig\n:\n";
+
+        let mut diversions = Diversions { path: path.clone(), list: Diversions::parse(&path, contents).unwrap() };
+        assert_eq!(diversions.list[1].package, None);
+        assert!(Diversions::parse(&path, "/usr/bin/opm\n/usr/bin/opm.distrib\n").is_err());
+
+        // Only the package that diverted it gets to place it there
+        let opm = Path::new("/usr/bin/opm");
+        assert_eq!(diversions.target(opm, "opm"), Path::new("/usr/bin/opm.distrib"));
+        assert_eq!(diversions.target(opm, "opm-wrapper"), opm);
+        assert_eq!(diversions.target(Path::new("/etc/opm.conf"), "opm"), Path::new("/etc/opm.conf.orig"));
+        assert_eq!(diversions.target(Path::new("/usr/bin/other"), "opm"), Path::new("/usr/bin/other"));
+
+        let clash = Diversion { from: PathBuf::from("/usr/bin/opm.distrib"), to: PathBuf::from("/usr/bin/opm.old"), package: None };
+        assert!(diversions.add(clash).is_err());
+        assert!(diversions.remove(Path::new("/usr/bin/other")).is_err());
+
+        diversions.remove(opm).unwrap();
+        diversions.save().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "/etc/opm.conf\n/etc/opm.conf.orig\n:\n");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,42 +1,57 @@
 use anyhow::Result;
 use indicatif::ProgressBar;
 use futures_util::StreamExt;
-use std::{path::PathBuf, fs::File, io::Write};
+use std::{path::{Path, PathBuf}, fs::{self, File}, io::Write};
 
 use crate::repos::config::Config;
-use super::{package::DebPackage, signatures};
+use super::{package::DebPackage, signatures::Hasher};
 
 // https://gist.github.com/giuliano-oliveira/4d11d6b3bb003dba3a1b53f43d81b30d
 pub async fn download(config: &Config, pkg: DebPackage, pb: ProgressBar) -> Result<PathBuf> {
-    let control = pkg.control.clone();
-    let url = format!("http://{}", control.filename);    
-    let response = reqwest::get(&url).await?;
-    let size = response.content_length().unwrap_or_default();
-
-    // println!("Get: {} {} {} {} [{}]", url, control.architecture,
-    // control.package, control.version, HumanBytes(size));
-
-    let name = control.filename.split('/').last().unwrap().to_string();
-    let mut content = Vec::with_capacity(size as usize);
-    let fname = format!("{}/{}", config.archive, name);
-
-    let (mut stream, mut downloaded) = (response.bytes_stream(), 0_u64);
-
-    while let Some(item) = stream.next().await {
-        let chunk = item?;
-        Write::write(&mut content, &chunk)?;
-        let progress = std::cmp::min(downloaded + chunk.len() as u64, size);
-        downloaded = progress;
-        pb.set_position(progress);
+    let control = &pkg.control;
+    let url = format!("http://{}", control.filename);
+
+    let name = control.filename.rsplit('/').next().unwrap().to_string();
+    let fname = PathBuf::from(format!("{}/{}", config.archive, name));
+
+    // A corrupted download gets one more chance before failing the install
+    match fetch(&pkg, &url, &fname, &pb).await {
+        Ok(()) => Ok(fname),
+        Err(e) => {
+            pb.println(format!("{} :: retrying {}", e, url));
+            pb.set_position(0);
+            fetch(&pkg, &url, &fname, &pb).await?;
+            Ok(fname)
+        }
     }
-
-    match signatures::verify_sig(&pkg, &content) {
-        Ok(()) => (),
-        Err(e) => eprintln!("Failed to check package signature :: {}", e)
+}
+
+///
+/// Streams `url` into `fname`, hashing it on the way. Nothing is left behind if it doesn't check out
+///
+async fn fetch(pkg: &DebPackage, url: &str, fname: &Path, pb: &ProgressBar) -> Result<()> {
+    let result = async {
+        let response = reqwest::get(url).await?.error_for_status()?;
+        let size = response.content_length().unwrap_or_default();
+
+        let (mut stream, mut downloaded) = (response.bytes_stream(), 0_u64);
+        let (mut file, mut hasher) = (File::create(fname)?, Hasher::new(&pkg.control)?);
+
+        while let Some(item) = stream.next().await {
+            let chunk = item?;
+            hasher.update(&chunk);
+            file.write_all(&chunk)?;
+            let progress = std::cmp::min(downloaded + chunk.len() as u64, size);
+            downloaded = progress;
+            pb.set_position(progress);
+        }
+
+        hasher.verify()
+    }.await;
+
+    if result.is_err() {
+        fs::remove_file(fname).unwrap_or_default();
     }
 
-    let mut pkg = File::create(&fname)?;
-    pkg.write_all(&content)?;
-
-    Ok(PathBuf::from(&fname))
-}
\ No newline at end of file
+    result
+}
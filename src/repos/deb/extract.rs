@@ -3,78 +3,249 @@ use ar::Archive;
 use tar::Archive as tarar;
 use xz2::read::XzDecoder;
 use flate2::read::GzDecoder;
+use bzip2::read::BzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
 
-use std::{fs::{self, File}};
+use std::{fs::{self, File}, path::Path};
 use std::io::{self, prelude::*};
+use std::os::unix::fs::lchown;
 use std::str;
 
-use crate::repos::config::Config;
+use crate::repos::{config::Config, errors::ExtractError};
 use super::package::{DebPackage, PkgKind, Info};
+use super::unpacker::{self, Policy, Refusal, UnpackPolicy};
 
-pub struct Data { pub info_path: String, pub control_path: String }
+pub struct Data { pub data_path: String, pub control_path: String }
 pub struct Package(pub DebPackage, pub Info, pub Data);
 
-fn unpack(filename: &str, dst: &str) -> Result<()> {
-    let file = File::open(&filename)?;
-
-    if filename.ends_with(".tar.gz") {
-        let tar = GzDecoder::new(file);
-        let mut archive = tarar::new(tar);
-        archive.unpack(dst)
-            .with_context(|| format!("Could not unpack {} archive", filename))?;
-    } else if filename.ends_with(".tar.xz") {
-        let tar = XzDecoder::new(file);
-        let mut archive = tarar::new(tar);
-        archive.unpack(dst)
-            .with_context(|| format!("Could not unpack {} archive", filename))?;
-    }
+///
+/// Decodes the `control.tar*`/`data.tar*` `member` as it's read, unpacking it at `dst` as `policy` says
+///
+fn unpack<R: Read>(deb: &str, member: &str, reader: R, dst: &str, policy: &UnpackPolicy) -> Result<Vec<Refusal>> {
+    let tar: Box<dyn Read + '_> = match member.split_once(".tar").map(|(_, ext)| ext) {
+        Some("") => Box::new(reader),
+        Some(".gz") => Box::new(GzDecoder::new(reader)),
+        Some(".xz") => Box::new(XzDecoder::new(reader)),
+        Some(".zst") => Box::new(ZstdDecoder::new(reader)?),
+        Some(".bz2") => Box::new(BzDecoder::new(reader)),
+        _ => anyhow::bail!(ExtractError::Invalid { deb: deb.to_owned(), why: format!("{} is compressed in an unsupported format", member) }),
+    };
+
+    unpacker::unpack(&mut tarar::new(tar), Path::new(dst), policy)
+        .with_context(|| format!("Could not unpack {} archive", member))
+}
 
-    Ok(())
+///
+/// Gives `path` to `uid:gid` without following symlinks
+///
+pub fn keep_owner(path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+    // Only root can give files away, so unprivileged runs keep their own user
+    match lchown(path, Some(uid), Some(gid)) {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Ok(()),
+        result => result,
+    }
 }
 
+///
+/// Unpacks the control files and the data of the .deb at `path` into `config.tmp`, straight from the archive
+///
 pub fn extract(config: &Config, path: &str, name: &str) -> Result<Package> {
-    let mut archive = Archive::new(File::open(path).expect("msg"));
-    let mut bytes: Vec<u8> = Vec::new();
+    // The installed version's control files are still needed until this one is unpacked
+    let info_dest = format!("{}/{}.control", config.tmp, name);
+    let data_dest = format!("{}/{}", config.tmp, name);
 
-    let mut file = File::open(path)
-        .with_context(|| format!("Could not open the file {}", path))?;
-    
-    file.read_to_end(&mut bytes)
-        .with_context(|| format!("Could not read the file {}", path))?;
-    
-    let info_dest = format!("{}/{}", config.info, name);
-    let data_dest = config.tmp.to_string();
-
-    match fs::create_dir_all(&info_dest) {
+    fs::create_dir_all(&info_dest)?;
+    fs::create_dir_all(&data_dest)?;
+
+    match members(path, &info_dest, &data_dest, &config.unpack) {
         Ok(()) => (),
-        _ => panic!("Could not create the directories")
+        Err(e) => {
+            let _ = fs::remove_dir_all(&info_dest);
+            let _ = fs::remove_dir_all(&data_dest);
+            return Err(e);
+        },
     }
 
-    while let Some(entry_result) = archive.next_entry() {
-        let mut entry = entry_result?;
-        
-        let filename = str::from_utf8(entry.header().identifier()).unwrap().to_string();
-        let mut file = File::create(&filename)
-            .with_context(|| "Could not create path file")?;
+    println!("Done");
+    let info = Info::load(Path::new(&info_dest))?;
+    let pkg = DebPackage::new(config, &info, PkgKind::Binary)?;
+
+    Ok(
+        Package(pkg, info, Data { data_path: data_dest, control_path: info_dest })
+    )
+}
+
+///
+/// Goes through the members of the .deb at `path` in the order deb(5) sets: `debian-binary`,
+/// `control.tar*` and `data.tar*`, where only ones starting with `_` may be in between.
+/// Whatever `policy` strips is reported, and anything it rejects fails the whole package
+///
+fn members(path: &str, info_dest: &str, data_dest: &str, policy: &UnpackPolicy) -> Result<()> {
+    let file = File::open(path)
+        .with_context(|| format!("Could not open the file {}", path))?;
+    let mut archive = Archive::new(file);
+    let invalid = |why: String| ExtractError::Invalid { deb: path.to_owned(), why };
+
+    let mut expected = ["debian-binary", "control.tar", "data.tar"].into_iter();
+    let mut next = expected.next();
+    let mut refused = vec![];
+
+    while let Some(entry) = archive.next_entry() {
+        let mut entry = entry.with_context(|| format!("Could not read the file {}", path))?;
+        let member = str::from_utf8(entry.header().identifier())?.trim_end_matches('/').to_owned();
+
+        match next {
+            Some(want) if member.starts_with(want) => (),
+            _ if member.starts_with('_') => continue,
+            Some(want) => anyhow::bail!(invalid(format!("found {} where {} should be", member, want))),
+            None => anyhow::bail!(invalid(format!("unexpected member {}", member))),
+        }
 
-        io::copy(&mut entry, &mut file)
-            .with_context(|| "Could not copy the contents of the file")?;
+        match next {
+            Some("debian-binary") => {
+                let mut version = String::new();
+                entry.read_to_string(&mut version)?;
 
-        match filename.as_ref() {
-            "data.tar.xz"|"data.tar.gz" => unpack(&filename, &data_dest)?,
-            "control.tar.xz"|"control.tar.gz" => unpack(&filename, &info_dest)?,
-            _ => ()
+                // Only the major version matters, as for dpkg
+                if !version.trim().starts_with("2.") {
+                    anyhow::bail!(invalid(format!("unsupported format version {:?}", version.trim())));
+                }
+            },
+            Some("control.tar") => refused.extend(unpack(path, &member, &mut entry, info_dest, policy)?),
+            _ => refused.extend(unpack(path, &member, &mut entry, data_dest, policy)?),
         }
 
-        fs::remove_file(&filename)
-            .with_context(|| format!("Could not remove {}", filename))?;
+        next = expected.next();
     }
 
-    println!("Done");
-    let info = super::package::Info::load(std::path::Path::new(&info_dest))?;
-    let pkg = DebPackage::new(config, &info, PkgKind::Binary)?;
-    
-    Ok(
-        Package(pkg, info, Data { info_path: info_dest, control_path: data_dest })
-    )
+    if let Some(want) = next {
+        anyhow::bail!(invalid(format!("{} is missing", want)));
+    }
+
+    let (rejected, stripped): (Vec<_>, Vec<_>) = refused.into_iter().partition(|refusal| refusal.policy == Policy::Reject);
+    stripped.iter().for_each(|refusal| println!("Warning: stripped {} from {}", refusal, path));
+
+    match rejected.is_empty() {
+        true => Ok(()),
+        false => anyhow::bail!(ExtractError::Hostile { deb: path.to_owned(), refused: rejected.iter().map(Refusal::to_string).collect() }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn tar(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            header.set_cksum();
+            builder.append_data(&mut header, path, contents.as_bytes()).unwrap();
+        }
+
+        builder.into_inner().unwrap()
+    }
+
+    fn compress(ext: &str, data: Vec<u8>) -> Vec<u8> {
+        match ext {
+            "" => data,
+            ".gz" => {
+                let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(&data).unwrap();
+                encoder.finish().unwrap()
+            },
+            ".xz" => {
+                let mut encoder = xz2::write::XzEncoder::new(vec![], 6);
+                encoder.write_all(&data).unwrap();
+                encoder.finish().unwrap()
+            },
+            ".zst" => zstd::stream::encode_all(&data[..], 0).unwrap(),
+            ".bz2" => {
+                let mut encoder = bzip2::write::BzEncoder::new(vec![], bzip2::Compression::default());
+                encoder.write_all(&data).unwrap();
+                encoder.finish().unwrap()
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    ///
+    /// A .deb at `dir` made of `members`
+    ///
+    fn deb(dir: &Path, name: &str, members: Vec<(String, Vec<u8>)>) -> String {
+        let path = dir.join(format!("{}.deb", name));
+        let mut builder = ar::Builder::new(File::create(&path).unwrap());
+        for (member, contents) in members {
+            builder.append(&ar::Header::new(member.into_bytes(), contents.len() as u64), &contents[..]).unwrap();
+        }
+
+        path.display().to_string()
+    }
+
+    fn setup(name: &str) -> (PathBuf, Config) {
+        let dir = std::env::temp_dir().join(format!("opm-extract-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut config = crate::repos::setup().unwrap();
+        config.tmp = dir.display().to_string();
+        (dir, config)
+    }
+
+    const CONTROL: &str = "Package: opm-test\nVersion: 1.0\nArchitecture: all\nMaintainer: opm\nDescription: test\n";
+
+    #[test]
+    fn extract_test() {
+        let (dir, config) = setup("formats");
+        let cwd = fs::read_dir(".").unwrap().count();
+
+        for ext in ["", ".gz", ".xz", ".zst", ".bz2"] {
+            let name = format!("opm-test{}", ext);
+            let path = deb(&dir, &name, vec![
+                (String::from("debian-binary"), b"2.0\n".to_vec()),
+                (format!("control.tar{}", ext), compress(ext, tar(&[("./control", CONTROL)]))),
+                (String::from("_extra"), vec![]),
+                (format!("data.tar{}", ext), compress(ext, tar(&[("./usr/share/opm-test/hello", "hello")]))),
+            ]);
+
+            let Package(pkg, info, data) = extract(&config, &path, &name).unwrap();
+            assert_eq!(pkg.control.package, "opm-test");
+            assert!(info.control.is_some());
+            assert_eq!(fs::read_to_string(Path::new(&data.data_path).join("usr/share/opm-test/hello")).unwrap(), "hello");
+        }
+
+        // Nothing gets written where it's run from
+        assert_eq!(fs::read_dir(".").unwrap().count(), cwd);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_test() {
+        let (dir, config) = setup("invalid");
+        let control = || compress(".gz", tar(&[("./control", CONTROL)]));
+        let data = || compress(".gz", tar(&[]));
+
+        let cases = [
+            ("version", vec![(String::from("debian-binary"), b"3.0\n".to_vec()), (String::from("control.tar.gz"), control()), (String::from("data.tar.gz"), data())]),
+            ("order", vec![(String::from("control.tar.gz"), control()), (String::from("debian-binary"), b"2.0\n".to_vec()), (String::from("data.tar.gz"), data())]),
+            ("missing", vec![(String::from("debian-binary"), b"2.0\n".to_vec()), (String::from("control.tar.gz"), control())]),
+            ("format", vec![(String::from("debian-binary"), b"2.0\n".to_vec()), (String::from("control.tar.lz"), control()), (String::from("data.tar.gz"), data())]),
+        ];
+
+        for (name, members) in cases {
+            let path = deb(&dir, name, members);
+            let err = extract(&config, &path, name).err().unwrap();
+            assert!(err.to_string().starts_with(&format!("{} is not a valid .deb", path)), "{}", err);
+
+            // What got unpacked before it failed doesn't stay around
+            assert!(!dir.join(name).exists() && !dir.join(format!("{}.control", name)).exists());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,195 @@
+use anyhow::Result;
+use std::{collections::HashMap, fs, path::{Component, Path, PathBuf}};
+
+use crate::repos::{config::Config, errors::CacheError};
+use super::database::DEBIAN_INFO;
+
+//
+// Per-package file manifests (`<info>/<pkg>.list`), the same format dpkg uses
+//
+
+fn list(info: &str, name: &str) -> PathBuf {
+    Path::new(info).join(format!("{}.list", name))
+}
+
+#[inline]
+pub fn list_path(config: &Config, name: &str) -> PathBuf {
+    list(&config.info, name)
+}
+
+///
+/// Writes the manifest of `name`, `paths` being every entry unpacked from `data.tar.*`
+///
+pub fn write(config: &Config, name: &str, paths: &[PathBuf]) -> Result<()> {
+    // dpkg always starts with the root itself
+    let mut list = String::from("/.\n");
+    paths.iter().for_each(|path| list.push_str(&format!("{}\n", path.display())));
+
+    fs::write(list_path(config, name), list)?;
+    Ok(())
+}
+
+///
+/// Reads the manifest of `name`, falling back to dpkg's one when sharing its database
+///
+pub fn read(config: &Config, name: &str) -> Result<Vec<PathBuf>> {
+    let mut lists = vec![list_path(config, name)];
+    if config.use_pre_existing_db {
+        lists.push(list(DEBIAN_INFO, name));
+    }
+
+    match lists.into_iter().find(|list| list.exists()) {
+        Some(list) => Ok(parse(&fs::read_to_string(list)?)),
+        None => anyhow::bail!(CacheError::NotFoundError { pkg: format!("{}.list", name), cache: config.info.clone() }),
+    }
+}
+
+///
+/// The manifest of every package, ours first and then dpkg's when sharing its database
+///
+fn manifests(config: &Config) -> Result<Vec<(String, PathBuf)>> {
+    let mut dirs = vec![config.info.as_str()];
+    if config.use_pre_existing_db {
+        dirs.push(DEBIAN_INFO);
+    }
+
+    let mut result: Vec<(String, PathBuf)> = vec![];
+    for dir in dirs.into_iter().filter(|dir| Path::new(dir).exists()) {
+        for entry in fs::read_dir(dir)? {
+            let list = entry?.path();
+            let name = match (list.extension(), list.file_stem()) {
+                (Some(ext), Some(stem)) if ext == "list" => stem.to_string_lossy().to_string(),
+                _ => continue,
+            };
+
+            if !result.iter().any(|(n, _)| *n == name) {
+                result.push((name, list));
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+///
+/// Every package whose manifest has `path` in it
+///
+pub fn owners(config: &Config, path: &Path) -> Result<Vec<String>> {
+    let mut result = vec![];
+    for (name, list) in manifests(config)? {
+        if parse(&fs::read_to_string(&list)?).iter().any(|p| p == path) {
+            result.push(name);
+        }
+    }
+
+    Ok(result)
+}
+
+///
+/// The packages owning each path, out of every manifest at once
+///
+pub fn ownership(config: &Config) -> Result<HashMap<PathBuf, Vec<String>>> {
+    let mut result: HashMap<PathBuf, Vec<String>> = HashMap::new();
+    for (name, list) in manifests(config)? {
+        for path in parse(&fs::read_to_string(&list)?) {
+            result.entry(path).or_default().push(name.clone());
+        }
+    }
+
+    Ok(result)
+}
+
+///
+/// Where `path` really is in `root`, following the symlinks already there as if `root` were `/`.
+/// None if one of them leads out of it
+///
+pub fn resolve(root: &Path, path: &Path) -> Option<PathBuf> {
+    let components = |path: &Path| path.components().rev().map(|component| PathBuf::from(component.as_os_str())).collect::<Vec<_>>();
+    let mut pending = components(path);
+    let mut resolved = PathBuf::new();
+    let mut hops = 0;
+
+    while let Some(next) = pending.pop() {
+        match next.components().next() {
+            Some(Component::ParentDir) if !resolved.pop() => return None,
+            Some(Component::Normal(name)) => {
+                resolved.push(name);
+                if let Ok(target) = fs::read_link(root.join(&resolved)) {
+                    // Same limit as the kernel, so loops end
+                    hops += 1;
+                    if hops > 40 {
+                        return None;
+                    }
+
+                    resolved.pop();
+                    if target.has_root() {
+                        resolved.clear();
+                    }
+                    pending.extend(components(&target));
+                }
+            },
+            _ => (),
+        }
+    }
+
+    Some(root.join(resolved))
+}
+
+///
+/// Where the entry at `path` is in `root`: its directory resolved as `resolve` does, the entry itself
+/// not followed (it may be a symlink getting replaced or removed). None if it leads out of `root`
+///
+pub fn locate(root: &Path, path: &Path) -> Option<PathBuf> {
+    let path = path.strip_prefix("/").unwrap_or(path);
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => Some(resolve(root, parent)?.join(name)),
+        _ => resolve(root, path),
+    }
+}
+
+fn parse(contents: &str) -> Vec<PathBuf> {
+    contents.lines()
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        let list = parse("/.\n/usr\n/usr/bin\n/usr/bin/opm hello\n\n");
+        assert_eq!(list, vec![
+            PathBuf::from("/."),
+            PathBuf::from("/usr"),
+            PathBuf::from("/usr/bin"),
+            PathBuf::from("/usr/bin/opm hello"),
+        ]);
+    }
+
+    #[test]
+    fn resolve_test() {
+        let root = std::env::temp_dir().join(format!("opm-resolve-{}", std::process::id()));
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        std::os::unix::fs::symlink("usr/bin", root.join("bin")).unwrap();
+        std::os::unix::fs::symlink("/etc", root.join("usr/etc")).unwrap();
+        std::os::unix::fs::symlink("../../..", root.join("usr/bin/up")).unwrap();
+        std::os::unix::fs::symlink("loop", root.join("loop")).unwrap();
+
+        // Absolute targets start from the root, not from the host's `/`
+        assert_eq!(resolve(&root, Path::new("/bin/opm")), Some(root.join("usr/bin/opm")));
+        assert_eq!(resolve(&root, Path::new("/usr/etc/shadow")), Some(root.join("etc/shadow")));
+        assert_eq!(resolve(&root, Path::new("/usr/bin/../lib")), Some(root.join("usr/lib")));
+        assert_eq!(resolve(&root, Path::new("/bin/up/etc")), None);
+        assert_eq!(resolve(&root, Path::new("/loop/opm")), None);
+
+        // The entry itself isn't followed
+        assert_eq!(locate(&root, Path::new("/usr/etc")), Some(root.join("usr/etc")));
+        assert_eq!(locate(&root, Path::new("/usr/etc/passwd")), Some(root.join("etc/passwd")));
+        assert_eq!(locate(&root, Path::new("/bin/up/etc/passwd")), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,17 +1,25 @@
 use indicatif::{HumanBytes, MultiProgress,ProgressBar, ProgressStyle, HumanDuration};
 use anyhow::{self, Result};
-use solvent::DepGraph;
 use tokio::time::Instant;
-use std::{path::Path, io::Write};
+use std::{collections::HashMap, path::{Path, PathBuf}, io::{self, Write}, fs};
+use std::{ffi::CString, os::unix::ffi::OsStrExt};
+use std::os::unix::fs::{symlink, FileTypeExt, MetadataExt, PermissionsExt};
 
-///
-/// Debian package install
-///
+//
+// Debian package install
+//
 
-use crate::repos::{errors::{InstallError, CacheError}, deb::{package::{DebPackage, PkgKind}, dependencies::get_dependencies}};
+use crate::repos::{errors::InstallError, deb::package::{ControlFile, DebPackage, PkgKind, Info}};
 use crate::repos::config::Config;
 use super::{extract, download};
-use super::{cache, scripts};
+use super::extract::Data;
+use super::{cache, conffiles, files, remove, triggers};
+use super::diversions::Diversions;
+use super::scripts::{self, Script};
+use super::conffiles::ConfChoice;
+use super::resolver::{self, Request, Transaction, Universe};
+use super::status::{State, Status, Want};
+use super::journal::{self, Journal, Operation, Step};
 use futures::future;
 use async_recursion::async_recursion;
 
@@ -30,25 +38,54 @@ fn user_input() -> Result<()> {
 
 // TODO: Get rid of most of those `clone()` calls
 #[async_recursion]
-pub async fn install(config: &Config, name: &str, force: bool) -> Result<()> {
+pub async fn install(config: &Config, name: &str, force: bool, choice: ConfChoice) -> Result<()> {
     if name.ends_with(".deb") {
         let pkg = extract::extract(config, name, name.rsplit('/').next().unwrap().split(".deb").next().unwrap())?;
         let (pkg, info, data) = (pkg.0, pkg.1, pkg.2);
 
-        if let Some(pkg) = cache::check_installed(config, &pkg.control.package) {
-            println!("{} - {}", pkg.control.package, pkg.control.version);
-            anyhow::bail!(InstallError::AlreadyInstalled(pkg.control.package));
+        // Another version gets upgraded (or downgraded) in place, while one that didn't get configured is tried again
+        let key = pkg.control.key();
+        let previous = cache::find_package(config, &key);
+        let old = previous.as_ref().filter(|old| old.control.status.is_none_or(|status| status.is_installed()));
+        if let Some(old) = old.filter(|old| old.control.version == pkg.control.version && old.control.status.is_none_or(|status| status.state == State::Installed)) {
+            println!("{} - {}", old.control.package, old.control.version);
+            anyhow::bail!(InstallError::AlreadyInstalled(old.control.package.clone()));
+        }
+
+        match &old {
+            Some(old) => println!("Upgrading {} ({} => {}) ...", key, old.control.version, pkg.control.version),
+            None => println!("Installing {} ...", key),
         }
+        let mut journal = Journal::begin(config, Operation::Install, &key)?;
 
-        println!("Installing {} ...", pkg.control.package);
-        scripts::execute_install_pre(&info)?;
-        scripts::execute_install_pos(&info)?;
+        let result = unpack(config, &mut journal, &pkg, &info, &data, previous.as_ref(), choice);
+        fs::remove_dir_all(&data.data_path)?;
+        fs::remove_dir_all(&data.control_path)?;
 
-        finish(Path::new(&data.control_path)).unwrap();
-        cache::add_package(config, pkg)?;
+        if let Err(e) = result {
+            eprintln!("Failed to install {}, rolling back ...", key);
+            drop(journal);
+            journal::recover(config)?;
+            anyhow::bail!(e);
+        }
+        journal.commit()?;
+
+        // Whatever it activates gets processed at the end of the transaction
+        let awaited = triggers::activate(config, &key, &Info::installed(config, &key)?, &files::read(config, &key)?)?;
+
+        // The version it was configured as before, if any
+        let configured = previous.as_ref()
+            .filter(|previous| previous.control.status.is_none_or(|status| status.state != State::NotInstalled))
+            .map(|previous| previous.control.version.as_str());
+        configure(config, &pkg.control, configured)?;
+        triggers::wait(config, &key, &awaited)?;
     } else {
+        // `pkg=version` and `pkg/suite` pick a version themselves
+        let request = name.parse::<Request>()?;
+        let key = request.key();
+
         // TODO: Find out a better way of checking for new packages
-        if let Some(pkg) = cache::check_installed(config, name) {
+        if let Some(pkg) = cache::check_installed(config, &key).filter(|_| !request.explicit()) {
             println!("{} - {}", pkg.control.package, pkg.control.version);
             let new = cache::cache_lookup(config, &pkg.control.package)?.unwrap();
             if new.control.version != pkg.control.version {
@@ -65,80 +102,532 @@ pub async fn install(config: &Config, name: &str, force: bool) -> Result<()> {
         println!("Installing {} for debian ...", name);
         println!("Looking up for dependencies ...");
 
-        if let Some(pkg) = cache::cache_lookup(config, name)? {
-            println!("Done");
-            let mut depgraph = DepGraph::new();
-            let mut tasks = vec![];
+        // `pkg:arch` asks for that architecture in particular, and virtual packages go for their providers
+        let universe = Universe::load(config, &[name])?;
+        let transaction = resolver::resolve(&universe, &[name], force)?;
+        println!("Done");
+
+        let start = Instant::now();
+        apply(config, transaction, force, choice).await?;
+        println!("Installed {} in {}", name, HumanDuration(start.elapsed()));
+    }
+
+    Ok(())
+}
+
+///
+/// Every installed package moved to its candidate, see `resolver::upgrade`
+///
+pub async fn upgrade(config: &Config, full: bool, choice: ConfChoice) -> Result<()> {
+    println!("Calculating upgrade ...");
+    let universe = Universe::load(config, &[])?;
+    let transaction = resolver::upgrade(&universe, full)?;
+    println!("Done");
+
+    if transaction.install.is_empty() && transaction.remove.is_empty() {
+        if !transaction.kept.is_empty() {
+            println!("The following packages have been kept back:");
+            println!(" {}", transaction.kept.join(" "));
+        }
+        println!("Nothing to upgrade");
+        return Ok(());
+    }
+
+    let start = Instant::now();
+    apply(config, transaction, false, choice).await?;
+    println!("Upgraded in {}", HumanDuration(start.elapsed()));
+
+    Ok(())
+}
+
+///
+/// Shows what `transaction` does and, once confirmed, downloads, removes and installs
+///
+async fn apply(config: &Config, transaction: Transaction, force: bool, choice: ConfChoice) -> Result<()> {
+    let installed = cache::db_dump(config)
+        .into_iter()
+        .map(|pkg| (pkg.control.key(), pkg.control))
+        .collect::<HashMap<_, _>>();
+
+    let kib = |pkg: &ControlFile| pkg.installed_size.as_ref().and_then(|size| size.parse::<i64>().ok()).unwrap_or(0) * 1024;
+    let (upgraded, new): (Vec<&ControlFile>, Vec<&ControlFile>) = transaction.install.iter()
+        .partition(|pkg| installed.contains_key(&pkg.key()));
+
+    let list = |what: &str, names: Vec<String>| {
+        if !names.is_empty() {
+            println!("The following {}:", what);
+            println!(" {}", names.join(" "));
+        }
+    };
+
+    list("packages will be REMOVED", transaction.remove.clone());
+    list("NEW packages will be installed", new.iter().map(|pkg| pkg.key()).collect());
+    list("packages will be upgraded", upgraded.iter()
+        .map(|pkg| format!("{} ({} => {})", pkg.key(), installed[&pkg.key()].version, pkg.version))
+        .collect());
+    list("packages have been kept back", transaction.kept.clone());
+    println!("{} upgraded, {} newly installed, {} to remove and {} not upgraded.", upgraded.len(), new.len(), transaction.remove.len(), transaction.kept.len());
+
+    let download = transaction.install.iter().map(|pkg| pkg.size.parse::<u64>().unwrap_or(0)).sum::<u64>();
+    let delta = transaction.install.iter().map(kib).sum::<i64>()
+        - upgraded.iter().map(|pkg| kib(&installed[&pkg.key()])).sum::<i64>()
+        - transaction.remove.iter().filter_map(|key| installed.get(key)).map(kib).sum::<i64>();
+
+    println!("Need to get {} of archives.", HumanBytes(download));
+    match delta < 0 {
+        true => println!("After this operation, {} disk space will be freed.", HumanBytes(delta.unsigned_abs())),
+        false => println!("After this operation, {} of additional disk space will be used.", HumanBytes(delta as u64)),
+    }
+    print!("Do you want to continue? [Y/n] ");
+    user_input()?;
+
+    let mut tasks = vec![];
+    let mp = MultiProgress::new();
+    for pkg in transaction.install.into_iter() {
+        let bar = mp.add(ProgressBar::new(pkg.size.parse::<u64>()?));
+        bar.set_style(ProgressStyle::default_bar()
+            .template(" [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
+            .progress_chars("#>-"));
+
+        tasks.push(download::download(config, DebPackage { control: pkg, kind: PkgKind::Binary }, bar));
+    }
+    let handle = tokio::task::spawn_blocking(move || mp.join().unwrap());
+
+    let downloads = future::join_all(tasks).await.into_iter().collect::<Result<Vec<_>>>();
+    handle.await?;
+
+    // Nothing gets removed or installed unless every package checked out
+    let downloads = downloads?;
+    for name in transaction.remove.iter() {
+        remove::remove(config, name, false)?;
+    }
+
+    for path in downloads {
+        install(config, path.to_str().unwrap(), force, choice).await?;
+    }
+    // Never from under a transaction left to recover
+    if !journal::pending(config) {
+        fs_extra::dir::create(&config.tmp, true)?;
+    }
+
+    Ok(())
+}
+
+///
+/// Every step of unpacking a single package, each one journaled before it happens, its scripts called as dpkg does.
+/// Upgrading from `previous` also takes away whatever it had that `pkg` doesn't ship anymore, but its conffiles
+///
+fn unpack(config: &Config, journal: &mut Journal, pkg: &DebPackage, info: &Info, data: &Data, previous: Option<&DebPackage>, choice: ConfChoice) -> Result<()> {
+    let (name, new) = (&pkg.control.key(), &pkg.control);
+    let version = new.version.as_str();
+
+    // The installed version takes part of the upgrade with its own scripts
+    let old = match previous.filter(|old| old.control.status.is_none_or(|status| status.is_installed())) {
+        Some(old) => Some((&old.control, Info::installed(config, name)?)),
+        None => None,
+    };
+    let leftover = previous.filter(|old| old.control.status.is_some_and(|status| status.state == State::ConfigFiles));
 
-            depgraph.register_dependency(Some(pkg.control.clone()), None);
-            get_dependencies(config, pkg.control.clone(), pkg.control.clone().depends, &mut depgraph, force)?;
+    // Before any script runs, so there's nothing to undo if it can't go on
+    // Diversions know packages by their name alone, as dpkg's do
+    let diverted = (Diversions::load(config)?, new.package.as_str());
+    let replaced = overwrites(config, new, Path::new(&data.data_path), &diverted)?;
+
+    if let Some((old, old_info)) = &old {
+        if let Err(e) = scripts::run(config, Some(&mut *journal), old, old_info, Script::Prerm, &["upgrade", version]) {
+            if !scripts::unwind(config, new, info, Script::Prerm, &["failed-upgrade", &old.version]) {
+                scripts::unwind(config, old, old_info, Script::Postinst, &["abort-upgrade", version]);
+                return Err(e);
+            }
+        }
+    }
+
+    let preinst = match (&old, leftover) {
+        (Some((old, _)), _) => vec!["upgrade", old.version.as_str()],
+        (None, Some(leftover)) => vec!["install", leftover.control.version.as_str()],
+        (None, None) => vec!["install"],
+    };
+    if let Err(e) = scripts::run(config, Some(&mut *journal), new, info, Script::Preinst, &preinst) {
+        match &old {
+            Some((old, old_info)) => {
+                scripts::unwind(config, new, info, Script::Postrm, &["abort-upgrade", &old.version]);
+                scripts::unwind(config, old, old_info, Script::Postinst, &["abort-upgrade", version]);
+            },
+            None => {
+                let mut args = vec!["abort-install"];
+                args.extend(leftover.map(|leftover| leftover.control.version.as_str()));
+                scripts::unwind(config, new, info, Script::Postrm, &args);
+            },
+        }
+        return Err(e);
+    }
+
+    // Whatever the last version (even a removed one) shipped as conffiles, with their md5
+    let stanza = cache::get_stanza(config, name)?;
+    let recorded = stanza.as_deref().map(conffiles::recorded).unwrap_or_default();
+    let conffiles = pkg.control.conffiles.iter().flatten().map(PathBuf::from).collect::<Vec<_>>();
+    let shipped = conffiles::settle(config, journal, Path::new(&data.data_path), &conffiles, &recorded, choice, &diverted)?;
+
+    let mut unpacked = finish(config, journal, Path::new(&data.data_path), &diverted)?;
+    // The ones left as they were are still the package's
+    for path in conffiles.iter().filter(|path| shipped.iter().any(|(p, _)| p == *path)) {
+        let path = diverted.0.target(path, diverted.1).to_path_buf();
+        if !unpacked.contains(&path) {
+            unpacked.push(path);
+        }
+    }
+    disown(config, journal, replaced)?;
+    let list = files::list_path(config, name);
 
-            let pkgs = depgraph.dependencies_of(&Some(pkg.control)).unwrap()
-                .filter_map(|node| node.ok())
-                .flatten().cloned()
-                .collect::<Vec<_>>();
+    if old.is_some() {
+        let obsolete = files::read(config, name)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|path| !unpacked.contains(path) && !recorded.contains_key(path))
+            .collect::<Vec<_>>();
 
-            println!("Installing {} NEW package", pkgs.len());
+        remove::unlink(config, journal, &obsolete)?;
+        if list.exists() {
+            journal.backup(&list, true)?;
+        }
+    }
 
-            let mut total = 0;
-            for pkg in pkgs.iter() {
-                print!(" {}", pkg.package);
-                total += pkg.size.parse::<u64>().unwrap();
+    if let Some((old, old_info)) = &old {
+        if let Err(e) = scripts::run(config, Some(&mut *journal), old, old_info, Script::Postrm, &["upgrade", version]) {
+            if !scripts::unwind(config, new, info, Script::Postrm, &["failed-upgrade", &old.version]) {
+                // The rollback puts the old files back afterwards
+                scripts::unwind(config, old, old_info, Script::Preinst, &["abort-upgrade", version]);
+                scripts::unwind(config, new, info, Script::Postrm, &["abort-upgrade", &old.version]);
+                scripts::unwind(config, old, old_info, Script::Postinst, &["abort-upgrade", version]);
+                return Err(e);
             }
-            println!();
+        }
+    }
+
+    register(config, journal, name, Path::new(&data.control_path))?;
+
+    let mut pkg = pkg.clone();
+    if let Some(stanza) = &stanza {
+        triggers::carry(stanza, &mut pkg.control.paragraph)?;
+    }
+
+    // The old version's, or the one a removal left behind with the conffiles
+    if let Some(stanza) = stanza {
+        journal.log(Step::DbRemoved { pkg: name.to_owned(), stanza })?;
+        cache::rm_package(config, name)?;
+    }
 
-            println!("After this operation, {} of additional disk space will be used.", HumanBytes(total));
-            print!("Do you want to continue? [Y/n] ");
-            user_input()?;
+    journal.log(Step::Placed { path: list })?;
+    files::write(config, name, &unpacked)?;
 
-            let mp = MultiProgress::new();
-            for pkg in pkgs.clone().into_iter() {
-                let bar = mp.add(ProgressBar::new(pkg.size.parse::<u64>()?));
-                bar.set_style(ProgressStyle::default_bar()
-                    .template(" [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
-                    .progress_chars("#>-"));
+    // A held package stays held
+    let want = match old.and_then(|(old, _)| old.status) {
+        Some(status) if status.want == Want::Hold => Want::Hold,
+        _ => Want::Install,
+    };
 
-                tasks.push(download::download(config, DebPackage { control: pkg, kind: PkgKind::Binary }, bar));
+    let field = conffiles::field(&shipped, &recorded);
+    if !field.is_empty() {
+        pkg.control.paragraph.set_lines("Conffiles", &field);
+    }
+
+    journal.log(Step::DbAdded { pkg: name.to_owned() })?;
+    cache::add_package(config, pkg, Status::new(want, State::Unpacked))
+}
+
+///
+/// The files at `data` that other packages own, by package. Those can only be overwritten if `pkg` replaces them,
+/// but for Multi-Arch: same instances of it, which share the ones they have identical
+///
+fn overwrites(config: &Config, pkg: &ControlFile, data: &Path, diverted: &(Diversions, &str)) -> Result<HashMap<String, Vec<PathBuf>>> {
+    let owners = files::ownership(config)?;
+    let mut paths = vec![];
+    walk(data, Path::new("/"), &mut paths)?;
+
+    let key = pkg.key();
+    let mut replaced: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    for (rel, path) in paths.iter().map(|rel| (rel, diverted.0.target(rel, diverted.1))) {
+        for other in owners.get(path).into_iter().flatten().filter(|other| **other != key) {
+            let other_pkg = cache::find_package(config, other);
+            let installed = files::locate(Path::new(&config.root), path).ok_or_else(|| anyhow::anyhow!(InstallError::OutsideRoot(path.display().to_string())))?;
+            if other_pkg.as_ref().is_some_and(|other| other.control.package == pkg.package) && identical(&data.join(rel.strip_prefix("/")?), &installed) {
+                continue;
             }
-            let handle = tokio::task::spawn_blocking(move || mp.join().unwrap());
-
-            let start = Instant::now();
-            for data in future::join_all(tasks).await
-                .into_iter()
-                .filter_map(|r| r.ok())
-                .zip(pkgs.into_iter().map(|ctrl| ctrl.package)) 
-            {
-                let (path, _name) = data;
-                install(config, path.to_str().unwrap(), force).await?;
+
+            let replaces = other_pkg.is_some_and(|other| pkg.replaces.iter()
+                .flatten()
+                .flat_map(|alternatives| alternatives.iter())
+                .any(|relation| relation.name == other.control.package && relation.satisfied_by(&other.control.version)));
+
+            if !replaces {
+                anyhow::bail!(InstallError::FileConflict { pkg: key, other: other.clone(), path: path.display().to_string() });
             }
-            let duration = start.elapsed();
-            println!("Installed {} in {}", name, HumanDuration(duration));
-            fs_extra::dir::create(&config.tmp, true)?;
-            handle.await?;
-        } else {
-            anyhow::bail!(CacheError::NotFoundError { pkg: name.to_owned(), cache: config.cache.clone() });
+            replaced.entry(other.clone()).or_default().push(path.to_path_buf());
         }
     }
 
+    Ok(replaced)
+}
+
+///
+/// Whether `a` and `b` are the same symlink or have the same contents
+///
+fn identical(a: &Path, b: &Path) -> bool {
+    match (fs::symlink_metadata(a), fs::symlink_metadata(b)) {
+        (Ok(x), Ok(y)) if x.file_type().is_symlink() && y.file_type().is_symlink() => fs::read_link(a).ok() == fs::read_link(b).ok(),
+        (Ok(x), Ok(y)) if x.is_file() && y.is_file() => x.len() == y.len() && fs::read(a).ok() == fs::read(b).ok(),
+        _ => false,
+    }
+}
+
+///
+/// Every path under `dir` but the directories, as `rel` is its place in the root
+///
+fn walk(dir: &Path, rel: &Path, paths: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let rel = rel.join(entry.file_name());
+
+        match entry.file_type()?.is_dir() {
+            true => walk(&entry.path(), &rel, paths)?,
+            false => paths.push(rel),
+        }
+    }
+
+    Ok(())
+}
+
+///
+/// Takes the `replaced` files out of the manifests of the packages that had them
+///
+fn disown(config: &Config, journal: &mut Journal, replaced: HashMap<String, Vec<PathBuf>>) -> Result<()> {
+    for (other, paths) in replaced {
+        println!("Replacing files in old package {} ...", other);
+        let remaining = files::read(config, &other)?
+            .into_iter()
+            .filter(|path| *path != Path::new("/.") && !paths.contains(path))
+            .collect::<Vec<_>>();
+
+        let list = files::list_path(config, &other);
+        match list.exists() {
+            true => journal.backup(&list, true)?,
+            false => journal.log(Step::Placed { path: list })?,
+        }
+        files::write(config, &other, &remaining)?;
+    }
+
     Ok(())
 }
 
-fn finish(p: &Path) -> Result<()> {
-    use fs_extra::error::ErrorKind;
-    let options = fs_extra::dir::CopyOptions::new();
-
-    for path in std::fs::read_dir(p)?
-        .filter_map(|entry| entry.ok())
-        .map(|entry| entry.path())
-    {
-        match fs_extra::dir::copy(&path, std::path::Path::new("/tmp/fake_root"), &options) {
-            Ok(_) => (),
-            Err(e) => match e.kind { 
-                ErrorKind::InvalidFolder | ErrorKind::AlreadyExists | ErrorKind::NotFound => continue,
-                _ => panic!("{} -> {:?}", e, path)
+///
+/// Swaps the control files of `name` for the ones staged at `from`
+///
+fn register(config: &Config, journal: &mut Journal, name: &str, from: &Path) -> Result<()> {
+    let dir = Path::new(&config.info).join(name);
+
+    match fs::read_dir(&dir) {
+        Ok(entries) => {
+            for path in entries.map(|entry| entry.map(|entry| entry.path())).collect::<io::Result<Vec<_>>>()? {
+                journal.backup(&path, false)?;
             }
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            journal.log(Step::Placed { path: dir.clone() })?;
+            fs::create_dir_all(&dir)?;
+        },
+        Err(e) => return Err(e.into()),
+    }
+
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            let dst = dir.join(entry.file_name());
+            journal.log(Step::Placed { path: dst.clone() })?;
+            fs::copy(entry.path(), &dst)?;
         }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+///
+/// Runs the postinst of the unpacked `pkg`, `configured` being the version it was configured as before.
+/// If it fails the package is left half-configured, for the next install to try again, and once it
+/// succeeds whatever triggers it still had pending are processed
+///
+fn configure(config: &Config, pkg: &ControlFile, configured: Option<&str>) -> Result<()> {
+    let name = &pkg.key();
+    let want = cache::find_package(config, name)
+        .and_then(|pkg| pkg.control.status)
+        .map_or(Want::Install, |status| status.want);
+
+    cache::set_status(config, name, Status::new(want, State::HalfConfigured))?;
+    scripts::run(config, None, pkg, &Info::installed(config, name)?, Script::Postinst, &["configure", configured.unwrap_or_default()])?;
+
+    let state = match triggers::pending(config, name)?.is_empty() {
+        true => State::Installed,
+        false => State::TriggersPending,
+    };
+    cache::set_status(config, name, Status::new(want, state))
+}
+
+///
+/// Moves the unpacked data into `config.root`, returning every path unpacked. Files `diverted`
+/// for the package being unpacked go where the diversion says, and are listed there
+///
+fn finish(config: &Config, journal: &mut Journal, from: &Path, diverted: &(Diversions, &str)) -> Result<Vec<PathBuf>> {
+    let mut unpacked = vec![];
+    merge(from, Path::new(&config.root), Path::new("/"), journal, &mut unpacked, diverted)?;
+
+    Ok(unpacked)
+}
+
+fn merge(from: &Path, root: &Path, rel: &Path, journal: &mut Journal, unpacked: &mut Vec<PathBuf>, diverted: &(Diversions, &str)) -> Result<()> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let (src, rel) = (entry.path(), rel.join(entry.file_name()));
+        let meta = fs::symlink_metadata(&src)?;
+        // Symlinks already there (like merged-/usr's `/bin`) are walked into, but only within the root
+        let resolve = |path: &Path| files::resolve(root, path).ok_or_else(|| anyhow::anyhow!(InstallError::OutsideRoot(rel.display().to_string())));
+
+        if meta.is_dir() {
+            let dst = resolve(&rel)?;
+            match fs::symlink_metadata(&dst) {
+                Ok(existing) if existing.is_dir() => (),
+                Ok(_) => anyhow::bail!(InstallError::UnexError { msg: format!("{:?} already exists and is not a directory", dst), err: None }),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    journal.log(Step::Placed { path: dst.clone() })?;
+                    fs::create_dir(&dst)?;
+                    extract::keep_owner(&dst, meta.uid(), meta.gid())?;
+                    fs::set_permissions(&dst, fs::Permissions::from_mode(meta.mode()))?;
+                },
+                Err(e) => return Err(e.into()),
+            }
+
+            unpacked.push(rel.clone());
+            merge(&src, root, &rel, journal, unpacked, diverted)?;
+        } else {
+            let rel = diverted.0.target(&rel, diverted.1).to_path_buf();
+            let dst = files::locate(root, &rel).ok_or_else(|| anyhow::anyhow!(InstallError::OutsideRoot(rel.display().to_string())))?;
+
+            // Place it next to the destination first so the swap is a single rename
+            let new = dst.with_file_name(format!("{}.opm-new", dst.file_name().unwrap_or_default().to_string_lossy()));
+            journal.log(Step::Placed { path: new.clone() })?;
+
+            let kind = meta.file_type();
+            if kind.is_symlink() {
+                symlink(fs::read_link(&src)?, &new)?;
+                extract::keep_owner(&new, meta.uid(), meta.gid())?;
+            } else {
+                if kind.is_file() {
+                    fs::copy(&src, &new)?;
+                } else if kind.is_fifo() || kind.is_char_device() || kind.is_block_device() {
+                    // Copying would block on a FIFO or read out of the device, so a new node takes its place
+                    mknod(&new, meta.mode(), meta.rdev())?;
+                } else {
+                    anyhow::bail!(InstallError::UnexError { msg: format!("{:?} is neither a file, a directory, a symlink nor a device", rel), err: None });
+                }
+
+                // chown clears the setuid/setgid bits, so the mode goes last
+                extract::keep_owner(&new, meta.uid(), meta.gid())?;
+                fs::set_permissions(&new, fs::Permissions::from_mode(meta.mode()))?;
+            }
+
+            if fs::symlink_metadata(&dst).is_ok() {
+                journal.backup(&dst, true)?;
+            }
+
+            journal.log(Step::Placed { path: dst.clone() })?;
+            fs::rename(&new, &dst)?;
+            unpacked.push(rel);
+        }
+    }
+
+    Ok(())
+}
+
+fn mknod(path: &Path, mode: u32, rdev: u64) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+
+    // SAFETY: `path` is a valid C string for the duration of the call
+    match unsafe { libc::mknod(path.as_ptr(), mode as libc::mode_t, rdev as libc::dev_t) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn overwrites_test() {
+        let dir = std::env::temp_dir().join(format!("opm-overwrites-{}", std::process::id()));
+        let (info, data) = (dir.join("info"), dir.join("data"));
+        fs::create_dir_all(&info).unwrap();
+        fs::create_dir_all(data.join("usr/bin")).unwrap();
+        fs::write(data.join("usr/bin/opm"), "").unwrap();
+        fs::write(data.join("usr/bin/opm-new"), "").unwrap();
+
+        let mut config = crate::repos::setup().unwrap();
+        config.info = info.display().to_string();
+        config.db = dir.join("db").display().to_string();
+        fs::write(&config.db, "Package: opm-old\nStatus: install ok installed\nVersion: 1.0\nArchitecture: all\nMaintainer: opm\nDescription: test\n").unwrap();
+        fs::write(info.join("opm-old.list"), "/.\n/usr\n/usr/bin\n/usr/bin/opm\n").unwrap();
+
+        let pkg = |replaces: &str| ControlFile::new(&config, &format!("Package: opm\nVersion: 2.0\nArchitecture: all\nMaintainer: opm\nDescription: test\n{}", replaces)).unwrap();
+        let diverted = (Diversions::load(&config).unwrap(), "opm");
+
+        // Shared directories are fine, a shared file isn't
+        let err = overwrites(&config, &pkg(""), &data, &diverted).unwrap_err();
+        assert_eq!(err.to_string(), "opm is trying to overwrite \"/usr/bin/opm\", which is also in package opm-old");
+        assert!(overwrites(&config, &pkg("Replaces: opm-old (<< 1.0)"), &data, &diverted).is_err());
+
+        let replaced = overwrites(&config, &pkg("Replaces: opm-old (<< 2.0)"), &data, &diverted).unwrap();
+        assert_eq!(replaced, HashMap::from([(String::from("opm-old"), vec![PathBuf::from("/usr/bin/opm")])]));
+
+        // Multi-Arch: same instances share what they have identical, and only that
+        let (root, data) = (dir.join("root"), dir.join("data-i386"));
+        config.root = root.display().to_string();
+        for (dir, copyright) in [(&root, "GPL"), (&data, "GPL")] {
+            fs::create_dir_all(dir.join("usr/share/doc/opm")).unwrap();
+            fs::write(dir.join("usr/share/doc/opm/copyright"), copyright).unwrap();
+        }
+        fs::write(&config.db, "Package: opm\nStatus: install ok installed\nMulti-Arch: same\nVersion: 2.0\nArchitecture: amd64\nMaintainer: opm\nDescription: test\n").unwrap();
+        fs::write(info.join("opm:amd64.list"), "/.\n/usr\n/usr/share\n/usr/share/doc\n/usr/share/doc/opm\n/usr/share/doc/opm/copyright\n").unwrap();
+
+        let i386 = ControlFile::new(&config, "Package: opm\nMulti-Arch: same\nVersion: 2.0\nArchitecture: i386\nMaintainer: opm\nDescription: test\n").unwrap();
+        assert!(overwrites(&config, &i386, &data, &diverted).unwrap().is_empty());
+
+        fs::write(data.join("usr/share/doc/opm/copyright"), "MIT").unwrap();
+        let err = overwrites(&config, &i386, &data, &diverted).unwrap_err();
+        assert_eq!(err.to_string(), "opm:i386 is trying to overwrite \"/usr/share/doc/opm/copyright\", which is also in package opm:amd64");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merge_fifo_test() {
+        let dir = std::env::temp_dir().join(format!("opm-merge-{}", std::process::id()));
+        let (data, root) = (dir.join("data"), dir.join("root"));
+        fs::create_dir_all(data.join("run")).unwrap();
+        fs::create_dir_all(&root).unwrap();
+        mknod(&data.join("run/opm-fifo"), libc::S_IFIFO, 0).unwrap();
+        fs::set_permissions(data.join("run/opm-fifo"), fs::Permissions::from_mode(0o620)).unwrap();
+
+        let mut config = crate::repos::setup().unwrap();
+        config.tmp = dir.join("tmp").display().to_string();
+        let diverted = (Diversions::load(&config).unwrap(), "opm");
+
+        // Copied, it would block until something writes to it
+        let (mut journal, mut unpacked) = (Journal::begin(&config, Operation::Install, "opm").unwrap(), vec![]);
+        merge(&data, &root, Path::new("/"), &mut journal, &mut unpacked, &diverted).unwrap();
+        journal.commit().unwrap();
+
+        let meta = fs::symlink_metadata(root.join("run/opm-fifo")).unwrap();
+        assert!(meta.file_type().is_fifo());
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o620);
+        assert_eq!(unpacked, vec![PathBuf::from("/run"), PathBuf::from("/run/opm-fifo")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,254 @@
+use anyhow::Result;
+use std::{fs::{self, File, OpenOptions}, io::{self, BufRead, BufReader, ErrorKind, Write}, path::{Path, PathBuf}};
+use std::os::unix::fs::{symlink, DirBuilderExt, MetadataExt};
+
+use crate::repos::{config::Config, errors::JournalError};
+use super::{cache, extract};
+
+//
+// Write-ahead journal of the install/remove steps, so a crash can be undone
+//
+
+const JOURNAL: &str = "journal";
+const BACKUPS: &str = "journal.d";
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Operation {
+    Install,
+    Remove,
+}
+
+///
+/// A single step, always logged *before* it is carried out
+///
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Step {
+    Begin      { op: Operation, pkg: String },
+    Placed     { path: PathBuf },
+    BackedUp   { path: PathBuf, backup: PathBuf },
+    DirRemoved { path: PathBuf, mode: u32 },
+    DbAdded    { pkg: String },
+    DbRemoved  { pkg: String, stanza: String },
+    ScriptRun  { pkg: String, script: String },
+    Commit,
+}
+
+pub struct Journal {
+    file: File,
+    path: PathBuf,
+    backups: PathBuf,
+    count: usize,
+}
+
+impl Journal {
+    pub fn begin(config: &Config, op: Operation, pkg: &str) -> Result<Self> {
+        settled(config)?;
+
+        let backups = Path::new(&config.tmp).join(BACKUPS);
+        fs::create_dir_all(&backups)?;
+
+        let path = journal_path(config);
+        let file = OpenOptions::new().create_new(true).append(true).open(&path)?;
+
+        let mut journal = Self { file, path, backups, count: 0 };
+        journal.log(Step::Begin { op, pkg: pkg.to_owned() })?;
+
+        Ok(journal)
+    }
+
+    pub fn log(&mut self, step: Step) -> Result<()> {
+        writeln!(self.file, "{}", serde_json::to_string(&step)?)?;
+        // Nothing happens before the step hits the disk
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+
+    ///
+    /// Saves a copy of `path` before it gets overwritten (`keep`) or removed
+    ///
+    pub fn backup(&mut self, path: &Path, keep: bool) -> Result<()> {
+        self.count += 1;
+        let backup = self.backups.join(self.count.to_string());
+
+        self.log(Step::BackedUp { path: path.to_owned(), backup: backup.clone() })?;
+        transfer(path, &backup, keep)?;
+
+        Ok(())
+    }
+
+    ///
+    /// Everything went fine, so the backups aren't needed anymore
+    ///
+    pub fn commit(mut self) -> Result<()> {
+        self.log(Step::Commit)?;
+        cleanup(&self.path, &self.backups)
+    }
+}
+
+fn journal_path(config: &Config) -> PathBuf {
+    Path::new(&config.tmp).join(JOURNAL)
+}
+
+#[inline]
+pub fn pending(config: &Config) -> bool {
+    journal_path(config).exists()
+}
+
+///
+/// Fails while a transaction is left to recover, its journal and backups living in `config.tmp`
+///
+pub fn settled(config: &Config) -> Result<()> {
+    if pending(config) {
+        anyhow::bail!(JournalError::Pending(journal_path(config).display().to_string()));
+    }
+
+    Ok(())
+}
+
+fn cleanup(journal: &Path, backups: &Path) -> Result<()> {
+    match fs::remove_dir_all(backups) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => (),
+    }
+
+    fs::remove_file(journal)?;
+    Ok(())
+}
+
+///
+/// Finishes a committed journal or undoes an interrupted one
+///
+pub fn recover(config: &Config) -> Result<()> {
+    let path = journal_path(config);
+    let backups = Path::new(&config.tmp).join(BACKUPS);
+
+    if !path.exists() {
+        println!("Nothing to recover");
+        return Ok(());
+    }
+
+    let mut steps = vec![];
+    for line in BufReader::new(File::open(&path)?).lines() {
+        // A crash halfway through writing leaves the last line cut, it never happened
+        match serde_json::from_str::<Step>(&line?) {
+            Ok(step) => steps.push(step),
+            Err(_) => break,
+        }
+    }
+
+    if steps.last() == Some(&Step::Commit) {
+        println!("Last transaction was complete, cleaning it up ...");
+        return cleanup(&path, &backups);
+    }
+
+    for step in steps.into_iter().rev() {
+        match step {
+            Step::Begin { op, pkg } => println!("Rolled back {:?} of {}", op, pkg),
+            Step::Placed { path } => unplace(&path)?,
+            Step::BackedUp { path, backup } => {
+                if fs::symlink_metadata(&backup).is_ok() {
+                    transfer(&backup, &path, false)?;
+                }
+            },
+            Step::DirRemoved { path, mode } => {
+                match fs::DirBuilder::new().mode(mode).create(&path) {
+                    Err(e) if e.kind() != ErrorKind::AlreadyExists => return Err(e.into()),
+                    _ => (),
+                }
+            },
+            Step::DbAdded { pkg } => {
+                if cache::check_installed(config, &pkg).is_some() {
+                    cache::rm_package(config, &pkg)?;
+                }
+            },
+            // Whatever the entry became since, it goes back to how it was
+            Step::DbRemoved { stanza, .. } => cache::add_stanza(config, &stanza)?,
+            Step::ScriptRun { pkg, script } => {
+                eprintln!("The {} script of {} had already run, its changes can't be undone", script, pkg);
+            },
+            Step::Commit => (),
+        }
+    }
+
+    cleanup(&path, &backups)
+}
+
+fn unplace(path: &Path) -> io::Result<()> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Err(e) if e.kind() != ErrorKind::NotFound && e.kind() != ErrorKind::DirectoryNotEmpty => Err(e),
+        _ => Ok(()),
+    }
+}
+
+///
+/// Moves (or links, when `keep`ing the original) `from` to `to`, copying across filesystems
+///
+fn transfer(from: &Path, to: &Path, keep: bool) -> io::Result<()> {
+    let result = if keep { fs::hard_link(from, to) } else { fs::rename(from, to) };
+
+    match result {
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            let meta = fs::symlink_metadata(from)?;
+            if meta.file_type().is_symlink() {
+                symlink(fs::read_link(from)?, to)?;
+            } else {
+                fs::copy(from, to)?;
+            }
+            extract::keep_owner(to, meta.uid(), meta.gid())?;
+
+            if !keep {
+                fs::remove_file(from)?;
+            }
+            Ok(())
+        },
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::repos;
+    use super::*;
+
+    #[test]
+    fn recover_interrupted_test() {
+        let dir = std::env::temp_dir().join("opm-journal-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut config = repos::setup().unwrap();
+        config.tmp = dir.join("tmp").display().to_string();
+        fs::create_dir_all(&config.tmp).unwrap();
+
+        let (placed, replaced) = (dir.join("placed"), dir.join("replaced"));
+        fs::write(&replaced, "old").unwrap();
+
+        let mut journal = Journal::begin(&config, Operation::Install, "opm-test").unwrap();
+        journal.log(Step::Placed { path: placed.clone() }).unwrap();
+        fs::write(&placed, "new").unwrap();
+        journal.backup(&replaced, true).unwrap();
+        journal.log(Step::Placed { path: replaced.clone() }).unwrap();
+        fs::write(dir.join("replaced.new"), "new").unwrap();
+        fs::rename(dir.join("replaced.new"), &replaced).unwrap();
+        // Crashed here, before committing
+        drop(journal);
+
+        assert!(Journal::begin(&config, Operation::Remove, "opm-test").is_err());
+        // Nor can its backups be cleared away
+        assert!(super::super::clear(&config).unwrap_err().to_string().contains("run `opm recover` first"));
+        assert!(pending(&config));
+        recover(&config).unwrap();
+
+        assert!(!placed.exists());
+        assert_eq!(fs::read_to_string(&replaced).unwrap(), "old");
+        assert!(!pending(&config));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
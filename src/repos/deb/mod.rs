@@ -2,20 +2,39 @@ mod extract;
 mod install;
 mod download;
 mod update;
-mod dependencies;
+mod resolver;
 mod signatures;
-mod scripts;
 mod remove;
+mod journal;
 
+pub mod alternatives;
+pub mod arch;
 pub mod cache;
+pub mod conffiles;
+pub mod deb822;
+pub mod diversions;
+pub mod openpgp;
+pub mod files;
 pub mod package;
+pub mod pkgcache;
+pub mod policy;
+pub mod relation;
+pub mod scripts;
+pub mod release;
 pub mod sources;
+pub mod status;
+pub mod triggers;
+pub mod unpacker;
 
-pub use install::install;
+pub use install::{install, upgrade};
 pub use update::{update, clear};
-pub use cache::{db_dump, check_installed};
+pub use cache::db_dump;
 pub use remove::remove;
+pub use journal::recover;
 
 pub mod database {
     pub const DEBIAN_DATABASE: &str = "/var/lib/dpkg/status";
+    pub const DEBIAN_INFO: &str = "/var/lib/dpkg/info";
+    pub const DEBIAN_DIVERSIONS: &str = "/var/lib/dpkg/diversions";
+    pub const DEBIAN_ALTERNATIVES: &str = "/var/lib/dpkg/alternatives";
 }
\ No newline at end of file
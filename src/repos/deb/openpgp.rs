@@ -0,0 +1,767 @@
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signature as Ed25519Signature, Verifier, VerifyingKey};
+use rsa::{BigUint, Pkcs1v15Sign, RsaPublicKey};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::{fs, path::Path};
+
+use crate::repos::errors::ReleaseError;
+
+//
+// The bits of OpenPGP (RFC 4880 / RFC 9580) needed to check a clearsigned `InRelease`:
+// v4 keys and signatures made with RSA or Ed25519
+//
+
+const TAG_SIGNATURE: u8 = 2;
+const TAG_PUBLIC_KEY: u8 = 6;
+const TAG_USER_ID: u8 = 13;
+const TAG_PUBLIC_SUBKEY: u8 = 14;
+
+const CANONICAL_TEXT: u8 = 0x01;
+// Generic, persona, casual and positive certifications of a user ID
+const CERTIFICATIONS: std::ops::RangeInclusive<u8> = 0x10..=0x13;
+const SUBKEY_BINDING: u8 = 0x18;
+const DIRECT_KEY: u8 = 0x1f;
+const KEY_REVOCATION: u8 = 0x20;
+const SUBKEY_REVOCATION: u8 = 0x28;
+
+const SUB_CREATED: u8 = 2;
+const SUB_EXPIRES: u8 = 3;
+const SUB_KEY_EXPIRES: u8 = 9;
+const SUB_ISSUER: u8 = 16;
+const SUB_KEY_FLAGS: u8 = 27;
+const SUB_ISSUER_FINGERPRINT: u8 = 33;
+const CAN_SIGN: u8 = 0x02;
+
+// Subpackets that may be marked critical, the ones left out (if critical) make the signature invalid.
+// Algorithm and key server preferences, primary user ID and features don't change what a signature means
+const UNDERSTOOD: &[u8] = &[SUB_CREATED, SUB_EXPIRES, SUB_KEY_EXPIRES, 11, SUB_ISSUER, 21, 22, 23, 25, SUB_KEY_FLAGS, 30, SUB_ISSUER_FINGERPRINT];
+
+// 1.3.6.1.4.1.11591.15.1
+const ED25519_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0xda, 0x47, 0x0f, 0x01];
+
+// DER encoded DigestInfo headers, what PKCS#1 v1.5 signs is one of those followed by the digest
+const SHA256_INFO: &[u8] = &[0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05, 0x00, 0x04, 0x20];
+const SHA384_INFO: &[u8] = &[0x30, 0x41, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02, 0x05, 0x00, 0x04, 0x30];
+const SHA512_INFO: &[u8] = &[0x30, 0x51, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03, 0x05, 0x00, 0x04, 0x40];
+
+fn invalid(why: &str) -> anyhow::Error {
+    anyhow::anyhow!(ReleaseError::Invalid(why.to_owned()))
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        match self.data.get(self.pos..self.pos + len) {
+            Some(bytes) => {
+                self.pos += len;
+                Ok(bytes)
+            },
+            None => Err(invalid("truncated packet")),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes([self.u8()?, self.u8()?, self.u8()?, self.u8()?]))
+    }
+
+    ///
+    /// A multiprecision integer: its length in bits, then the big-endian bytes
+    ///
+    fn mpi(&mut self) -> Result<Vec<u8>> {
+        let bits = self.u16()? as usize;
+        Ok(self.take(bits.div_ceil(8))?.to_vec())
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+}
+
+///
+/// Splits `data` into `(tag, body)` packets, either old or new format
+///
+fn packets(data: &[u8]) -> Result<Vec<(u8, &[u8])>> {
+    let mut reader = Reader::new(data);
+    let mut result = vec![];
+
+    while reader.remaining() > 0 {
+        let ctb = reader.u8()?;
+        if ctb & 0x80 == 0 {
+            return Err(invalid("not an OpenPGP packet"));
+        }
+
+        let (tag, len) = if ctb & 0x40 != 0 {
+            let len = match reader.u8()? as usize {
+                len @ 0..=191 => len,
+                len @ 192..=223 => ((len - 192) << 8) + reader.u8()? as usize + 192,
+                255 => reader.u32()? as usize,
+                _ => return Err(invalid("partial body lengths are not supported")),
+            };
+            (ctb & 0x3f, len)
+        } else {
+            let len = match ctb & 0x03 {
+                0 => reader.u8()? as usize,
+                1 => reader.u16()? as usize,
+                2 => reader.u32()? as usize,
+                _ => reader.remaining(),
+            };
+            ((ctb >> 2) & 0x0f, len)
+        };
+
+        result.push((tag, reader.take(len)?));
+    }
+
+    Ok(result)
+}
+
+///
+/// `(type, critical, data)` of every signature subpacket in `area`
+///
+fn subpackets(area: &[u8]) -> Result<Vec<(u8, bool, &[u8])>> {
+    let mut reader = Reader::new(area);
+    let mut result = vec![];
+
+    while reader.remaining() > 0 {
+        let len = match reader.u8()? as usize {
+            len @ 0..=191 => len,
+            len @ 192..=254 => ((len - 192) << 8) + reader.u8()? as usize + 192,
+            _ => reader.u32()? as usize,
+        };
+
+        let data = reader.take(len)?;
+        match data.split_first() {
+            // The top bit only says whether it's critical
+            Some((kind, data)) => result.push((kind & 0x7f, kind & 0x80 != 0, data)),
+            None => return Err(invalid("empty subpacket")),
+        }
+    }
+
+    Ok(result)
+}
+
+///
+/// The decoded contents of every `-----BEGIN <kind>-----` block in `text`
+///
+fn dearmor(text: &str, kind: &str) -> Result<Vec<Vec<u8>>> {
+    let (begin, end) = (format!("-----BEGIN {}-----", kind), format!("-----END {}-----", kind));
+    let lines = text.lines().map(|line| line.trim_end()).collect::<Vec<_>>();
+    let mut result = vec![];
+    let mut i = 0;
+
+    while i < lines.len() {
+        if lines[i] != begin {
+            i += 1;
+            continue;
+        }
+        i += 1;
+
+        // Armor headers (`Key: value`), then a blank line
+        while i < lines.len() && lines[i].contains(": ") {
+            i += 1;
+        }
+        if lines.get(i) == Some(&"") {
+            i += 1;
+        }
+
+        let mut body = String::new();
+        loop {
+            match lines.get(i) {
+                Some(line) if *line == end => break,
+                // The CRC24 checksum, which is optional
+                Some(line) if line.starts_with('=') => (),
+                Some(line) => body.push_str(line),
+                None => return Err(invalid(&format!("unterminated {}", kind))),
+            }
+            i += 1;
+        }
+
+        result.push(STANDARD.decode(body).map_err(|e| invalid(&e.to_string()))?);
+    }
+
+    Ok(result)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Material {
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    Ed25519([u8; 32]),
+    Unsupported(u8),
+}
+
+#[derive(Debug, Clone)]
+pub struct Key {
+    pub fingerprint: Vec<u8>,
+    ///
+    /// Fingerprint of the primary key, its own one for primary keys
+    ///
+    pub primary: Vec<u8>,
+    body: Vec<u8>,
+    material: Material,
+    created: u64,
+    ///
+    /// When its newest self-signature (or binding signature) says it expires
+    ///
+    expires: Option<u64>,
+    revoked: bool,
+}
+
+impl Key {
+    ///
+    /// Parses a (sub)key packet, `None` being any version other than 4
+    ///
+    fn parse(body: &[u8], primary: Option<&Key>) -> Result<Option<Self>> {
+        let mut reader = Reader::new(body);
+        if reader.u8()? != 4 {
+            return Ok(None);
+        }
+
+        let created = reader.u32()? as u64;
+        let algo = reader.u8()?;
+        let material = match algo {
+            1 | 3 => Material::Rsa { n: reader.mpi()?, e: reader.mpi()? },
+            22 => {
+                let len = reader.u8()? as usize;
+                let oid = reader.take(len)?;
+                let point = reader.mpi()?;
+
+                // Native point format, 0x40 followed by the actual key
+                match (oid == ED25519_OID, point.split_first()) {
+                    (true, Some((0x40, key))) => Material::Ed25519(key.try_into().map_err(|_| invalid("bad Ed25519 key"))?),
+                    _ => Material::Unsupported(algo),
+                }
+            },
+            27 => Material::Ed25519(reader.take(32)?.try_into().map_err(|_| invalid("bad Ed25519 key"))?),
+            _ => Material::Unsupported(algo),
+        };
+
+        let mut sha1 = Sha1::new();
+        sha1.update(framed(body));
+        let fingerprint = sha1.finalize().to_vec();
+
+        Ok(Some(Self {
+            primary: primary.map_or_else(|| fingerprint.clone(), |p| p.fingerprint.clone()),
+            fingerprint,
+            body: body.to_vec(),
+            material,
+            created,
+            expires: None,
+            revoked: false,
+        }))
+    }
+
+    ///
+    /// The long key id, the last 8 bytes of the fingerprint
+    ///
+    pub fn id(&self) -> &[u8] {
+        &self.fingerprint[12..]
+    }
+
+    fn issued(&self, sig: &Signature) -> bool {
+        match &sig.issuer {
+            Some(issuer) if issuer.len() == 8 => self.id() == issuer.as_slice(),
+            Some(issuer) => self.fingerprint == *issuer,
+            None => false,
+        }
+    }
+}
+
+///
+/// How a key is hashed when signing it: 0x99, its length and its body
+///
+fn framed(body: &[u8]) -> Vec<u8> {
+    let mut result = vec![0x99];
+    result.extend((body.len() as u16).to_be_bytes());
+    result.extend(body);
+    result
+}
+
+#[derive(Debug, Clone)]
+struct Signature {
+    kind: u8,
+    algo: u8,
+    hash: u8,
+    ///
+    /// From the version up to the end of the hashed subpackets, all of it gets hashed
+    ///
+    hashed: Vec<u8>,
+    ///
+    /// Either the long key id or the fingerprint of who made it
+    ///
+    issuer: Option<Vec<u8>>,
+    flags: Option<u8>,
+    created: Option<u64>,
+    ///
+    /// Seconds after its creation it expires, and the key it binds after the key's own creation
+    ///
+    expires: Option<u64>,
+    key_expires: Option<u64>,
+    ///
+    /// The first critical subpacket not understood
+    ///
+    critical: Option<u8>,
+    left16: [u8; 2],
+    mpis: Vec<Vec<u8>>,
+}
+
+impl Signature {
+    ///
+    /// Parses a signature packet, `None` being any version other than 4
+    ///
+    fn parse(body: &[u8]) -> Result<Option<Self>> {
+        let mut reader = Reader::new(body);
+        if reader.u8()? != 4 {
+            return Ok(None);
+        }
+
+        let (kind, algo, hash) = (reader.u8()?, reader.u8()?, reader.u8()?);
+        let len = reader.u16()? as usize;
+        let hashed_area = reader.take(len)?;
+        let hashed = body[..6 + len].to_vec();
+        let len = reader.u16()? as usize;
+        let unhashed_area = reader.take(len)?;
+        let left16 = [reader.u8()?, reader.u8()?];
+
+        let mpis = match algo {
+            1 | 3 => vec![reader.mpi()?],
+            22 => vec![reader.mpi()?, reader.mpi()?],
+            27 => vec![reader.take(64)?.to_vec()],
+            _ => vec![],
+        };
+
+        let mut sig = Self { kind, algo, hash, hashed, issuer: None, flags: None, created: None, expires: None, key_expires: None, critical: None, left16, mpis };
+        let time = |data: &[u8]| data.try_into().ok().map(|data| u32::from_be_bytes(data) as u64);
+
+        // The issuer isn't always hashed, but a wrong one just won't verify. Times only count when hashed
+        for (area, hashed) in [(hashed_area, true), (unhashed_area, false)] {
+            for (kind, critical, data) in subpackets(area)? {
+                match kind {
+                    SUB_ISSUER_FINGERPRINT if data.len() > 1 => sig.issuer = Some(data[1..].to_vec()),
+                    SUB_ISSUER if data.len() == 8 && sig.issuer.is_none() => sig.issuer = Some(data.to_vec()),
+                    SUB_KEY_FLAGS if hashed && !data.is_empty() => sig.flags = Some(data[0]),
+                    SUB_CREATED if hashed => sig.created = time(data),
+                    // Zero means it never expires
+                    SUB_EXPIRES if hashed => sig.expires = time(data).filter(|secs| *secs != 0),
+                    SUB_KEY_EXPIRES if hashed => sig.key_expires = time(data).filter(|secs| *secs != 0),
+                    kind if critical && !UNDERSTOOD.contains(&kind) => sig.critical = sig.critical.or(Some(kind)),
+                    _ => (),
+                }
+            }
+        }
+
+        Ok(Some(sig))
+    }
+
+    ///
+    /// Checks it was made by `key` over `data` (everything hashed before the signature's own trailer)
+    ///
+    fn verify(&self, key: &Key, data: &[&[u8]]) -> Result<()> {
+        if let Some(kind) = self.critical {
+            return Err(invalid(&format!("unknown critical subpacket {}", kind)));
+        }
+
+        let mut trailer = vec![0x04, 0xff];
+        trailer.extend((self.hashed.len() as u32).to_be_bytes());
+
+        let parts = data.iter().copied().chain([self.hashed.as_slice(), trailer.as_slice()]);
+        let (digest, info) = match self.hash {
+            8 => (hash::<Sha256>(parts), SHA256_INFO),
+            9 => (hash::<Sha384>(parts), SHA384_INFO),
+            10 => (hash::<Sha512>(parts), SHA512_INFO),
+            2 => return Err(invalid("SHA1 signatures are not accepted")),
+            algo => return Err(invalid(&format!("unsupported hash algorithm {}", algo))),
+        };
+
+        if digest[..2] != self.left16 {
+            return Err(invalid("digest mismatch"));
+        }
+
+        match (&key.material, self.algo, self.mpis.as_slice()) {
+            (Material::Rsa { n, e }, 1 | 3, [sig]) => {
+                // Leading zeros are dropped from the MPI, but PKCS#1 wants the full width
+                let mut padded = vec![0; n.len().saturating_sub(sig.len())];
+                padded.extend(sig);
+
+                RsaPublicKey::new(BigUint::from_bytes_be(n), BigUint::from_bytes_be(e))?
+                    .verify(Pkcs1v15Sign::new_unprefixed(), &[info, &digest].concat(), &padded)?;
+            },
+            (Material::Ed25519(point), 22 | 27, mpis) => {
+                let mut sig = [0u8; 64];
+                match mpis {
+                    [raw] if raw.len() == 64 => sig.copy_from_slice(raw),
+                    [r, s] if r.len() <= 32 && s.len() <= 32 => {
+                        sig[32 - r.len()..32].copy_from_slice(r);
+                        sig[64 - s.len()..].copy_from_slice(s);
+                    },
+                    _ => return Err(invalid("bad Ed25519 signature")),
+                }
+
+                VerifyingKey::from_bytes(point)?.verify(&digest, &Ed25519Signature::from_bytes(&sig))?;
+            },
+            (_, algo, _) => return Err(invalid(&format!("unsupported public key algorithm {}", algo))),
+        }
+
+        Ok(())
+    }
+}
+
+impl Signature {
+    fn expired(&self, now: u64) -> bool {
+        matches!((self.created, self.expires), (Some(created), Some(expires)) if created + expires <= now)
+    }
+}
+
+fn hash<'a, D: Digest>(parts: impl Iterator<Item = &'a [u8]>) -> Vec<u8> {
+    let mut digest = D::new();
+    parts.for_each(|part| digest.update(part));
+    digest.finalize().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+///
+/// Trusted public keys, primary ones and their signing subkeys
+///
+#[derive(Debug, Clone, Default)]
+pub struct Keyring {
+    keys: Vec<Key>,
+}
+
+impl Keyring {
+    ///
+    /// Reads binary (`.gpg`) or armored (`.asc`) keys. Subkeys are only kept
+    /// when their primary key signed them for signing
+    ///
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let blocks = match std::str::from_utf8(data) {
+            Ok(text) if text.contains("-----BEGIN PGP PUBLIC KEY BLOCK-----") => dearmor(text, "PGP PUBLIC KEY BLOCK")?,
+            _ => vec![data.to_vec()],
+        };
+
+        let mut keyring = Self::default();
+        for block in blocks.iter() {
+            // Indices in `keyring.keys` of the current primary key and of its last bound subkey
+            let (mut primary, mut bound): (Option<usize>, Option<usize>) = (None, None);
+            let (mut subkey, mut user_id, mut newest): (Option<Key>, Option<&[u8]>, u64) = (None, None, 0);
+
+            for (tag, body) in packets(block)? {
+                match tag {
+                    TAG_PUBLIC_KEY => {
+                        (subkey, bound, user_id, newest) = (None, None, None, 0);
+                        primary = Key::parse(body, None)?.map(|key| {
+                            keyring.keys.push(key);
+                            keyring.keys.len() - 1
+                        });
+                    },
+                    TAG_USER_ID => user_id = Some(body),
+                    TAG_PUBLIC_SUBKEY => {
+                        (bound, user_id) = (None, None);
+                        subkey = match primary {
+                            Some(primary) => Key::parse(body, Some(&keyring.keys[primary]))?,
+                            None => None,
+                        };
+                    },
+                    TAG_SIGNATURE => {
+                        let (primary, sig) = match (primary, Signature::parse(body)?) {
+                            (Some(primary), Some(sig)) => (primary, sig),
+                            _ => continue,
+                        };
+                        let key = &keyring.keys[primary];
+                        let framed_key = framed(&key.body);
+
+                        match sig.kind {
+                            KEY_REVOCATION if sig.verify(key, &[&framed_key]).is_ok() => keyring.keys[primary].revoked = true,
+                            // Only its own signatures say when it expires, the newest one standing
+                            kind if kind == DIRECT_KEY || CERTIFICATIONS.contains(&kind) => {
+                                let certified = match (kind, user_id) {
+                                    (DIRECT_KEY, _) => vec![],
+                                    (_, Some(user_id)) => [&[0xb4][..], &(user_id.len() as u32).to_be_bytes(), user_id].concat(),
+                                    (_, None) => continue,
+                                };
+
+                                let created = sig.created.unwrap_or_default();
+                                if key.issued(&sig) && created >= newest && sig.verify(key, &[&framed_key, &certified]).is_ok() {
+                                    newest = created;
+                                    keyring.keys[primary].expires = sig.key_expires.map(|secs| key.created + secs);
+                                }
+                            },
+                            SUBKEY_BINDING => {
+                                let binds = subkey.as_ref().is_some_and(|subkey| sig.flags.is_none_or(|flags| flags & CAN_SIGN != 0)
+                                    && sig.verify(key, &[&framed_key, &framed(&subkey.body)]).is_ok());
+
+                                if let Some(mut subkey) = subkey.take().filter(|_| binds) {
+                                    subkey.expires = sig.key_expires.map(|secs| subkey.created + secs);
+                                    keyring.keys.push(subkey);
+                                    bound = Some(keyring.keys.len() - 1);
+                                }
+                            },
+                            SUBKEY_REVOCATION => {
+                                let revoked = |subkey: &Key| sig.verify(key, &[&framed_key, &framed(&subkey.body)]).is_ok();
+                                match (&subkey, bound) {
+                                    (Some(pending), _) if revoked(pending) => subkey = None,
+                                    (None, Some(bound)) if revoked(&keyring.keys[bound]) => keyring.keys[bound].revoked = true,
+                                    _ => (),
+                                }
+                            },
+                            _ => (),
+                        }
+                    },
+                    _ => (),
+                }
+            }
+        }
+
+        Ok(keyring)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    pub fn extend(&mut self, other: Keyring) {
+        self.keys.extend(other.keys);
+    }
+
+    ///
+    /// Keeps the keys (and subkeys) of the given fingerprints only, as `signed-by` lists them
+    ///
+    pub fn retain(&mut self, fingerprints: &[String]) {
+        let fingerprints = fingerprints.iter()
+            .map(|fp| fp.trim_end_matches('!').replace(' ', "").to_ascii_uppercase())
+            .collect::<Vec<_>>();
+
+        self.keys.retain(|key| fingerprints.contains(&hex(&key.fingerprint)) || fingerprints.contains(&hex(&key.primary)));
+    }
+
+    ///
+    /// Why `key` can't be trusted anymore at `now`, if so: it or its primary key got revoked or expired
+    ///
+    fn unusable(&self, key: &Key, now: u64) -> Option<String> {
+        let primary = self.keys.iter().find(|primary| primary.fingerprint == key.primary);
+
+        [Some(key), primary].into_iter().flatten().find_map(|key| match key.expires {
+            _ if key.revoked => Some(format!("key {} is revoked", hex(&key.fingerprint))),
+            Some(expires) if expires <= now => Some(format!("key {} has expired", hex(&key.fingerprint))),
+            _ => None,
+        })
+    }
+}
+
+///
+/// The text lines (dash-unescaped) and the armored signature of a clearsigned message
+///
+fn split_cleartext(contents: &str) -> Result<(Vec<&str>, String), &'static str> {
+    let mut lines = contents.split('\n').map(|line| line.strip_suffix('\r').unwrap_or(line));
+
+    if lines.next() != Some("-----BEGIN PGP SIGNED MESSAGE-----") {
+        return Err("not a clearsigned message");
+    }
+
+    for line in lines.by_ref() {
+        match line {
+            "" => break,
+            line if line.starts_with("Hash: ") => (),
+            _ => return Err("unexpected armor header"),
+        }
+    }
+
+    let mut text = vec![];
+    loop {
+        match lines.next() {
+            Some("-----BEGIN PGP SIGNATURE-----") => break,
+            // Dash-escaped, so it isn't taken for an armor line
+            Some(line) if line.starts_with("- ") => text.push(&line[2..]),
+            Some(line) if line.starts_with('-') => return Err("unescaped dash line"),
+            Some(line) => text.push(line),
+            None => return Err("no signature"),
+        }
+    }
+
+    let mut armored = String::from("-----BEGIN PGP SIGNATURE-----\n");
+    for line in lines.by_ref() {
+        armored.push_str(line);
+        armored.push('\n');
+
+        if line == "-----END PGP SIGNATURE-----" {
+            break;
+        }
+    }
+
+    if lines.any(|line| !line.trim().is_empty()) {
+        return Err("data after the signature");
+    }
+
+    Ok((text, armored))
+}
+
+fn message(text: &[&str]) -> String {
+    let mut message = text.join("\n");
+    message.push('\n');
+    message
+}
+
+///
+/// The text of a clearsigned message, without checking its signature. Only for
+/// what was verified already, e.g.: the `InRelease` files saved by `opm update`
+///
+pub fn cleartext(contents: &str) -> Result<String> {
+    let (text, _) = split_cleartext(contents).map_err(invalid)?;
+    Ok(message(&text))
+}
+
+///
+/// Checks a clearsigned message against `keyring`, returning the signed text only,
+/// since nothing outside of it can be trusted. At least one signature has to come
+/// from a trusted key that's still valid at `now` (and not be expired itself), and none of those may be bad
+///
+pub fn verify_cleartext(name: &str, contents: &str, keyring: &Keyring, now: u64) -> Result<String> {
+    let bad = |why: &str| anyhow::anyhow!(ReleaseError::BadSignature { release: name.to_owned(), why: why.to_owned() });
+    let (text, armored) = split_cleartext(contents).map_err(bad)?;
+
+    // Signed as canonical text: CRLF line endings, no trailing whitespace
+    let canonical = text.iter()
+        .map(|line| line.trim_end_matches([' ', '\t']))
+        .collect::<Vec<_>>()
+        .join("\r\n");
+
+    let (mut good, mut unknown, mut worthless) = (false, vec![], vec![]);
+    for block in dearmor(&armored, "PGP SIGNATURE")? {
+        for (tag, body) in packets(&block)? {
+            let sig = match (tag, Signature::parse(body)?) {
+                (TAG_SIGNATURE, Some(sig)) if sig.kind == CANONICAL_TEXT => sig,
+                _ => continue,
+            };
+
+            let keys = keyring.keys.iter().filter(|key| key.issued(&sig)).collect::<Vec<_>>();
+            if keys.is_empty() {
+                unknown.push(sig.issuer.as_deref().map(hex).unwrap_or_else(|| String::from("unknown key")));
+                continue;
+            }
+
+            let results = keys.iter().map(|key| (key, sig.verify(key, &[canonical.as_bytes()]))).collect::<Vec<_>>();
+            let key = match results.iter().find(|(_, result)| result.is_ok()) {
+                Some((key, _)) => *key,
+                None => {
+                    let why = results.iter().find_map(|(_, result)| result.as_ref().err()).map(|e| e.to_string()).unwrap_or_default();
+                    return Err(bad(&format!("bad signature from {} ({})", hex(keys[0].id()), why)));
+                },
+            };
+
+            // Good, but worth nothing from a key that's gone or once it expired
+            match keyring.unusable(key, now) {
+                Some(why) => worthless.push(why),
+                None if sig.expired(now) => worthless.push(format!("signature from {} has expired", hex(key.id()))),
+                None => good = true,
+            }
+        }
+    }
+
+    if !good && !worthless.is_empty() {
+        return Err(bad(&worthless.join(", ")));
+    }
+    if !good {
+        anyhow::bail!(ReleaseError::Untrusted { release: name.to_owned(), keys: unknown });
+    }
+
+    Ok(message(&text))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NODESOURCE: &str = include_str!("../../../tests/fixtures/openpgp/nodesource_InRelease");
+    const OPM_TEST: &str = include_str!("../../../tests/fixtures/openpgp/opm-test_InRelease");
+    // Sun, 18 Oct 2026 13:00:00 UTC
+    const NOW: u64 = 1792328400;
+
+    fn keyring(name: &str) -> Keyring {
+        Keyring::load(format!("{}/tests/fixtures/openpgp/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap()
+    }
+
+    #[test]
+    fn keyring_test() {
+        // A binary RSA keyring, its subkey is only for encryption
+        let nodesource = keyring("nodesource.gpg");
+        assert_eq!(nodesource.keys.len(), 1);
+        assert_eq!(hex(&nodesource.keys[0].fingerprint), "6F71F525282841EEDAF851B42F59B5F99B1BE0B4");
+
+        let opm = keyring("opm-test.asc");
+        assert_eq!(hex(&opm.keys[0].fingerprint), "1E59E139CEEE0FD5AB39F8846BAB98D232D4EB70");
+        assert!(matches!(opm.keys[0].material, Material::Ed25519(_)));
+    }
+
+    #[test]
+    fn verify_test() {
+        // RSA + SHA256
+        let message = verify_cleartext("nodesource", NODESOURCE, &keyring("nodesource.gpg"), NOW).unwrap();
+        assert!(message.starts_with("Origin: . nodistro\n"));
+        assert!(!message.contains("PGP"));
+
+        // Ed25519 + SHA512
+        let message = verify_cleartext("opm", OPM_TEST, &keyring("opm-test.asc"), NOW).unwrap();
+        assert!(message.starts_with("Origin: opm\n") && message.ends_with("main/binary-amd64/Packages.xz\n"));
+    }
+
+    #[test]
+    fn reject_test() {
+        let opm = keyring("opm-test.asc");
+
+        let err = verify_cleartext("opm", OPM_TEST, &keyring("nodesource.gpg"), NOW).unwrap_err();
+        assert!(err.to_string().contains("1E59E139CEEE0FD5AB39F8846BAB98D232D4EB70"), "{}", err);
+
+        let tampered = OPM_TEST.replace("Suite: stable", "Suite: stab1e");
+        assert!(verify_cleartext("opm", &tampered, &opm, NOW).unwrap_err().to_string().contains("not valid"));
+
+        let mut restricted = keyring("opm-test.asc");
+        restricted.retain(&["6F71F525282841EEDAF851B42F59B5F99B1BE0B4".to_owned()]);
+        assert!(restricted.keys.is_empty());
+
+        // Whatever is around the signed text isn't trusted
+        assert!(verify_cleartext("opm", &format!("Suite: evil\n{}", OPM_TEST), &opm, NOW).is_err());
+        assert!(verify_cleartext("opm", &format!("{}Suite: evil\n", OPM_TEST), &opm, NOW).is_err());
+        assert!(verify_cleartext("opm", &OPM_TEST.replace("-----BEGIN PGP SIGNATURE-----", "-- "), &opm, NOW).is_err());
+    }
+
+    #[test]
+    fn freshness_test() {
+        let fixture = |name: &str| fs::read_to_string(format!("{}/tests/fixtures/openpgp/{}_InRelease", env!("CARGO_MANIFEST_DIR"), name)).unwrap();
+        let expiry = keyring("opm-expiry.asc");
+        let err = |name: &str, keyring: &Keyring, now: u64| verify_cleartext(name, &fixture(name), keyring, now).unwrap_err().to_string();
+
+        // The key is good for a year, the signature for a day
+        assert!(verify_cleartext("opm", &fixture("opm-expiry"), &expiry, NOW).is_ok());
+        assert!(err("opm-expiry", &expiry, NOW + 2 * 365 * 86400).contains("key 62E4D9C5C490360CE02B397109A4E42B3A166F18 has expired"));
+        assert!(verify_cleartext("opm", &fixture("opm-sigexpiry"), &expiry, NOW).is_ok());
+        assert!(err("opm-sigexpiry", &expiry, NOW + 2 * 86400).contains("signature from 09A4E42B3A166F18 has expired"));
+
+        assert!(err("opm-critical", &expiry, NOW).contains("unknown critical subpacket 20"));
+        assert!(err("opm-revoked", &keyring("opm-revoked.asc"), NOW).contains("key 16140A05D23247262FA86A1433AF711B7025EE64 is revoked"));
+    }
+
+    #[test]
+    fn dearmor_test() {
+        let armored = "junk\n-----BEGIN PGP SIGNATURE-----\nComment: hi\n\nb3Bt\n=abcd\n-----END PGP SIGNATURE-----\n";
+        assert_eq!(dearmor(armored, "PGP SIGNATURE").unwrap(), vec![b"opm".to_vec()]);
+        assert!(dearmor("-----BEGIN PGP SIGNATURE-----\n\nb3Bt\n", "PGP SIGNATURE").is_err());
+    }
+}
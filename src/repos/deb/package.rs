@@ -1,8 +1,14 @@
 use anyhow::{self, Result, bail};
 use crate::repos::{errors::ConfigError, config::Config};
-use std::{collections::HashMap, path::{PathBuf, Path}};
+use std::{fmt::{self, Display}, path::{PathBuf, Path}, sync::Arc};
 use std::fs;
 
+use super::deb822::Paragraph;
+use super::relation::{parse_relations, RelationAlternatives};
+use super::release::Origin;
+use super::status::Status;
+use super::database::DEBIAN_INFO;
+
 ///
 /// Kind of the package
 ///
@@ -38,7 +44,7 @@ impl PkgPriority {
 }
 
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct Info {
     pub conffiles: Option<PathBuf>,
     pub control: Option<PathBuf>,
@@ -47,6 +53,7 @@ pub struct Info {
     pub postinst: Option<PathBuf>,
     pub prerm: Option<PathBuf>,
     pub postrm: Option<PathBuf>,
+    pub triggers: Option<PathBuf>,
 }
 
 impl Info {
@@ -59,6 +66,7 @@ impl Info {
             postinst: None,
             prerm: None,
             postrm: None,
+            triggers: None,
         };
 
         for entry in fs::read_dir(from)? {
@@ -73,16 +81,43 @@ impl Info {
                 "postinst" => result.postinst = Some(path.clone()),
                 "prerm" => result.prerm = Some(path.clone()),
                 "postrm" => result.postrm = Some(path.clone()),
+                "triggers" => result.triggers = Some(path.clone()),
                 _ => ()
             }
         }
 
         Ok(result)
     }
+
+    ///
+    /// The control files of the installed `name`, falling back to dpkg's ones (`<info>/<pkg>.<file>`) when sharing its database
+    ///
+    pub fn installed(config: &Config, name: &str) -> Result<Self> {
+        let dir = Path::new(&config.info).join(name);
+        if dir.exists() {
+            return Self::load(&dir);
+        } else if !config.use_pre_existing_db {
+            return Ok(Self::default());
+        }
+
+        let dpkg = |file: &str| Some(PathBuf::from(format!("{}/{}.{}", DEBIAN_INFO, name, file))).filter(|path| path.exists());
+        Ok(
+            Self {
+                conffiles: dpkg("conffiles"),
+                control: None,
+                md5sums: dpkg("md5sums"),
+                preinst: dpkg("preinst"),
+                postinst: dpkg("postinst"),
+                prerm: dpkg("prerm"),
+                postrm: dpkg("postrm"),
+                triggers: dpkg("triggers"),
+            }
+        )
+    }
 }
 
 ///
-/// Debian's control file (mandatory fields)
+/// Debian's control file, the typed fields plus the whole paragraph it came from
 ///
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ControlFile {
@@ -92,65 +127,92 @@ pub struct ControlFile {
     pub architecture: String,
     pub maintainer: String,
     pub description: String,
-    pub depends: Option<Vec<String>>,
-    pub recommends: Option<Vec<String>>,
-    pub suggests: Option<Vec<String>>,
-    pub enhances: Option<Vec<String>>,
-    pub pre_depends: Option<Vec<String>>,
-    pub breaks: Option<Vec<String>>,
-    pub conflicts: Option<Vec<String>>,
+    pub section: Option<String>,
+    pub installed_size: Option<String>,
+    pub homepage: Option<String>,
+    pub source: Option<String>,
+    pub multi_arch: Option<String>,
+    ///
+    /// Only in the status db
+    ///
+    pub status: Option<Status>,
+    pub depends: Option<Vec<RelationAlternatives>>,
+    pub recommends: Option<Vec<RelationAlternatives>>,
+    pub suggests: Option<Vec<RelationAlternatives>>,
+    pub enhances: Option<Vec<RelationAlternatives>>,
+    pub pre_depends: Option<Vec<RelationAlternatives>>,
+    pub breaks: Option<Vec<RelationAlternatives>>,
+    pub conflicts: Option<Vec<RelationAlternatives>>,
+    pub replaces: Option<Vec<RelationAlternatives>>,
+    pub provides: Option<Vec<RelationAlternatives>>,
     pub conffiles: Option<Vec<String>>,
     pub filename: String,
     pub size: String,
     pub md5sum: String,
     pub sha1: String,
     pub sha256: String,
-    pub sha512: String
+    pub sha512: String,
+    ///
+    /// The release it's available from, if it comes from a repository
+    ///
+    pub origin: Option<Arc<Origin>>,
+    pub paragraph: Paragraph,
 }
 
-// TODO: Improve this in the future
 impl ControlFile {
-    pub fn new(_config: &Config, contents: &str) -> Result<Self> {        
-        let mut map: HashMap<String, String> = HashMap::new();
+    pub fn new(_config: &Config, contents: &str) -> Result<Self> {
+        Self::from_paragraph(Paragraph::parse(contents)?)
+    }
 
-        contents.lines().map(|line| line.trim()).for_each(|line| {
-            let values = line.splitn(2, ':').map(|line| line.to_owned()).collect::<Vec<_>>();
-            if values.len() == 2 {
-                map.insert(values[0].clone(), values[1].clone());
-            }
-        });
+    pub fn from_paragraph(paragraph: Paragraph) -> Result<Self> {
+        let p = &paragraph;
 
         Ok(
             Self {
-                package: Self::try_get(&map, "Package")?,
-                version: Self::try_get(&map, "Version")?,
-                architecture: Self::try_get(&map, "Architecture")?,
-                maintainer: Self::try_get(&map, "Maintainer")?,
-                description: Self::try_get(&map, "Description")?,
+                package: Self::try_get(p, "Package")?,
+                version: Self::try_get(p, "Version")?,
+                architecture: Self::try_get(p, "Architecture")?,
+                maintainer: Self::try_get(p, "Maintainer")?,
+                // Just the synopsis, the extended description stays in the paragraph
+                description: Self::try_get(p, "Description")?.lines().next().unwrap_or_default().to_owned(),
                 // Should be like the others
                 // But, when reading /var/lib/dpkg/status it does not have those fields
-                priority: Self::try_get(&map, "Priority").unwrap_or_default(),
-                depends: Self::split_optional(Some(&Self::try_get(&map, "Depends").unwrap_or_default())),
-                recommends: Self::split_optional(Some(&Self::try_get(&map, "Recommends").unwrap_or_default())),
-                suggests: Self::split_optional(Some(&Self::try_get(&map, "Suggests").unwrap_or_default())),
-                enhances: Self::split_optional(Some(&Self::try_get(&map, "Enhances").unwrap_or_default())),
-                pre_depends: Self::split_optional(Some(&Self::try_get(&map, "Pre-Depends").unwrap_or_default())),
-                breaks: Self::split_optional(Some(&Self::try_get(&map, "Breaks").unwrap_or_default())),
-                conflicts: Self::split_optional(Some(&Self::try_get(&map, "Conflicts").unwrap_or_default())),
-                conffiles: None,
-                filename: Self::try_get(&map, "Filename").unwrap_or_default(),
-                size: Self::try_get(&map, "Size").unwrap_or_default(),
-                md5sum: Self::try_get(&map, "MD5sum").unwrap_or_default(),
-                sha1: Self::try_get(&map, "SHA1").unwrap_or_default(),
-                sha256: Self::try_get(&map, "SHA256").unwrap_or_default(),
-                sha512: Self::try_get(&map, "SHA512").unwrap_or_default(),
+                priority: Self::get(p, "Priority").unwrap_or_default(),
+                section: Self::get(p, "Section"),
+                installed_size: Self::get(p, "Installed-Size"),
+                homepage: Self::get(p, "Homepage"),
+                source: Self::get(p, "Source"),
+                multi_arch: Self::get(p, "Multi-Arch"),
+                status: p.get("Status").map(|status| status.parse()).transpose()?,
+                depends: Self::relations(p.get("Depends"))?,
+                recommends: Self::relations(p.get("Recommends"))?,
+                suggests: Self::relations(p.get("Suggests"))?,
+                enhances: Self::relations(p.get("Enhances"))?,
+                pre_depends: Self::relations(p.get("Pre-Depends"))?,
+                breaks: Self::relations(p.get("Breaks"))?,
+                conflicts: Self::relations(p.get("Conflicts"))?,
+                replaces: Self::relations(p.get("Replaces"))?,
+                provides: Self::relations(p.get("Provides"))?,
+                // The status db lists them as `<path> <md5>`, one per line
+                conffiles: match p.lines("Conffiles") {
+                    lines if lines.is_empty() => None,
+                    lines => Some(lines.iter().filter_map(|line| line.split_whitespace().next()).map(|f| f.to_owned()).collect()),
+                },
+                filename: Self::get(p, "Filename").unwrap_or_default(),
+                size: Self::get(p, "Size").unwrap_or_default(),
+                md5sum: Self::get(p, "MD5sum").unwrap_or_default(),
+                sha1: Self::get(p, "SHA1").unwrap_or_default(),
+                sha256: Self::get(p, "SHA256").unwrap_or_default(),
+                sha512: Self::get(p, "SHA512").unwrap_or_default(),
+                origin: None,
+                paragraph,
             }
         )
     }
 
     pub fn from_info(config: &Config, info: &Info) -> Result<Option<Self>> {
         if let Some(control) = &info.control {
-            let mut result = Self::new(config, &fs::read_to_string(&control)?)?;
+            let mut result = Self::new(config, &fs::read_to_string(control)?)?;
             
             if let Some(conffiles) = &info.conffiles {
                 result.conffiles = Some(fs::read_to_string(conffiles)?.lines().map(|line| line.trim().to_string()).collect::<Vec<_>>());
@@ -162,27 +224,32 @@ impl ControlFile {
         }
     }
 
-    fn try_get(hashmap: &HashMap<String, String>, field: &str) -> Result<String> {
-        if let Some(v) = hashmap.get(field) {
-            Ok (v.trim().to_owned())
+    fn try_get(paragraph: &Paragraph, field: &str) -> Result<String> {
+        if let Some(v) = paragraph.get(field) {
+            Ok (v.to_owned())
         } else {
             bail!(ConfigError::UnexError { msg: format!("Invalid debain's control file! Missing \"{}\" field", field), err: None });
         }
     }
 
-    fn split_optional(dependencies: Option<&str>) -> Option<Vec<String>> {
-        if let Some(val) = dependencies {
-            if !val.is_empty() {
-                let val = val
-                    .split(',')
-                    .map(|d| d.trim().to_owned())
-                    .collect::<Vec<_>>();
-                Some(val)
-            } else {
-                None
-            }
-        } else {
-            None
+    fn get(paragraph: &Paragraph, field: &str) -> Option<String> {
+        paragraph.get(field).map(|v| v.to_owned())
+    }
+
+    fn relations(field: Option<&str>) -> Result<Option<Vec<RelationAlternatives>>> {
+        match field {
+            Some(val) if !val.is_empty() => Ok(Some(parse_relations(val)?)),
+            _ => Ok(None),
+        }
+    }
+
+    ///
+    /// How dpkg calls it on disk: `pkg:arch` when every architecture can be installed at once
+    ///
+    pub fn key(&self) -> String {
+        match self.multi_arch.as_deref() {
+            Some("same") => format!("{}:{}", self.package, self.architecture),
+            _ => self.package.clone(),
         }
     }
 
@@ -191,6 +258,12 @@ impl ControlFile {
     }
 }
 
+impl Display for ControlFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.paragraph)
+    }
+}
+
 /// 
 /// Debian binary package format structure
 ///
@@ -241,4 +314,38 @@ Description-md5: 8aeed0a03c7cd494f0c4b8d977483d7e";
 		ControlFile::new(&config, data).unwrap();
 	}
 
+    #[test]
+    fn control_fields_test() {
+        let config = crate::repos::setup().unwrap();
+        let data = "Package: dash
+Status: install ok installed
+Priority: required
+Section: shells
+Installed-Size: 214
+Maintainer: Debian Shell Maintainers <pkg-shell-maint@lists.alioth.debian.org>
+Architecture: amd64
+Multi-Arch: foreign
+Version: 0.5.12-2
+Provides: sh
+Depends: debianutils (>= 5.6-0.1), dpkg (>= 1.19.1)
+Pre-Depends: libc6 (>= 2.34)
+Conffiles:
+ /etc/dash.conf 2b0c5ea1ce2bb4ec9e2b1b4e8e3a4f5c
+Description: POSIX-compliant shell
+ The Debian Almquist Shell (dash) is a POSIX-compliant shell derived
+ from ash.
+Homepage: http://gondor.apana.org.au/~herbert/dash/
+";
+        let control = ControlFile::new(&config, data).unwrap();
+
+        assert_eq!(control.description, "POSIX-compliant shell");
+        assert_eq!(control.status.map(|status| status.to_string()).as_deref(), Some("install ok installed"));
+        assert_eq!(control.section.as_deref(), Some("shells"));
+        assert_eq!(control.multi_arch.as_deref(), Some("foreign"));
+        assert_eq!(control.provides, Some(parse_relations("sh").unwrap()));
+        assert_eq!(control.pre_depends.as_ref().unwrap()[0].0[0].name, "libc6");
+        assert_eq!(control.conffiles, Some(vec!["/etc/dash.conf".to_owned()]));
+        assert_eq!(control.to_string(), data);
+    }
+
 }
\ No newline at end of file
@@ -0,0 +1,450 @@
+use anyhow::Result;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::UNIX_EPOCH,
+};
+
+use crate::repos::{config::Config, errors::CacheError};
+use super::{cache, deb822, package::ControlFile, release::{Origin, Release}};
+
+//
+// Binary cache over the `Packages` indices, so looking a package up doesn't mean parsing all of them.
+// It maps every package name (and every name something `Provides`) to where its paragraphs are
+// in the indices, and it's rebuilt as soon as any of them changes.
+//
+// Layout (little endian):
+//   magic, stamp (u64), 6 section lengths (u32)
+//   files     [str_off, str_len]
+//   entries   [file, offset, len]                          one per paragraph
+//   names     [str_off, str_len, refs_start, refs_count]   open addressing, power of two buckets
+//   provides  [str_off, str_len, refs_start, refs_count]
+//   refs      [entry]
+//   strings
+//
+
+const PKGCACHE: &str = "pkgcache.bin";
+const MAGIC: &[u8; 8] = b"OPMPKGC1";
+const HEADER: usize = 8 + 8 + 6 * 4;
+const BUCKET: usize = 4 * 4;
+const ENTRY: usize = 3 * 4;
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+///
+/// The local copy of a `Packages` index gets its name from where it was fetched,
+/// see `sources::uri_to_file_name`. This gives that repository back (without scheme),
+/// which `Filename`s are relative to, even in flat repositories
+///
+pub fn repository(index: &str) -> String {
+    let base = match index.find("_dists_") {
+        Some(end) => &index[..end + 1],
+        None => index.strip_suffix("Packages").unwrap_or(index),
+    };
+
+    base.replace('_', "/").replace("%5f", "_")
+}
+
+///
+/// Identifies the exact state of the indices: names, sizes and modification times
+///
+fn stamp(indices: &[PathBuf]) -> u64 {
+    let mut state = vec![];
+
+    for path in indices {
+        let meta = fs::metadata(path).ok();
+        let mtime = meta.as_ref()
+            .and_then(|meta| meta.modified().ok())
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |time| time.as_nanos());
+
+        state.extend(path.to_string_lossy().as_bytes());
+        state.extend(meta.map_or(0, |meta| meta.len()).to_le_bytes());
+        state.extend(mtime.to_le_bytes());
+    }
+
+    fnv1a(&state)
+}
+
+///
+/// The origin of every package of each index, from the `InRelease` saved next to it
+///
+fn origins(config: &Config, files: &[(File, String)]) -> Vec<Option<Arc<Origin>>> {
+    let releases = fs::read_dir(cache::releases(config))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let prefix = name.strip_suffix("InRelease").or_else(|| name.strip_suffix("Release"))?.to_owned();
+            Some((prefix, entry.path()))
+        })
+        .collect::<Vec<_>>();
+
+    let mut loaded: HashMap<PathBuf, Option<Release>> = HashMap::new();
+    files.iter()
+        .map(|(_, path)| {
+            let index = Path::new(path).file_name()?.to_string_lossy().into_owned();
+            // The longest one, `dists_stable_` shouldn't match a `dists_stable-updates_` index
+            let (prefix, release) = releases.iter()
+                .filter(|(prefix, _)| index.starts_with(prefix.as_str()))
+                .max_by_key(|(prefix, _)| prefix.len())?;
+
+            let release = loaded.entry(release.clone()).or_insert_with(|| Release::load(release).ok()).as_ref()?;
+            let rest = &index[prefix.len()..];
+            let component = rest.split_once("_binary-").map_or("", |(component, _)| component).replace('_', "/");
+            let site = repository(&index).split('/').next().unwrap_or_default().to_owned();
+
+            Some(Arc::new(release.origin(&component, &site)))
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct PackageCache {
+    data: Vec<u8>,
+    files: Vec<(File, String)>,
+    origins: Vec<Option<Arc<Origin>>>,
+    sections: [usize; 6],
+    counts: [usize; 6],
+}
+
+impl PackageCache {
+    ///
+    /// The binary cache of every index in the cache, (re)built first if it's missing or out of date
+    ///
+    pub fn open(config: &Config) -> Result<Self> {
+        let indices = cache::indices(config)?;
+        let stamp = stamp(&indices);
+        let path = Path::new(&config.cache).join(PKGCACHE);
+
+        let mut cache = match fs::read(&path).ok().and_then(|data| Self::from_bytes(data, stamp)) {
+            Some(cache) => cache,
+            None => {
+                let data = Self::build(&indices, stamp)?;
+                // Still usable when the cache directory isn't writable (e.g.: APT's lists as a regular user)
+                if let Err(e) = fs::write(&path, &data) {
+                    eprintln!("Could not save the package cache at {:?} :: {}", path, e);
+                }
+
+                Self::from_bytes(data, stamp).ok_or_else(|| anyhow::anyhow!(CacheError::NoCache(path.to_string_lossy().into_owned())))?
+            }
+        };
+
+        cache.origins = origins(config, &cache.files);
+        Ok(cache)
+    }
+
+    fn build(indices: &[PathBuf], stamp: u64) -> Result<Vec<u8>> {
+        let mut strings: Vec<u8> = vec![];
+        let mut add_string = |s: &str| {
+            let offset = strings.len() as u32;
+            strings.extend(s.as_bytes());
+            (offset, s.len() as u32)
+        };
+
+        let (mut files, mut entries) = (vec![], vec![]);
+        let mut names: HashMap<String, Vec<u32>> = HashMap::new();
+        let mut provides: HashMap<String, Vec<u32>> = HashMap::new();
+
+        for path in indices {
+            let contents = match fs::read_to_string(path) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    eprintln!("Skipping the index {:?} :: {}", path, e);
+                    continue;
+                }
+            };
+
+            let file = files.len();
+            files.push(add_string(&path.to_string_lossy()));
+
+            for stanza in deb822::split(&contents) {
+                let offset = stanza.as_ptr() as usize - contents.as_ptr() as usize;
+                let id = entries.len() as u32;
+                entries.push((file as u32, offset as u32, stanza.len() as u32));
+
+                if let Some(name) = field(stanza, "Package") {
+                    names.entry(name.trim().to_owned()).or_default().push(id);
+                }
+
+                for provided in field(stanza, "Provides").iter().flat_map(|value| value.split(',')) {
+                    let name = provided.split(['(', ':']).next().unwrap_or_default().trim();
+                    if !name.is_empty() {
+                        provides.entry(name.to_owned()).or_default().push(id);
+                    }
+                }
+            }
+        }
+
+        let mut refs: Vec<u32> = vec![];
+        let mut table = |map: HashMap<String, Vec<u32>>| {
+            let mut buckets = vec![[0u32; 4]; (map.len() * 2).next_power_of_two().max(1)];
+            let mask = buckets.len() - 1;
+
+            for (name, ids) in map {
+                let mut i = fnv1a(name.as_bytes()) as usize & mask;
+                while buckets[i][3] != 0 {
+                    i = (i + 1) & mask;
+                }
+
+                let (offset, len) = add_string(&name);
+                buckets[i] = [offset, len, refs.len() as u32, ids.len() as u32];
+                refs.extend(ids);
+            }
+
+            buckets
+        };
+
+        let names = table(names);
+        let provides = table(provides);
+
+        let mut data = MAGIC.to_vec();
+        data.extend(stamp.to_le_bytes());
+        for len in [files.len(), entries.len(), names.len(), provides.len(), refs.len(), strings.len()] {
+            data.extend((len as u32).to_le_bytes());
+        }
+
+        files.iter().for_each(|&(offset, len)| [offset, len].iter().for_each(|v| data.extend(v.to_le_bytes())));
+        entries.iter().for_each(|&(file, offset, len)| [file, offset, len].iter().for_each(|v| data.extend(v.to_le_bytes())));
+        names.iter().chain(provides.iter()).flatten().for_each(|v| data.extend(v.to_le_bytes()));
+        refs.iter().for_each(|v| data.extend(v.to_le_bytes()));
+        data.extend(strings);
+
+        Ok(data)
+    }
+
+    ///
+    /// `None` if `data` isn't a (complete) cache of the indices as they are right now
+    ///
+    fn from_bytes(data: Vec<u8>, stamp: u64) -> Option<Self> {
+        if data.len() < HEADER || &data[..8] != MAGIC || data[8..16] != stamp.to_le_bytes() {
+            return None;
+        }
+
+        let mut counts = [0; 6];
+        for (i, count) in counts.iter_mut().enumerate() {
+            *count = u32::from_le_bytes(data[16 + i * 4..20 + i * 4].try_into().ok()?) as usize;
+        }
+
+        let sizes = [8, ENTRY, BUCKET, BUCKET, 4, 1];
+        let mut sections = [0; 6];
+        let mut offset = HEADER;
+        for i in 0..6 {
+            sections[i] = offset;
+            offset += counts[i] * sizes[i];
+        }
+
+        if offset != data.len() {
+            return None;
+        }
+
+        let mut cache = Self { data, files: vec![], origins: vec![], sections, counts };
+        for i in 0..counts[0] {
+            let path = cache.string(cache.u32(sections[0] + i * 8)?, cache.u32(sections[0] + i * 8 + 4)?)?.to_owned();
+            cache.files.push((File::open(&path).ok()?, path));
+        }
+
+        // A corrupt one is as good as a stale one: entries and refs must point at something that's there
+        let entries = (0..counts[1]).all(|i| cache.u32(sections[1] + i * ENTRY).is_some_and(|file| (file as usize) < counts[0]));
+        let refs = (0..counts[4]).all(|i| cache.u32(sections[4] + i * 4).is_some_and(|entry| (entry as usize) < counts[1]));
+        if !entries || !refs {
+            return None;
+        }
+
+        Some(cache)
+    }
+
+    fn u32(&self, at: usize) -> Option<u32> {
+        Some(u32::from_le_bytes(self.data.get(at..at + 4)?.try_into().ok()?))
+    }
+
+    fn string(&self, offset: u32, len: u32) -> Option<&str> {
+        let start = self.sections[5] + offset as usize;
+        std::str::from_utf8(self.data.get(start..start + len as usize)?).ok()
+    }
+
+    ///
+    /// `[str_off, str_len, refs_start, refs_count]` of the `i`-th bucket of a table
+    ///
+    fn bucket(&self, table: usize, i: usize) -> Option<[u32; 4]> {
+        let at = self.sections[table] + i * BUCKET;
+        Some([self.u32(at)?, self.u32(at + 4)?, self.u32(at + 8)?, self.u32(at + 12)?])
+    }
+
+    ///
+    /// Entries of `name` in one of the tables (2 for names, 3 for provides)
+    ///
+    fn find(&self, table: usize, name: &str) -> Vec<u32> {
+        let buckets = self.counts[table];
+        if buckets == 0 {
+            return vec![];
+        }
+
+        let mut i = fnv1a(name.as_bytes()) as usize & (buckets - 1);
+        for _ in 0..buckets {
+            match self.bucket(table, i) {
+                Some([_, _, _, 0]) | None => break,
+                Some([offset, len, start, count]) if self.string(offset, len) == Some(name) => {
+                    return (start..start + count).filter_map(|r| self.u32(self.sections[4] + r as usize * 4)).collect();
+                },
+                _ => i = (i + 1) & (buckets - 1),
+            }
+        }
+
+        vec![]
+    }
+
+    ///
+    /// The paragraph behind an entry, `Filename` already pointing at its repository
+    ///
+    fn entry(&self, id: u32) -> Result<ControlFile> {
+        let at = self.sections[1] + id as usize * ENTRY;
+        let (file, offset, len) = match (self.u32(at), self.u32(at + 4), self.u32(at + 8)) {
+            (Some(file), Some(offset), Some(len)) => (file as usize, offset as u64, len as usize),
+            _ => anyhow::bail!(CacheError::NoCache(PKGCACHE.to_owned())),
+        };
+
+        let (index, path) = match self.files.get(file) {
+            Some(file) => file,
+            None => anyhow::bail!(CacheError::NoCache(PKGCACHE.to_owned())),
+        };
+        let mut stanza = vec![0; len];
+        index.read_exact_at(&mut stanza, offset)?;
+
+        let mut pkg = ControlFile::from_paragraph(deb822::Paragraph::parse(&String::from_utf8_lossy(&stanza))?)?;
+        let name = Path::new(path).file_name().unwrap_or_default().to_string_lossy();
+        let url = format!("{}{}", repository(&name), pkg.filename.trim_start_matches("./"));
+        pkg.set_filename(&url);
+        pkg.origin = self.origins.get(file).cloned().flatten();
+
+        Ok(pkg)
+    }
+
+    ///
+    /// Every version (and architecture) of the package `name`
+    ///
+    pub fn lookup(&self, name: &str) -> Result<Vec<ControlFile>> {
+        self.find(2, name).into_iter().map(|id| self.entry(id)).collect()
+    }
+
+    ///
+    /// Every package name in the cache, in no particular order
+    ///
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        (0..self.counts[2])
+            .filter_map(|i| self.bucket(2, i))
+            .filter(|bucket| bucket[3] != 0)
+            .filter_map(|[offset, len, _, _]| self.string(offset, len))
+    }
+
+    ///
+    /// Every package that could take part in installing `names`: those, what provides them,
+    /// and so on through their `Depends` and `Pre-Depends`
+    ///
+    pub fn reachable(&self, names: &[&str]) -> Result<Vec<ControlFile>> {
+        let mut queue = names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        let (mut seen_names, mut seen_entries) = (HashSet::new(), HashSet::new());
+        let mut result = vec![];
+
+        while let Some(name) = queue.pop() {
+            if !seen_names.insert(name.clone()) {
+                continue;
+            }
+
+            for id in self.find(2, &name).into_iter().chain(self.find(3, &name)) {
+                if !seen_entries.insert(id) {
+                    continue;
+                }
+
+                let pkg = self.entry(id)?;
+                queue.extend(pkg.pre_depends.iter().chain(pkg.depends.iter())
+                    .flatten()
+                    .flat_map(|alternatives| alternatives.iter())
+                    .map(|relation| relation.name.clone()));
+
+                result.push(pkg);
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+///
+/// The raw value of a field (continuation lines included) without parsing the whole paragraph
+///
+fn field(stanza: &str, name: &str) -> Option<String> {
+    let mut lines = stanza.lines();
+    let first = lines.find_map(|line| line.split_once(':').filter(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v))?;
+
+    let mut value = first.to_owned();
+    for line in lines.take_while(|line| line.starts_with(' ') || line.starts_with('\t')) {
+        value.push_str(line);
+    }
+
+    Some(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn repository_test() {
+        assert_eq!(repository("deb.debian.org_debian_dists_bookworm_main_binary-amd64_Packages"), "deb.debian.org/debian/");
+        assert_eq!(repository("deb.nodesource.com_node%5f20.x_dists_nodistro_main_binary-amd64_Packages"), "deb.nodesource.com/node_20.x/");
+        assert_eq!(repository("flat.org_repo_Packages"), "flat.org/repo/");
+    }
+
+    #[test]
+    fn pkgcache_test() {
+        let dir = std::env::temp_dir().join(format!("opm-pkgcache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let index = dir.join("opm.org_debian_dists_stable_main_binary-amd64_Packages");
+        fs::copy(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/Packages"), &index).unwrap();
+
+        let indices = vec![index.clone()];
+        let cache = PackageCache::from_bytes(PackageCache::build(&indices, stamp(&indices)).unwrap(), stamp(&indices)).unwrap();
+
+        let names = cache.names().collect::<Vec<_>>();
+        assert!(!names.is_empty());
+
+        for name in names.iter().take(50) {
+            let pkgs = cache.lookup(name).unwrap();
+            assert!(!pkgs.is_empty() && pkgs.iter().all(|pkg| pkg.package == *name));
+            assert!(pkgs[0].filename.starts_with("opm.org/debian/pool/"), "{}", pkgs[0].filename);
+        }
+
+        assert!(cache.lookup("opm-does-not-exist").unwrap().is_empty());
+
+        // Reachable packages include every dependency that's in the index
+        let first = names[0];
+        let reachable = cache.reachable(&[first]).unwrap();
+        for pkg in reachable.iter() {
+            for relation in pkg.depends.iter().flatten().flat_map(|alternatives| alternatives.iter()) {
+                let known = !cache.lookup(&relation.name).unwrap().is_empty() || !cache.find(3, &relation.name).is_empty();
+                assert!(!known || reachable.iter().any(|p| p.package == relation.name
+                    || p.provides.iter().flatten().flat_map(|alternatives| alternatives.iter()).any(|provided| provided.name == relation.name)));
+            }
+        }
+
+        // So does a corrupt entry, instead of it panicking later
+        let mut corrupt = PackageCache::build(&indices, stamp(&indices)).unwrap();
+        corrupt[HEADER + cache.counts[0] * 8..][..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(PackageCache::from_bytes(corrupt, stamp(&indices)).is_none());
+
+        // Any change to the indices makes it stale
+        let old = stamp(&indices);
+        fs::write(&index, "Package: opm\nVersion: 1.0\nArchitecture: all\nMaintainer: opm\nDescription: test\n").unwrap();
+        assert!(PackageCache::from_bytes(PackageCache::build(&indices, old).unwrap(), stamp(&indices)).is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
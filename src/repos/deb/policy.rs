@@ -0,0 +1,413 @@
+use anyhow::Result;
+use regex::Regex;
+use std::{borrow::Borrow, cmp::Ordering, fs, path::{Path, PathBuf}};
+
+use super::deb822::{self, Paragraph};
+use super::package::ControlFile;
+use crate::repos::{config::Config, errors::PreferencesError};
+
+//
+// Which version of a package is the candidate: APT's priorities and pins
+// https://manpages.debian.org/bookworm/apt/apt_preferences.5.en.html
+//
+
+const PREFERENCES: &str = "/etc/apt/preferences";
+const PREFERENCES_DIR: &str = "/etc/apt/preferences.d";
+
+///
+/// Installed versions, and what doesn't come from a repository
+///
+pub const INSTALLED: i32 = 100;
+pub const DEFAULT: i32 = 500;
+pub const NOT_AUTOMATIC: i32 = 1;
+///
+/// From this one on a version gets installed even if it means a downgrade
+///
+pub const DOWNGRADE: i32 = 1000;
+
+///
+/// A pin: every version of `package` matching all the given fields gets `priority`.
+/// Everything but `priority` is a glob (`*`, `?`) or a regex between slashes (`/^lib/`)
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Pin {
+    ///
+    /// Names separated by spaces, `*` for every package
+    ///
+    pub package: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    ///
+    /// The `Origin` of the release (e.g.: `Debian`), `o=` in APT
+    ///
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    ///
+    /// `a=` in APT (e.g.: `stable`, `bookworm-backports`)
+    ///
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suite: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub codename: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub component: Option<String>,
+    ///
+    /// Host of the repository, `Pin: origin` in APT
+    ///
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub site: Option<String>,
+    pub priority: i32,
+}
+
+impl Pin {
+    ///
+    /// The records of an APT preferences file
+    ///
+    pub fn parse(file: &str, contents: &str) -> Result<Vec<Pin>> {
+        let malformed = |why: String| PreferencesError::Malformed { file: file.to_owned(), why };
+        let mut pins = vec![];
+
+        for paragraph in deb822::split(contents) {
+            let paragraph = Paragraph::parse(paragraph)?;
+            let package = match paragraph.get("Package") {
+                Some(package) => package.to_owned(),
+                // Only comments
+                None if paragraph.get("Pin").is_none() => continue,
+                None => anyhow::bail!(malformed(String::from("a record without Package"))),
+            };
+
+            let priority = match paragraph.get("Pin-Priority").map(|p| p.parse::<i32>()) {
+                Some(Ok(priority)) => priority,
+                Some(Err(_)) => anyhow::bail!(malformed(format!("bad Pin-Priority for {}", package))),
+                None => anyhow::bail!(malformed(format!("no Pin-Priority for {}", package))),
+            };
+
+            let mut pin = Pin { package, priority, ..Default::default() };
+            let unquote = |value: &str| value.trim().trim_matches('"').to_owned();
+
+            match paragraph.get("Pin").and_then(|p| p.split_once(char::is_whitespace)) {
+                Some(("version", version)) => pin.version = Some(unquote(version)),
+                Some(("origin", site)) => pin.site = Some(unquote(site)),
+                Some(("release", release)) => {
+                    for item in release.split(',') {
+                        let (key, value) = match item.split_once('=') {
+                            Some((key, value)) => (key.trim(), unquote(value)),
+                            // `release 12`, a version
+                            None => ("v", unquote(item)),
+                        };
+
+                        match key {
+                            "a" | "archive" => pin.suite = Some(value),
+                            "n" | "codename" => pin.codename = Some(value),
+                            "v" | "version" => pin.version = Some(value),
+                            "o" | "origin" => pin.origin = Some(value),
+                            "l" | "label" => pin.label = Some(value),
+                            "c" | "component" => pin.component = Some(value),
+                            _ => anyhow::bail!(malformed(format!("unknown release field {:?} for {}", key, pin.package))),
+                        }
+                    }
+                },
+                _ => anyhow::bail!(malformed(format!("bad Pin for {}", pin.package))),
+            }
+
+            pins.push(pin);
+        }
+
+        Ok(pins)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Field {
+    Version,
+    Origin,
+    Label,
+    Suite,
+    Codename,
+    Component,
+    Site,
+}
+
+impl Field {
+    fn value(self, pkg: &ControlFile) -> Option<&str> {
+        let origin = pkg.origin.as_deref();
+
+        match self {
+            Field::Version => Some(&pkg.version),
+            Field::Origin => origin.map(|o| o.origin.as_str()),
+            Field::Label => origin.map(|o| o.label.as_str()),
+            Field::Suite => origin.map(|o| o.suite.as_str()),
+            Field::Codename => origin.map(|o| o.codename.as_str()),
+            Field::Component => origin.map(|o| o.component.as_str()),
+            Field::Site => origin.map(|o| o.site.as_str()),
+        }
+    }
+}
+
+///
+/// A `Pin` ready to be matched
+///
+#[derive(Debug, Clone)]
+struct Rule {
+    packages: Vec<Regex>,
+    fields: Vec<(Field, Regex)>,
+    priority: i32,
+}
+
+impl Rule {
+    fn new(pin: &Pin) -> Result<Self> {
+        let packages = pin.package.split_whitespace().map(pattern).collect::<Result<Vec<_>>>()?;
+        let fields = [
+            (Field::Version, &pin.version),
+            (Field::Origin, &pin.origin),
+            (Field::Label, &pin.label),
+            (Field::Suite, &pin.suite),
+            (Field::Codename, &pin.codename),
+            (Field::Component, &pin.component),
+            (Field::Site, &pin.site),
+        ];
+
+        let fields = fields.into_iter()
+            .filter_map(|(field, value)| value.as_ref().map(|value| Ok((field, pattern(value)?))))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { packages, fields, priority: pin.priority })
+    }
+
+    fn matches(&self, pkg: &ControlFile) -> bool {
+        self.packages.iter().any(|re| re.is_match(&pkg.package))
+            && self.fields.iter().all(|(field, re)| field.value(pkg).is_some_and(|value| re.is_match(value)))
+    }
+}
+
+///
+/// `/regex/` as is, a glob otherwise
+///
+fn pattern(pattern: &str) -> Result<Regex> {
+    match pattern.strip_prefix('/').and_then(|p| p.strip_suffix('/')) {
+        Some(re) => Ok(Regex::new(re)?),
+        None => Ok(Regex::new(&format!("^{}$", regex::escape(pattern).replace(r"\*", ".*").replace(r"\?", ".")))?),
+    }
+}
+
+///
+/// Whether `package` names a single package instead of a pattern
+///
+fn is_specific(package: &str) -> bool {
+    !package.contains(['*', '?', '/', ' '])
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    ///
+    /// Pins for a package by name come first, then the first matching one wins
+    ///
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub fn new(pins: &[Pin]) -> Result<Self> {
+        let (specific, general): (Vec<&Pin>, Vec<&Pin>) = pins.iter().partition(|pin| is_specific(&pin.package));
+
+        Ok(Self { rules: specific.into_iter().chain(general).map(Rule::new).collect::<Result<_>>()? })
+    }
+
+    ///
+    /// The pins from `config`, then the ones from `/etc/apt/preferences` and `/etc/apt/preferences.d`
+    ///
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let mut files = vec![PathBuf::from(PREFERENCES)];
+        if let Ok(entries) = fs::read_dir(PREFERENCES_DIR) {
+            // Like APT, only files without an extension or ending with `.pref`
+            let mut entries = entries.flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_none_or(|ext| ext == "pref"))
+                .filter(|path| path.file_name().is_some_and(|name| name.to_string_lossy()
+                    .chars().all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c))))
+                .collect::<Vec<_>>();
+
+            entries.sort();
+            files.extend(entries);
+        }
+
+        let mut pins = config.pins.clone();
+        for file in files.iter().filter(|file| file.is_file()) {
+            pins.extend(Self::load(file)?);
+        }
+
+        Self::new(&pins)
+    }
+
+    fn load(file: &Path) -> Result<Vec<Pin>> {
+        Pin::parse(&file.to_string_lossy(), &fs::read_to_string(file)?)
+    }
+
+    ///
+    /// The priority of a version: the one of the first pin matching it, otherwise 500 normally,
+    /// 1 for `NotAutomatic` releases and 100 if they're also `ButAutomaticUpgrades`.
+    /// Negative ones are never installed
+    ///
+    pub fn priority(&self, pkg: &ControlFile) -> i32 {
+        if let Some(rule) = self.rules.iter().find(|rule| rule.matches(pkg)) {
+            return rule.priority;
+        }
+
+        match pkg.origin.as_deref() {
+            None => INSTALLED,
+            Some(origin) if origin.not_automatic && origin.but_automatic_upgrades => INSTALLED,
+            Some(origin) if origin.not_automatic => NOT_AUTOMATIC,
+            Some(_) => DEFAULT,
+        }
+    }
+
+    ///
+    /// The installed version is worth at least `INSTALLED`, or more if some of `available` has it
+    ///
+    pub fn installed_priority<'a>(&self, installed: &ControlFile, available: impl IntoIterator<Item = &'a ControlFile>) -> i32 {
+        available.into_iter()
+            .filter(|pkg| pkg.key() == installed.key() && pkg.version == installed.version)
+            .map(|pkg| self.priority(pkg))
+            .fold(self.priority(installed).max(INSTALLED), i32::max)
+    }
+
+    ///
+    /// Highest priority first, then the highest version
+    ///
+    pub fn sort<T: Borrow<ControlFile>>(&self, pkgs: &mut [T]) {
+        pkgs.sort_by(|a, b| {
+            let (a, b) = (a.borrow(), b.borrow());
+            self.priority(b).cmp(&self.priority(a))
+                .then_with(|| deb_version::compare_versions(&b.version, &a.version))
+        });
+    }
+
+    ///
+    /// Whether `pkg` can be picked without being asked for: never with a negative priority, and
+    /// over the `installed` version (with its priority) only if it's an upgrade from a release
+    /// that ranks as high, or if its priority allows downgrades
+    ///
+    pub fn allowed(&self, pkg: &ControlFile, installed: Option<(&ControlFile, i32)>) -> bool {
+        let priority = self.priority(pkg);
+
+        priority >= 0 && match installed {
+            Some((installed, installed_priority)) => priority >= DOWNGRADE
+                || (deb_version::compare_versions(&pkg.version, &installed.version) != Ordering::Less
+                    && priority >= installed_priority),
+            None => true,
+        }
+    }
+
+    ///
+    /// The version of `available` to install given what's `installed`, which stays
+    /// the candidate when none of them is allowed over it
+    ///
+    pub fn candidate<'a>(&self, available: &[&'a ControlFile], installed: Option<&'a ControlFile>) -> Option<&'a ControlFile> {
+        let with_priority = installed.map(|pkg| (pkg, self.installed_priority(pkg, available.iter().copied())));
+
+        let mut available = available.to_vec();
+        self.sort(&mut available);
+        available.into_iter().find(|pkg| self.allowed(pkg, with_priority)).or(installed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::release::Origin;
+    use std::sync::Arc;
+
+    const PREFERENCES: &str = "
+# Backports only when asked for, but everything from ours
+Package: *
+Pin: release n=bookworm-backports
+Pin-Priority: 100
+
+Explanation: ours
+Package: *
+Pin: origin \"apt.example.org\"
+Pin-Priority: 900
+
+Package: nginx-core
+Pin: release a=bookworm-backports, c=main
+Pin-Priority: 990
+
+Package: /^lib.*-dev$/
+Pin: version 2.*
+Pin-Priority: -1
+
+Package: curl
+Pin: release o=Debian
+Pin-Priority: 1001
+";
+
+    fn pkg(name: &str, version: &str, origin: Option<(&str, &str, &str)>) -> ControlFile {
+        let stanza = format!("Package: {}\nVersion: {}\nArchitecture: amd64\nMaintainer: opm\nDescription: test\n", name, version);
+        let mut pkg = ControlFile::from_paragraph(Paragraph::parse(&stanza).unwrap()).unwrap();
+        pkg.origin = origin.map(|(suite, codename, site)| Arc::new(Origin {
+            origin: String::from("Debian"),
+            suite: suite.to_owned(),
+            codename: codename.to_owned(),
+            component: String::from("main"),
+            site: site.to_owned(),
+            ..Default::default()
+        }));
+
+        pkg
+    }
+
+    #[test]
+    fn parse_test() {
+        let pins = Pin::parse("preferences", PREFERENCES).unwrap();
+
+        assert_eq!(pins.len(), 5);
+        assert_eq!(pins[0], Pin { package: String::from("*"), codename: Some(String::from("bookworm-backports")), priority: 100, ..Default::default() });
+        assert_eq!(pins[1].site.as_deref(), Some("apt.example.org"));
+        assert_eq!((pins[2].suite.as_deref(), pins[2].component.as_deref()), (Some("bookworm-backports"), Some("main")));
+        assert_eq!((pins[3].version.as_deref(), pins[3].priority), (Some("2.*"), -1));
+
+        assert!(Pin::parse("bad", "Package: a\nPin: release x=1\nPin-Priority: 1").is_err());
+        assert!(Pin::parse("bad", "Package: a\nPin: version 1").is_err());
+    }
+
+    #[test]
+    fn priority_test() {
+        let policy = Policy::new(&Pin::parse("preferences", PREFERENCES).unwrap()).unwrap();
+        let stable = Some(("stable", "bookworm", "deb.debian.org"));
+        let backports = Some(("bookworm-backports", "bookworm-backports", "deb.debian.org"));
+
+        assert_eq!(policy.priority(&pkg("vim", "9", stable)), DEFAULT);
+        assert_eq!(policy.priority(&pkg("vim", "9", None)), INSTALLED);
+        assert_eq!(policy.priority(&pkg("vim", "9", backports)), 100);
+        assert_eq!(policy.priority(&pkg("vim", "9", Some(("stable", "bookworm", "apt.example.org")))), 900);
+        // Pins by name go first
+        assert_eq!(policy.priority(&pkg("nginx-core", "1.24", backports)), 990);
+        assert_eq!(policy.priority(&pkg("libssl-dev", "2.0", None)), -1);
+        assert_eq!(policy.priority(&pkg("libssl-dev", "3.0", None)), INSTALLED);
+        assert_eq!(policy.priority(&pkg("curl", "8", stable)), 1001);
+    }
+
+    #[test]
+    fn candidate_test() {
+        let policy = Policy::new(&Pin::parse("preferences", PREFERENCES).unwrap()).unwrap();
+        let (stable, backports) = (Some(("stable", "bookworm", "deb.debian.org")), Some(("bookworm-backports", "bookworm-backports", "deb.debian.org")));
+
+        let (old, new) = (pkg("vim", "9.0", stable), pkg("vim", "9.1", backports));
+        assert_eq!(policy.candidate(&[&old, &new], None).unwrap().version, "9.0");
+        // Upgraded from backports once it came from there
+        let installed = pkg("vim", "9.1~rc", None);
+        assert_eq!(policy.candidate(&[&old, &new], Some(&installed)).unwrap().version, "9.1");
+
+        // Nothing newer
+        let installed = pkg("vim", "10", None);
+        assert_eq!(policy.candidate(&[&old, &new], Some(&installed)).unwrap().version, "10");
+
+        let (old, new) = (pkg("curl", "7", stable), pkg("curl", "8", stable));
+        assert_eq!(policy.candidate(&[&old], Some(&new)).unwrap().version, "7");
+
+        let dev = pkg("libssl-dev", "2.0", stable);
+        assert!(policy.candidate(&[&dev], None).is_none());
+    }
+}
@@ -0,0 +1,376 @@
+use anyhow::Result;
+use std::{cmp::Ordering, fmt::{self, Display}, str::FromStr};
+
+use crate::repos::errors::ConfigError;
+
+//
+// Package relationships (Depends, Pre-Depends, Breaks, Provides, ...)
+// https://www.debian.org/doc/debian-policy/ch-relationships.html
+//
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VersionOp {
+    Lt, // <<
+    Le, // <=
+    Eq, // =
+    Ge, // >=
+    Gt, // >>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct VersionConstraint {
+    pub op: VersionOp,
+    pub version: String,
+}
+
+///
+/// `amd64` or `!amd64` inside `[...]`, or a build profile inside `<...>`
+///
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Restriction {
+    pub name: String,
+    pub negated: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Relation {
+    pub name: String,
+    pub arch: Option<String>,
+    pub version: Option<VersionConstraint>,
+    pub archs: Vec<Restriction>,
+    pub profiles: Vec<Vec<Restriction>>,
+}
+
+///
+/// `a | b | c`, satisfied by any one of them
+///
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RelationAlternatives(pub Vec<Relation>);
+
+impl VersionOp {
+    fn parse(op: &str) -> Option<Self> {
+        match op {
+            "<<" => Some(VersionOp::Lt),
+            // `<` and `>` are obsolete, but they still mean "or equal"
+            "<=" | "<" => Some(VersionOp::Le),
+            "=" => Some(VersionOp::Eq),
+            ">=" | ">" => Some(VersionOp::Ge),
+            ">>" => Some(VersionOp::Gt),
+            _ => None,
+        }
+    }
+}
+
+impl Display for VersionOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VersionOp::Lt => write!(f, "<<"),
+            VersionOp::Le => write!(f, "<="),
+            VersionOp::Eq => write!(f, "="),
+            VersionOp::Ge => write!(f, ">="),
+            VersionOp::Gt => write!(f, ">>"),
+        }
+    }
+}
+
+impl VersionConstraint {
+    pub fn satisfied_by(&self, version: &str) -> bool {
+        use Ordering::{Equal, Greater, Less};
+        let result = deb_version::compare_versions(version, &self.version);
+
+        match self.op {
+            VersionOp::Lt => result == Less,
+            VersionOp::Le => result == Less || result == Equal,
+            VersionOp::Eq => result == Equal,
+            VersionOp::Ge => result == Greater || result == Equal,
+            VersionOp::Gt => result == Greater,
+        }
+    }
+}
+
+impl Relation {
+    ///
+    /// Whether `version` meets the version constraint, if there's any
+    ///
+    pub fn satisfied_by(&self, version: &str) -> bool {
+        match &self.version {
+            Some(constraint) => constraint.satisfied_by(version),
+            None => true,
+        }
+    }
+}
+
+impl RelationAlternatives {
+    pub fn iter(&self) -> std::slice::Iter<'_, Relation> {
+        self.0.iter()
+    }
+}
+
+fn invalid(relation: &str, why: &str) -> anyhow::Error {
+    anyhow::anyhow!(ConfigError::UnexError { msg: format!("Invalid relation {:?}: {}", relation, why), err: None })
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.' | '_')
+}
+
+///
+/// Splits `[amd64 !i386]` / `<!nocheck cross>` contents into restrictions
+///
+fn restrictions(list: &str) -> Vec<Restriction> {
+    list.split_whitespace()
+        .map(|r| match r.strip_prefix('!') {
+            Some(name) => Restriction { name: name.to_owned(), negated: true },
+            None => Restriction { name: r.to_owned(), negated: false },
+        })
+        .collect()
+}
+
+impl FromStr for Relation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut rest = s.trim();
+
+        let end = rest.find(|c: char| !is_name_char(c)).unwrap_or(rest.len());
+        let name = &rest[..end];
+        if name.is_empty() {
+            return Err(invalid(s, "missing package name"));
+        }
+        rest = rest[end..].trim_start();
+
+        let mut arch = None;
+        if let Some(r) = rest.strip_prefix(':') {
+            let end = r.find(|c: char| !is_name_char(c)).unwrap_or(r.len());
+            if end == 0 {
+                return Err(invalid(s, "empty architecture qualifier"));
+            }
+            arch = Some(r[..end].to_owned());
+            rest = r[end..].trim_start();
+        }
+
+        let mut version = None;
+        if let Some(r) = rest.strip_prefix('(') {
+            let end = r.find(')').ok_or_else(|| invalid(s, "unclosed version constraint"))?;
+            let constraint = r[..end].trim();
+            let split = constraint.find(|c: char| !matches!(c, '<' | '>' | '=')).unwrap_or(constraint.len());
+            let op = VersionOp::parse(&constraint[..split]).ok_or_else(|| invalid(s, "unknown version operator"))?;
+            let number = constraint[split..].trim();
+            if number.is_empty() || number.contains(char::is_whitespace) {
+                return Err(invalid(s, "invalid version"));
+            }
+
+            version = Some(VersionConstraint { op, version: number.to_owned() });
+            rest = r[end + 1..].trim_start();
+        }
+
+        let mut archs = vec![];
+        if let Some(r) = rest.strip_prefix('[') {
+            let end = r.find(']').ok_or_else(|| invalid(s, "unclosed architecture restriction"))?;
+            archs = restrictions(&r[..end]);
+            rest = r[end + 1..].trim_start();
+        }
+
+        let mut profiles = vec![];
+        while let Some(r) = rest.strip_prefix('<') {
+            let end = r.find('>').ok_or_else(|| invalid(s, "unclosed build profile"))?;
+            profiles.push(restrictions(&r[..end]));
+            rest = r[end + 1..].trim_start();
+        }
+
+        if !rest.is_empty() {
+            return Err(invalid(s, "unexpected trailing characters"));
+        }
+
+        Ok(Self { name: name.to_owned(), arch, version, archs, profiles })
+    }
+}
+
+impl FromStr for RelationAlternatives {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(Self(s.split('|').map(|r| r.parse()).collect::<Result<Vec<Relation>>>()?))
+    }
+}
+
+///
+/// Parses a whole relationship field, e.g.: `libc6 (>= 2.34), mawk | gawk`
+///
+pub fn parse_relations(field: &str) -> Result<Vec<RelationAlternatives>> {
+    field.split(',')
+        // dpkg tolerates a trailing comma, and so do we
+        .filter(|r| !r.trim().is_empty())
+        .map(|r| r.parse())
+        .collect()
+}
+
+fn write_restrictions(f: &mut fmt::Formatter<'_>, list: &[Restriction]) -> fmt::Result {
+    for (i, r) in list.iter().enumerate() {
+        if i > 0 {
+            write!(f, " ")?;
+        }
+        write!(f, "{}{}", if r.negated { "!" } else { "" }, r.name)?;
+    }
+
+    Ok(())
+}
+
+impl Display for Relation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+
+        if let Some(arch) = &self.arch {
+            write!(f, ":{}", arch)?;
+        }
+
+        if let Some(constraint) = &self.version {
+            write!(f, " ({} {})", constraint.op, constraint.version)?;
+        }
+
+        if !self.archs.is_empty() {
+            write!(f, " [")?;
+            write_restrictions(f, &self.archs)?;
+            write!(f, "]")?;
+        }
+
+        for profile in self.profiles.iter() {
+            write!(f, " <")?;
+            write_restrictions(f, profile)?;
+            write!(f, ">")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for RelationAlternatives {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let alternatives = self.0.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+        write!(f, "{}", alternatives.join(" | "))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+    use crate::repos::deb::deb822;
+
+    const PACKAGES: &str = include_str!("../../../tests/fixtures/Packages");
+    const FIELDS: [&str; 9] = ["Depends", "Pre-Depends", "Recommends", "Suggests", "Enhances", "Breaks", "Conflicts", "Replaces", "Provides"];
+
+    fn real_relations() -> Vec<String> {
+        deb822::split(PACKAGES)
+            .into_iter()
+            .filter_map(|p| deb822::Paragraph::parse(p).ok())
+            .flat_map(|p| FIELDS.iter().filter_map(|f| p.get(f).map(|v| v.to_owned())).collect::<Vec<_>>())
+            .collect()
+    }
+
+    #[test]
+    fn parse_relation_test() {
+        let r: Relation = "libc6:amd64 (>= 2.34) [amd64 !i386] <!nocheck> <stage1 cross>".parse().unwrap();
+        assert_eq!(r.name, "libc6");
+        assert_eq!(r.arch.as_deref(), Some("amd64"));
+        assert_eq!(r.version, Some(VersionConstraint { op: VersionOp::Ge, version: "2.34".to_owned() }));
+        assert_eq!(r.archs, vec![
+            Restriction { name: "amd64".to_owned(), negated: false },
+            Restriction { name: "i386".to_owned(), negated: true },
+        ]);
+        assert_eq!(r.profiles.len(), 2);
+        assert!(r.profiles[0][0].negated);
+
+        let r: Relation = "demo_pkg(<<1:1.33.7~rc1)".parse().unwrap();
+        assert_eq!(r.name, "demo_pkg");
+        assert_eq!(r.version, Some(VersionConstraint { op: VersionOp::Lt, version: "1:1.33.7~rc1".to_owned() }));
+        assert_eq!(r.to_string(), "demo_pkg (<< 1:1.33.7~rc1)");
+
+        let r: Relation = "python3:any".parse().unwrap();
+        assert_eq!((r.name.as_str(), r.arch.as_deref(), r.version), ("python3", Some("any"), None));
+    }
+
+    #[test]
+    fn parse_alternatives_test() {
+        let relations = parse_relations("node-xtend, nodejs:any | nodejs (<< 12.22.5~dfsg-4~),\n mawk,").unwrap();
+        assert_eq!(relations.len(), 3);
+        assert_eq!(relations[1].0.len(), 2);
+        assert_eq!(relations[1].0[1].name, "nodejs");
+        assert_eq!(relations[1].to_string(), "nodejs:any | nodejs (<< 12.22.5~dfsg-4~)");
+    }
+
+    #[test]
+    fn invalid_relation_test() {
+        for r in ["", "(>= 1.0)", "demo_pkg (>= 1.33.7", "demo_pkg )>= 1.33.7(", "demo_pkg (=> 1.0)",
+            "demo_pkg (>= )", "demo_pkg [amd64", "demo_pkg <nocheck", "demo_pkg:", "demo pkg"] {
+            assert!(r.parse::<Relation>().is_err(), "{:?} should not parse", r);
+        }
+    }
+
+    #[test]
+    fn satisfied_by_test() {
+        let check = |constraint: &str, version: &str| format!("demo_pkg ({})", constraint).parse::<Relation>().unwrap().satisfied_by(version);
+
+        assert!(check("<= 1.33.8", "1.33.7"));
+        assert!(check(">= 1.33.7", "1.33.7"));
+        assert!(check("= 1.33.7", "1.33.7"));
+        assert!(!check("<< 1.33.7", "1.33.7"));
+        assert!(!check(">> 1.33.7", "1.33.7"));
+        assert!(check(">> 1.33.7~rc1", "1.33.7"));
+        assert!(check("< 1.0", "1.0"));
+        assert!(check(">= 1:0.1", "1:0.1-1"));
+        assert!(!check(">= 1:0.1", "9.9"));
+        assert!("demo_pkg".parse::<Relation>().unwrap().satisfied_by("0"));
+    }
+
+    #[test]
+    fn real_packages_test() {
+        let fields = real_relations();
+        assert!(fields.len() > 100);
+
+        for field in fields {
+            // The archive writes them in canonical form, so they must come back untouched
+            let relations = parse_relations(&field).unwrap();
+            let canonical = relations.iter().map(|r| r.to_string()).collect::<Vec<_>>().join(", ");
+            assert_eq!(canonical, field);
+        }
+    }
+
+    fn restriction() -> impl Strategy<Value = Restriction> {
+        ("[a-z][a-z0-9-]{0,8}", any::<bool>()).prop_map(|(name, negated)| Restriction { name, negated })
+    }
+
+    fn relation() -> impl Strategy<Value = Relation> {
+        let op = prop_oneof![Just(VersionOp::Lt), Just(VersionOp::Le), Just(VersionOp::Eq), Just(VersionOp::Ge), Just(VersionOp::Gt)];
+        let version = (op, "([0-9]:)?[0-9][0-9a-z.+~]{0,8}(-[0-9a-z.+~]{1,4})?").prop_map(|(op, version)| VersionConstraint { op, version });
+
+        (
+            "[a-z0-9][a-z0-9+.-]{1,15}",
+            proptest::option::of("any|native|amd64|i386|arm64"),
+            proptest::option::of(version),
+            proptest::collection::vec(restriction(), 0..3),
+            proptest::collection::vec(proptest::collection::vec(restriction(), 1..3), 0..2),
+        ).prop_map(|(name, arch, version, archs, profiles)| Relation { name, arch, version, archs, profiles })
+    }
+
+    proptest! {
+        #[test]
+        fn display_parse_round_trip(alternatives in proptest::collection::vec(relation(), 1..4)) {
+            let alternatives = RelationAlternatives(alternatives);
+            prop_assert_eq!(alternatives.to_string().parse::<RelationAlternatives>().unwrap(), alternatives);
+        }
+
+        #[test]
+        fn real_relations_whitespace(index in any::<prop::sample::Index>(), spaces in "[ \t\n]{0,3}") {
+            // Any amount of whitespace around the separators means the same
+            let fields = real_relations();
+            let field = index.get(&fields);
+            let spaced = field
+                .replace(", ", &format!("{},{}", spaces, spaces))
+                .replace(" | ", &format!("{}|{}", spaces, spaces))
+                .replace(" (", &format!("{}({}", spaces, spaces));
+
+            prop_assert_eq!(parse_relations(&spaced).unwrap(), parse_relations(field).unwrap());
+        }
+    }
+}
@@ -0,0 +1,356 @@
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, fs, path::Path};
+
+use super::{deb822::{self, Paragraph}, openpgp::{self, Keyring}, sources::DebianSource};
+use crate::repos::errors::ReleaseError;
+
+//
+// (In)Release files, what a repository vouches for
+// https://wiki.debian.org/DebianRepository/Format#A.22Release.22_files
+//
+
+const TRUSTED: &str = "/etc/apt/trusted.gpg";
+const TRUSTED_DIR: &str = "/etc/apt/trusted.gpg.d";
+const CLOCK_SKEW: u64 = 600;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+    pub sha256: String,
+    pub size: u64,
+}
+
+///
+/// Where a package comes from, as priorities and pins see it
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Origin {
+    pub origin: String,
+    pub label: String,
+    pub suite: String,
+    pub codename: String,
+    pub version: String,
+    pub component: String,
+    ///
+    /// Host of the repository
+    ///
+    pub site: String,
+    ///
+    /// Nothing gets installed from it unless asked for (e.g.: experimental, backports)
+    ///
+    pub not_automatic: bool,
+    ///
+    /// Yet what came from it gets upgraded from it
+    ///
+    pub but_automatic_upgrades: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Release {
+    ///
+    /// Where it came from, for error messages
+    ///
+    pub name: String,
+    pub paragraph: Paragraph,
+    files: HashMap<String, Checksum>,
+}
+
+impl Release {
+    pub fn parse(name: &str, contents: &str) -> Result<Self> {
+        let paragraph = match deb822::split(contents).first() {
+            Some(paragraph) => Paragraph::parse(paragraph)?,
+            None => anyhow::bail!(ReleaseError::Invalid(format!("{:?} is empty", name))),
+        };
+
+        let mut files = HashMap::new();
+        for line in paragraph.lines("SHA256") {
+            match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [sha256, size, path] => match size.parse() {
+                    Ok(size) => {
+                        files.insert(path.to_owned(), Checksum { sha256: sha256.to_ascii_lowercase(), size });
+                    },
+                    Err(_) => anyhow::bail!(ReleaseError::Invalid(format!("bad size in {:?}: {:?}", name, line))),
+                },
+                _ => anyhow::bail!(ReleaseError::Invalid(format!("bad SHA256 entry in {:?}: {:?}", name, line))),
+            }
+        }
+
+        Ok(Self { name: name.to_owned(), paragraph, files })
+    }
+
+    ///
+    /// A `Release` or an `InRelease` already verified by `opm update`
+    ///
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let name = path.to_string_lossy();
+
+        match contents.starts_with("-----BEGIN PGP SIGNED MESSAGE-----") {
+            true => Self::parse(&name, &openpgp::cleartext(&contents)?),
+            false => Self::parse(&name, &contents),
+        }
+    }
+
+    ///
+    /// Parses a clearsigned `InRelease`, only once its signature checks out against `keyring`
+    /// and it's still fresh at `now` (seconds since the epoch)
+    ///
+    pub fn verify(name: &str, contents: &str, keyring: &Keyring, now: u64) -> Result<Self> {
+        let release = Self::parse(name, &openpgp::verify_cleartext(name, contents, keyring, now)?)?;
+        release.fresh(now)?;
+        Ok(release)
+    }
+
+    ///
+    /// When it was made, from its `Date` field
+    ///
+    pub fn date(&self) -> Result<Option<u64>> {
+        self.time("Date")
+    }
+
+    fn time(&self, field: &str) -> Result<Option<u64>> {
+        match self.paragraph.get(field) {
+            Some(value) => match timestamp(value) {
+                Some(time) => Ok(Some(time)),
+                None => anyhow::bail!(ReleaseError::Invalid(format!("bad {} in {:?}: {:?}", field, self.name, value))),
+            },
+            None => Ok(None),
+        }
+    }
+
+    ///
+    /// Whether `now` is between its `Date` and its `Valid-Until`, so an old one can't be replayed forever
+    ///
+    fn fresh(&self, now: u64) -> Result<()> {
+        if let Some(until) = self.time("Valid-Until")?.filter(|until| *until <= now) {
+            anyhow::bail!(ReleaseError::Expired { release: self.name.clone(), since: until });
+        }
+        // A few minutes of clock skew between the repository and us is fine
+        if let Some(date) = self.date()?.filter(|date| *date > now + CLOCK_SKEW) {
+            anyhow::bail!(ReleaseError::NotYetValid { release: self.name.clone(), date });
+        }
+
+        Ok(())
+    }
+
+    pub fn origin(&self, component: &str, site: &str) -> Origin {
+        let field = |name: &str| self.paragraph.get(name).unwrap_or_default().to_owned();
+        let yes = |name: &str| self.paragraph.get(name).is_some_and(|value| value.eq_ignore_ascii_case("yes"));
+
+        Origin {
+            origin: field("Origin"),
+            label: field("Label"),
+            suite: field("Suite"),
+            codename: field("Codename"),
+            version: field("Version"),
+            component: component.to_owned(),
+            site: site.to_owned(),
+            not_automatic: yes("NotAutomatic"),
+            but_automatic_upgrades: yes("ButAutomaticUpgrades"),
+        }
+    }
+
+    ///
+    /// The entry of `path`, relative to the `dists/<suite>/` directory
+    ///
+    pub fn get(&self, path: &str) -> Option<&Checksum> {
+        self.files.get(path)
+    }
+
+    ///
+    /// Whether `data` is exactly what the Release says `path` is
+    ///
+    pub fn check(&self, path: &str, data: &[u8]) -> Result<()> {
+        let expected = match self.get(path) {
+            Some(checksum) => checksum,
+            None => anyhow::bail!(ReleaseError::NotListed { release: self.name.clone(), index: path.to_owned() }),
+        };
+
+        let got = Checksum {
+            sha256: Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect(),
+            size: data.len() as u64,
+        };
+
+        if got != *expected {
+            anyhow::bail!(ReleaseError::Mismatch {
+                index: path.to_owned(),
+                expected: format!("{} ({} bytes)", expected.sha256, expected.size),
+                got: format!("{} ({} bytes)", got.sha256, got.size),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+///
+/// Keys allowed to sign `source`: its `signed-by` keyrings if any, every trusted one otherwise.
+/// Fingerprints in `signed-by` narrow the choice down to those keys
+///
+pub fn keyring(source: &DebianSource) -> Result<Keyring> {
+    let signed_by = match &source.options.signed_by {
+        // deb822 sources can embed the key itself
+        Some(key) if key.contains("-----BEGIN PGP PUBLIC KEY BLOCK-----") => return Keyring::parse(key.as_bytes()),
+        Some(signed_by) => signed_by.split([',', ' ', '\t', '\n']).filter(|item| !item.is_empty()).collect::<Vec<_>>(),
+        None => vec![],
+    };
+
+    let (paths, fingerprints): (Vec<&str>, Vec<&str>) = signed_by.into_iter().partition(|item| item.starts_with('/'));
+
+    let mut keyring = Keyring::default();
+    if paths.is_empty() {
+        keyring = trusted();
+    } else {
+        for path in paths {
+            keyring.extend(Keyring::load(path)?);
+        }
+    }
+
+    if !fingerprints.is_empty() {
+        keyring.retain(&fingerprints.into_iter().map(|fp| fp.to_owned()).collect::<Vec<_>>());
+    }
+
+    Ok(keyring)
+}
+
+///
+/// `/etc/apt/trusted.gpg` and every `.gpg`/`.asc` under `/etc/apt/trusted.gpg.d`
+///
+fn trusted() -> Keyring {
+    let mut paths = vec![Path::new(TRUSTED).to_path_buf()];
+    if let Ok(entries) = fs::read_dir(TRUSTED_DIR) {
+        let mut entries = entries.flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "gpg" || ext == "asc"))
+            .collect::<Vec<_>>();
+
+        entries.sort();
+        paths.extend(entries);
+    }
+
+    let mut keyring = Keyring::default();
+    for path in paths.iter().filter(|path| path.exists()) {
+        match Keyring::load(path) {
+            Ok(keys) => keyring.extend(keys),
+            Err(e) => eprintln!("Ignoring the keyring {:?} :: {}", path, e),
+        }
+    }
+
+    keyring
+}
+
+///
+/// Seconds since the epoch of an RFC 2822 date (e.g.: `Sun, 18 Oct 2026 12:00:00 UTC`), which is what Releases use
+///
+fn timestamp(date: &str) -> Option<u64> {
+    // The weekday is optional, and says nothing the rest doesn't
+    let date = date.split_once(',').map_or(date, |(_, date)| date);
+    let [day, month, year, time, zone] = date.split_whitespace().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let month = MONTHS.iter().position(|m| m.eq_ignore_ascii_case(month))? as i64 + 1;
+    let (day, year): (i64, i64) = (day.parse().ok()?, year.parse().ok()?);
+    let time = time.split(':').map(|part| part.parse::<i64>().ok()).collect::<Option<Vec<_>>>()?;
+    let (hours, minutes, seconds) = match time[..] {
+        [hours, minutes, seconds] => (hours, minutes, seconds),
+        [hours, minutes] => (hours, minutes, 0),
+        _ => return None,
+    };
+
+    let offset = match zone {
+        "UTC" | "GMT" | "Z" => 0,
+        zone if zone.len() == 5 && (zone.starts_with('+') || zone.starts_with('-')) => {
+            let (hh, mm): (i64, i64) = (zone[1..3].parse().ok()?, zone[3..].parse().ok()?);
+            let offset = hh * 3600 + mm * 60;
+            if zone.starts_with('-') { -offset } else { offset }
+        },
+        _ => return None,
+    };
+
+    if !(1..=31).contains(&day) || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+
+    // Days since the epoch, counting from March so leap days come last
+    let (y, m) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * m + 2) / 5 + day - 1;
+    let days = era * 146097 + yoe * 365 + yoe / 4 - yoe / 100 + doy - 719468;
+
+    u64::try_from(days * 86400 + hours * 3600 + minutes * 60 + seconds - offset).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repos::deb::sources::{SourceKind, SourceOptions};
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+    // Sun, 18 Oct 2026 13:00:00 UTC
+    const NOW: u64 = 1792328400;
+
+    fn source(signed_by: &str) -> DebianSource {
+        DebianSource {
+            kind: SourceKind::Binary,
+            url: String::from("http://example.org/"),
+            distribution: String::from("stable"),
+            components: vec![String::from("main")],
+            options: SourceOptions { signed_by: Some(signed_by.to_owned()), ..Default::default() },
+        }
+    }
+
+    #[test]
+    fn release_test() {
+        let contents = fs::read_to_string(format!("{}/openpgp/opm-test_InRelease", FIXTURES)).unwrap();
+        let keyring = keyring(&source(&format!("{}/openpgp/opm-test.asc", FIXTURES))).unwrap();
+        let release = Release::verify("opm", &contents, &keyring, NOW).unwrap();
+
+        assert_eq!(release.paragraph.get("Suite"), Some("stable"));
+        assert_eq!(release.get("main/binary-amd64/Packages.xz").unwrap().size, 15956);
+
+        let packages = fs::read(format!("{}/Packages", FIXTURES)).unwrap();
+        release.check("main/binary-amd64/Packages", &packages).unwrap();
+
+        let err = release.check("main/binary-amd64/Packages", &packages[1..]).unwrap_err();
+        assert!(err.to_string().contains("does not match"), "{}", err);
+        assert!(release.check("main/binary-i386/Packages", &packages).is_err());
+    }
+
+    #[test]
+    fn signed_by_test() {
+        let path = format!("{}/openpgp/opm-test.asc", FIXTURES);
+        let contents = fs::read_to_string(format!("{}/openpgp/opm-test_InRelease", FIXTURES)).unwrap();
+        let verify = |signed_by: &str| Release::verify("opm", &contents, &keyring(&source(signed_by)).unwrap(), NOW);
+
+        assert!(verify(&format!("{},1E59E139CEEE0FD5AB39F8846BAB98D232D4EB70", path)).is_ok());
+        assert!(verify(&format!("{} 6F71F525282841EEDAF851B42F59B5F99B1BE0B4", path)).is_err());
+        assert!(verify(&fs::read_to_string(&path).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn fresh_test() {
+        let contents = fs::read_to_string(format!("{}/openpgp/opm-expiry_InRelease", FIXTURES)).unwrap();
+        let keyring = keyring(&source(&format!("{}/openpgp/opm-expiry.asc", FIXTURES))).unwrap();
+        let verify = |now: u64| Release::verify("opm", &contents, &keyring, now).map(|_| ()).map_err(|e| e.to_string());
+
+        assert_eq!(verify(NOW), Ok(()));
+        // Valid until Sun, 25 Oct 2026 10:00:00 UTC
+        assert!(verify(NOW + 7 * 86400).unwrap_err().contains("expired"));
+        // Made on Sun, 18 Oct 2026 10:00:00 UTC
+        assert!(verify(NOW - 4 * 3600).unwrap_err().contains("not valid yet"));
+    }
+
+    #[test]
+    fn timestamp_test() {
+        assert_eq!(timestamp("Sun, 18 Oct 2026 13:00:00 UTC"), Some(NOW));
+        assert_eq!(timestamp("18 Oct 2026 13:00:00 GMT"), Some(NOW));
+        assert_eq!(timestamp("Sun, 18 Oct 2026 15:00:00 +0200"), Some(NOW));
+        assert_eq!(timestamp("Thu, 01 Jan 1970 00:00:00 UTC"), Some(0));
+        assert_eq!(timestamp("Thu, 29 Feb 2024 00:00:00 UTC"), Some(1709164800));
+        assert_eq!(timestamp("Sun, 18 Foo 2026 13:00:00 UTC"), None);
+        assert_eq!(timestamp("Sun, 18 Oct 2026 13:00:00"), None);
+    }
+}
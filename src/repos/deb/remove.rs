@@ -1,50 +1,229 @@
 use anyhow::Result;
-use std::{path::Path, fs};
-use super::cache;
-use crate::repos::{errors::RemoveError, config::Config, deb::{package::Info, scripts}};
+use std::{path::{Path, PathBuf}, fs, io::ErrorKind};
+use std::os::unix::fs::PermissionsExt;
+use super::{cache, conffiles, files, triggers};
+use super::diversions::Diversions;
+use super::status::{State, Status, Want};
+use super::journal::{self, Journal, Operation, Step};
+use crate::repos::{errors::RemoveError, config::Config};
+use super::package::{ControlFile, Info};
+use super::scripts::{self, Script};
 
 pub fn remove(config: &Config, name: &str, purge: bool) -> Result<()> {
-    if cache::check_installed(config, name).is_some() {
-        let info = Info::load(Path::new(&format!("{}/{}", config.info, name)))?;
-
-        if let Some(md5sums) = &info.md5sums {
-            // TODO: Remove this workaround
-            let mut files = fs::read_to_string(md5sums)?.lines()
-                .map(|line| line.split(' '))
-                .flatten()
-                .filter(|f| f.contains('/'))
-                .map(|f| format!("/{}", f))
-                .collect::<Vec<_>>();
-                
-            if purge {
-                println!("Purging {} ...", name);
-                if let Some(conffiles) = &info.conffiles {
-                    let mut conffiles = fs::read_to_string(conffiles)?.lines()
-                        .map(|line| line.to_owned())
-                        .collect::<Vec<_>>();
-                    files.append(&mut conffiles);
-                }
-
-                scripts::execute_remove_pre(&info)?;
-                fs_extra::remove_items(&files)?;
-                scripts::execute_install_pos(&info)?;
-                cache::rm_package(config, name)?;
-            } else {
-                println!("Removing {} ...", name);
-                if let Some(bin) = files.into_iter().find(|name| name.contains("bin/")) {
-                    scripts::execute_remove_pre(&info)?;
-                    fs::remove_file(bin)?;
-                    scripts::execute_install_pos(&info)?;
-                    cache::rm_package(config, name)?;
-                }
-            }
+    // Purging also goes for what was removed before but left its conffiles behind
+    let pkg = cache::find_package(config, name)
+        .filter(|pkg| pkg.control.status.is_none_or(|status| status.is_installed() || (purge && status.state == State::ConfigFiles)));
+
+    if let Some(pkg) = pkg {
+        let name = &pkg.control.key();
+        let info = Info::installed(config, name)?;
+        let installed = pkg.control.status.is_none_or(|status| status.is_installed());
+        // Half-installed is what a failed postrm leaves, its files are gone already
+        let unpacked = installed && pkg.control.status.is_none_or(|status| status.state != State::HalfInstalled);
+        let mut paths = match files::read(config, name) {
+            Ok(paths) => paths,
+            Err(_) if !unpacked => vec![],
+            Err(_) => anyhow::bail!(RemoveError::NotFoundError(name.to_owned())),
+        };
+        // What others have too, like the files Multi-Arch: same instances share, stays
+        let owners = files::ownership(config)?;
+        paths.retain(|path| owners.get(path).is_none_or(|owners| owners.iter().all(|other| other == name)));
+
+        // The database records them (obsolete ones too), the control files only what the package ships
+        let conffiles = match (&pkg.control.conffiles, &info.conffiles) {
+            (Some(conffiles), _) => conffiles.iter().map(PathBuf::from).collect::<Vec<_>>(),
+            (None, Some(conffiles)) => fs::read_to_string(conffiles)?.lines()
+                .map(|line| PathBuf::from(line.trim()))
+                .collect::<Vec<_>>(),
+            (None, None) => vec![],
+        };
+        // Wherever they were diverted to is where they are, diversions knowing packages by their name alone
+        let diversions = Diversions::load(config)?;
+        let conffiles = conffiles.iter().map(|conffile| diversions.target(conffile, &pkg.control.package).to_path_buf()).collect::<Vec<_>>();
 
-            Ok(())
+        // Left with its conffiles, its list keeps only the directories it had holding them, for purging them later
+        let holding = paths.iter()
+            .filter(|path| conffiles.iter().any(|conffile| conffile.ancestors().skip(1).any(|dir| dir == *path)))
+            .filter(|path| *path != Path::new("/."))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let want = if purge {
+            println!("Purging {} ...", name);
+            // Its own directories are in its list, only ever gone once empty
+            paths.extend(conffiles.iter().flat_map(|conffile| conffiles::leftovers(conffile)));
+            paths.extend(conffiles.iter().cloned());
+            Want::Purge
         } else {
-            anyhow::bail!(RemoveError::NotFoundError(name.to_owned()));
+            println!("Removing {} ...", name);
+            paths.retain(|p| !conffiles.contains(p));
+            Want::Deinstall
+        };
+
+        let mut journal = Journal::begin(config, Operation::Remove, name)?;
+
+        if let Err(e) = take(config, &mut journal, &pkg.control, &info, &paths, unpacked, want) {
+            eprintln!("Failed to remove {}, rolling back ...", name);
+            drop(journal);
+            journal::recover(config)?;
+            anyhow::bail!(e);
+        }
+
+        journal.commit()?;
+
+        // Only what it unpacks activates its own triggers, removing it goes for file triggers alone
+        triggers::activate(config, name, &Info::default(), &paths)?;
+
+        // Past this point the files are gone for good, a failing postrm leaves it half-installed
+        if installed {
+            scripts::run(config, None, &pkg.control, &info, Script::Postrm, &["remove"])?;
+
+            // Removed packages stay as `config-files` for as long as their conffiles do
+            if !purge && conffiles.is_empty() {
+                return forget(config, name);
+            }
+            if !purge {
+                files::write(config, name, &holding)?;
+            }
+            cache::set_status(config, name, Status::new(want, State::ConfigFiles))?;
+        }
+
+        if purge {
+            scripts::run(config, None, &pkg.control, &info, Script::Postrm, &["purge"])?;
+            forget(config, name)?;
         }
-            
+
+        Ok(())
     } else {
         anyhow::bail!(RemoveError::NotFoundError(name.to_owned()));
     }
-}
\ No newline at end of file
+}
+
+///
+/// Every step of a single package removal up to its postrm, each one journaled before it happens.
+/// What's not `unpacked` anymore has no prerm to run
+///
+fn take(config: &Config, journal: &mut Journal, pkg: &ControlFile, info: &Info, paths: &[PathBuf], unpacked: bool, want: Want) -> Result<()> {
+    let name = &pkg.key();
+
+    if unpacked {
+        if let Err(e) = scripts::run(config, Some(&mut *journal), pkg, info, Script::Prerm, &["remove"]) {
+            // Nothing was touched, but its postinst may not agree with it staying configured
+            if !scripts::unwind(config, pkg, info, Script::Postinst, &["abort-remove"]) {
+                cache::set_status(config, name, Status::new(pkg.status.map_or(Want::Install, |status| status.want), State::HalfConfigured))?;
+            }
+            return Err(e);
+        }
+    }
+
+    unlink(config, journal, paths)?;
+
+    if let Some(stanza) = cache::get_stanza(config, name)?.filter(|_| unpacked) {
+        journal.log(Step::DbRemoved { pkg: name.to_owned(), stanza })?;
+        cache::set_status(config, name, Status::new(want, State::HalfInstalled))?;
+    }
+
+    let list = files::list_path(config, name);
+    if list.exists() {
+        journal.backup(&list, false)?;
+    }
+
+    Ok(())
+}
+
+///
+/// Takes `name` out of the database along with its control files and scripts log
+///
+fn forget(config: &Config, name: &str) -> Result<()> {
+    cache::rm_package(config, name)?;
+
+    let info = Path::new(&config.info).join(name);
+    if info.exists() {
+        fs::remove_dir_all(info)?;
+    }
+
+    match fs::remove_file(scripts::log_path(config, name)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => (),
+    }
+
+    Ok(())
+}
+
+///
+/// Removes `paths` from `config.root`, directories only once they're empty.
+/// Symlinks on the way are followed the same way as when installing, the ones leading out of the root skipped
+///
+pub(super) fn unlink(config: &Config, journal: &mut Journal, paths: &[PathBuf]) -> Result<()> {
+    let paths = paths.iter()
+        .filter(|p| *p != Path::new("/."))
+        .filter_map(|p| match files::locate(Path::new(&config.root), p) {
+            None => {
+                eprintln!("Not removing {}, it leads outside of the root through a symlink", p.display());
+                None
+            },
+            path => path,
+        })
+        .collect::<Vec<PathBuf>>();
+
+    // Deepest paths first, so directories are emptied before we get to them
+    for path in paths.iter().rev() {
+        let result = match fs::symlink_metadata(path) {
+            Ok(meta) if meta.is_dir() => {
+                journal.log(Step::DirRemoved { path: path.clone(), mode: meta.permissions().mode() })?;
+                fs::remove_dir(path)
+            },
+            Ok(_) => {
+                journal.backup(path, false)?;
+                Ok(())
+            },
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(()) => (),
+            Err(e) => match e.kind() {
+                // Either gone already or still holding someone else's files
+                ErrorKind::NotFound | ErrorKind::DirectoryNotEmpty => (),
+                _ => anyhow::bail!(RemoveError::UnexError { msg: format!("Could not remove {:?}", path), err: Some(e.into()) }),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::symlink;
+    use super::*;
+
+    #[test]
+    fn unlink_test() {
+        let dir = std::env::temp_dir().join(format!("opm-unlink-{}", std::process::id()));
+        let (root, host) = (dir.join("root"), dir.join("host"));
+        fs::create_dir_all(root.join("opt")).unwrap();
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::create_dir_all(&host).unwrap();
+        fs::write(root.join("etc/opm.conf"), "").unwrap();
+        fs::write(host.join("passwd"), "").unwrap();
+
+        // Absolute links start over at the root, not at the host's `/`
+        symlink("/etc", root.join("opt/etc")).unwrap();
+        symlink(&host, root.join("opt/host")).unwrap();
+        symlink("../../..", root.join("opt/up")).unwrap();
+
+        let mut config = crate::repos::setup().unwrap();
+        config.root = root.display().to_string();
+        config.tmp = dir.join("tmp").display().to_string();
+
+        let mut journal = Journal::begin(&config, Operation::Remove, "opm").unwrap();
+        let paths = ["/opt/etc/opm.conf", "/opt/host/passwd", "/opt/up/host/passwd"].map(PathBuf::from);
+        unlink(&config, &mut journal, &paths).unwrap();
+        journal.commit().unwrap();
+
+        assert!(!root.join("etc/opm.conf").exists());
+        assert!(host.join("passwd").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,871 @@
+use anyhow::Result;
+use std::{cell::Cell, cmp::Ordering, collections::HashMap, str::FromStr};
+
+use crate::repos::{config::Config, errors::InstallError};
+use super::{cache, pkgcache::PackageCache};
+use super::arch::Architectures;
+use super::package::ControlFile;
+use super::policy::Policy;
+use super::status::Want;
+use super::relation::{Relation, RelationAlternatives};
+
+//
+// Dependency resolution: picks a set of packages that satisfies every relation
+// of what was asked for, backtracking over alternatives and providers. A choice that
+// makes some later relation impossible (even a sibling's) gets undone and the next one tried
+//
+
+///
+/// How many packages may be tried before giving up, so an unsolvable universe doesn't take forever
+///
+const BUDGET: usize = 100_000;
+
+///
+/// Packages indexed by name and by what they provide
+///
+#[derive(Debug, Default)]
+struct Index {
+    packages: Vec<ControlFile>,
+    names: HashMap<String, Vec<usize>>,
+    provides: HashMap<String, Vec<(usize, Option<String>)>>,
+}
+
+impl Index {
+    fn new(mut packages: Vec<ControlFile>, archs: &Architectures, policy: &Policy) -> Self {
+        // Highest version first, native before foreign. The same version from several
+        // places counts as the one with the highest priority
+        packages.sort_by(|a, b| a.package.cmp(&b.package)
+            .then_with(|| deb_version::compare_versions(&b.version, &a.version))
+            .then_with(|| policy.priority(b).cmp(&policy.priority(a)))
+            .then_with(|| (archs.effective(&a.architecture) != archs.native).cmp(&(archs.effective(&b.architecture) != archs.native))));
+        packages.dedup_by(|a, b| a.package == b.package && a.version == b.version && a.architecture == b.architecture);
+
+        let mut index = Self { packages, ..Default::default() };
+        for (i, pkg) in index.packages.iter().enumerate() {
+            index.names.entry(pkg.package.clone()).or_default().push(i);
+
+            for provided in pkg.provides.iter().flatten().flat_map(|alternatives| alternatives.iter()) {
+                let version = provided.version.as_ref().map(|constraint| constraint.version.clone());
+                index.provides.entry(provided.name.clone()).or_default().push((i, version));
+            }
+        }
+
+        index
+    }
+
+    ///
+    /// Everything that satisfies `relation` for a package of the `from` architecture:
+    /// the real package first, then its providers
+    ///
+    fn matching(&self, relation: &Relation, from: &str, archs: &Architectures) -> Vec<&ControlFile> {
+        let real = self.names.get(&relation.name)
+            .into_iter()
+            .flatten()
+            .map(|&i| &self.packages[i])
+            .filter(|pkg| relation.satisfied_by(&pkg.version));
+
+        let virtual_ = self.provides.get(&relation.name)
+            .into_iter()
+            .flatten()
+            .filter(|(_, version)| provided_satisfies(relation, version))
+            .map(|&(i, _)| &self.packages[i]);
+
+        let mut result: Vec<&ControlFile> = vec![];
+        for pkg in real.chain(virtual_).filter(|pkg| arch_satisfies(relation, pkg, from, archs)) {
+            if !result.iter().any(|p| p.package == pkg.package && p.version == pkg.version && p.architecture == pkg.architecture) {
+                result.push(pkg);
+            }
+        }
+
+        result
+    }
+
+    fn get(&self, key: &str) -> Option<&ControlFile> {
+        let name = key.split(':').next().unwrap_or(key);
+        self.names.get(name)?.iter().map(|&i| &self.packages[i]).find(|pkg| pkg.key() == key)
+    }
+}
+
+///
+/// An unversioned `Provides` never satisfies a versioned relation
+///
+fn provided_satisfies(relation: &Relation, version: &Option<String>) -> bool {
+    match (&relation.version, version) {
+        (None, _) => true,
+        (Some(constraint), Some(version)) => constraint.satisfied_by(version),
+        (Some(_), None) => false,
+    }
+}
+
+///
+/// Whether `pkg` can satisfy `relation` (`:any`, `:native`, `:<arch>` or nothing) of a package
+/// built for `from`. `Multi-Arch: foreign` ones satisfy every architecture,
+/// `Multi-Arch: allowed` ones only the `:any` relations
+///
+fn arch_satisfies(relation: &Relation, pkg: &ControlFile, from: &str, archs: &Architectures) -> bool {
+    let arch = archs.effective(&pkg.architecture);
+    let multi_arch = pkg.multi_arch.as_deref();
+
+    match relation.arch.as_deref() {
+        None => multi_arch == Some("foreign") || arch == archs.effective(from),
+        Some("any") => matches!(multi_arch, Some("foreign") | Some("allowed")) || arch == archs.effective(from),
+        Some("native") => arch == archs.native,
+        Some(qualifier) => arch == qualifier,
+    }
+}
+
+///
+/// Whether `pkg` is `relation`, either by name or through its `Provides`.
+/// Without `from`, architectures don't matter (as for `Conflicts` and `Breaks`)
+///
+fn matches(relation: &Relation, pkg: &ControlFile, from: Option<&str>, archs: &Architectures) -> bool {
+    from.is_none_or(|from| arch_satisfies(relation, pkg, from, archs))
+        && ((pkg.package == relation.name && relation.satisfied_by(&pkg.version))
+            || pkg.provides.iter()
+                .flatten()
+                .flat_map(|alternatives| alternatives.iter())
+                .any(|provided| provided.name == relation.name
+                    && provided_satisfies(relation, &provided.version.as_ref().map(|c| c.version.clone()))))
+}
+
+fn flatten(relations: &Option<Vec<RelationAlternatives>>) -> impl Iterator<Item = &Relation> {
+    relations.iter().flatten().flat_map(|alternatives| alternatives.iter())
+}
+
+///
+/// Every candidate the resolver may pick and what's installed right now
+///
+#[derive(Debug)]
+pub struct Universe {
+    available: Index,
+    installed: Index,
+    archs: Architectures,
+    policy: Policy,
+}
+
+impl Universe {
+    ///
+    /// Candidates built for an architecture that isn't enabled are left out
+    ///
+    pub fn new(available: Vec<ControlFile>, installed: Vec<ControlFile>, archs: Architectures, policy: Policy) -> Self {
+        let available = available.into_iter()
+            .filter(|pkg| archs.allowed(&pkg.architecture))
+            .collect();
+
+        Self {
+            available: Index::new(available, &archs, &policy),
+            installed: Index::new(installed, &archs, &policy),
+            archs,
+            policy,
+        }
+    }
+
+    ///
+    /// Orders `pkgs` by priority, then version. Unless they were asked for explicitly,
+    /// versions the policy doesn't allow over the installed one are dropped
+    ///
+    fn candidates<'a>(&self, mut pkgs: Vec<&'a ControlFile>, explicit: bool) -> Vec<&'a ControlFile> {
+        self.policy.sort(&mut pkgs);
+
+        if !explicit {
+            pkgs.retain(|pkg| {
+                let installed = self.installed.get(&pkg.key()).map(|installed| {
+                    let same = self.available.names.get(&installed.package).into_iter().flatten().map(|&i| &self.available.packages[i]);
+                    (installed, self.policy.installed_priority(installed, same))
+                });
+
+                self.policy.allowed(pkg, installed)
+            });
+        }
+
+        pkgs
+    }
+
+    ///
+    /// The database, plus every package of the cache that installing `names` could involve:
+    /// whatever they depend on and whatever is installed already (which may need an upgrade)
+    ///
+    pub fn load(config: &Config, names: &[&str]) -> Result<Self> {
+        let installed = cache::db_dump(config)
+            .into_iter()
+            .map(|pkg| pkg.control)
+            .collect::<Vec<_>>();
+
+        let roots = names.iter()
+            .map(|name| name.split([':', ' ', '(', '=', '/']).next().unwrap_or(name))
+            .chain(installed.iter().map(|pkg| pkg.package.as_str()))
+            .collect::<Vec<_>>();
+
+        let available = PackageCache::open(config)?.reachable(&roots)?;
+        Ok(Self::new(available, installed, Architectures::from_config(config), Policy::from_config(config)?))
+    }
+}
+
+///
+/// What has to happen to the system, `install` being in the order to install it
+/// and `remove` holding the keys (`pkg` or `pkg:arch`) of what goes away
+///
+#[derive(Debug, Default)]
+pub struct Transaction {
+    pub install: Vec<ControlFile>,
+    pub remove: Vec<String>,
+    ///
+    /// Keys of the installed packages that have a newer candidate which couldn't be picked
+    ///
+    pub kept: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct State<'a> {
+    selected: HashMap<String, &'a ControlFile>,
+    order: Vec<&'a ControlFile>,
+    removed: Vec<String>,
+}
+
+///
+/// Why something couldn't be picked, outermost step first
+///
+type Problem = Vec<String>;
+
+///
+/// What's left to do before the packages picked so far can be installed
+///
+#[derive(Debug, Clone)]
+enum Goal<'a> {
+    ///
+    /// One of `alternatives` has to be there for a package built for `from`, `why` being the steps that led to it
+    ///
+    Satisfy { alternatives: &'a RelationAlternatives, from: &'a str, why: Problem },
+    ///
+    /// The installed `pkg` would be broken, so it's upgraded to one of `upgrades` (or goes away, if that's allowed)
+    ///
+    Upgrade { pkg: &'a ControlFile, upgrades: Vec<&'a ControlFile>, why: Problem },
+    ///
+    /// Everything `pkg` depends on is in, so it comes next
+    ///
+    Done(&'a ControlFile),
+}
+
+struct Resolver<'a> {
+    universe: &'a Universe,
+    force: bool,
+    ///
+    /// Whether installed packages in the way can go away even if nothing replaces them
+    ///
+    removals: bool,
+    budget: Cell<usize>,
+}
+
+impl<'a> Resolver<'a> {
+    ///
+    /// Whatever would be on the system if the transaction happened right now
+    ///
+    fn present<'b>(&self, state: &'b State<'a>) -> impl Iterator<Item = (&'a ControlFile, bool)> + 'b where 'a: 'b {
+        let installed = self.universe.installed.packages.iter()
+            .filter(move |pkg| {
+                let key = pkg.key();
+                !state.removed.contains(&key) && !state.selected.contains_key(&key)
+            })
+            .map(|pkg| (pkg, true));
+
+        state.selected.values().map(|&pkg| (pkg, false)).chain(installed)
+    }
+
+    fn describe(&self, pkg: &ControlFile) -> String {
+        match self.universe.archs.effective(&pkg.architecture) == self.universe.archs.native {
+            true => format!("{} {}", pkg.package, pkg.version),
+            false => format!("{}:{} {}", pkg.package, pkg.architecture, pkg.version),
+        }
+    }
+
+    fn satisfied(&self, state: &State<'a>, alternatives: &RelationAlternatives, from: &str) -> bool {
+        self.present(state).any(|(pkg, _)| alternatives.iter().any(|relation| matches(relation, pkg, Some(from), &self.universe.archs)))
+    }
+
+    ///
+    /// Works through `agenda` (the next goal being the last one) up to a choice to make
+    ///
+    fn solve(&self, state: &mut State<'a>, mut agenda: Vec<Goal<'a>>) -> Result<(), Problem> {
+        while let Some(goal) = agenda.pop() {
+            match goal {
+                Goal::Done(pkg) => state.order.push(pkg),
+                Goal::Satisfy { alternatives, from, why } => {
+                    if self.satisfied(state, alternatives, from) {
+                        continue;
+                    }
+
+                    let archs = &self.universe.archs;
+                    let candidates = self.universe.candidates(alternatives.iter()
+                        .flat_map(|relation| self.universe.available.matching(relation, from, archs))
+                        .collect(), false);
+
+                    if candidates.is_empty() {
+                        let available = alternatives.iter()
+                            .flat_map(|relation| self.universe.available.names.get(&relation.name).into_iter().flatten())
+                            .map(|&i| self.describe(&self.universe.available.packages[i]))
+                            .collect::<Vec<_>>();
+
+                        return match available.is_empty() {
+                            true => Err(prefix(why, format!("nothing provides {}", alternatives))),
+                            false => Err(prefix(why, format!("{} is not satisfied by {}", alternatives, available.join(", ")))),
+                        };
+                    }
+
+                    return self.choose(state, candidates, &why, None, agenda);
+                },
+                Goal::Upgrade { pkg, upgrades, why } => {
+                    if state.removed.contains(&pkg.key()) {
+                        continue;
+                    }
+
+                    let remove = self.removals.then(|| pkg.key());
+                    return self.choose(state, upgrades, &why, remove, agenda)
+                        .map_err(|problem| [prefix(why.clone(), format!("breaks {} (installed)", self.describe(pkg))), problem].concat());
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Tries every candidate in order (then taking `remove` away, if any), along with whatever is left
+    /// to do in `rest`, keeping the first one that works out for all of it
+    ///
+    fn choose(&self, state: &mut State<'a>, candidates: Vec<&'a ControlFile>, why: &Problem, remove: Option<String>, rest: Vec<Goal<'a>>) -> Result<(), Problem> {
+        let mut problems: Vec<Problem> = vec![];
+        let mut fail = |problem: Problem| if !problems.contains(&problem) {
+            problems.push(problem);
+        };
+
+        for candidate in candidates {
+            let snapshot = state.clone();
+            let result = self.select(state, candidate, why).and_then(|goals| {
+                let mut agenda = rest.clone();
+                agenda.extend(goals);
+                self.solve(state, agenda)
+            });
+
+            match result {
+                Ok(()) => return Ok(()),
+                Err(problem) => {
+                    *state = snapshot;
+                    fail(problem);
+                },
+            }
+        }
+
+        if let Some(key) = remove {
+            let snapshot = state.clone();
+            state.removed.push(key);
+            match self.solve(state, rest) {
+                Ok(()) => return Ok(()),
+                Err(problem) => {
+                    *state = snapshot;
+                    fail(problem);
+                },
+            }
+        }
+
+        Err(problems.concat())
+    }
+
+    ///
+    /// Picks `pkg`, returning what's left to do for it (next goal last)
+    ///
+    fn select(&self, state: &mut State<'a>, pkg: &'a ControlFile, why: &Problem) -> Result<Vec<Goal<'a>>, Problem> {
+        let (this, key) = (self.describe(pkg), pkg.key());
+
+        if let Some(selected) = state.selected.get(&key) {
+            return match selected.version == pkg.version && selected.architecture == pkg.architecture {
+                true => Ok(vec![]),
+                false => Err(prefix(why.clone(), format!("{} was already picked instead of {}", self.describe(selected), this))),
+            };
+        }
+
+        match self.budget.get() {
+            0 => return Err(prefix(why.clone(), String::from("gave up after trying too many combinations"))),
+            left => self.budget.set(left - 1),
+        }
+
+        let mut goals = match self.force {
+            true => vec![],
+            false => self.check_conflicts(state, pkg, &prefix(why.clone(), this.clone()))?,
+        };
+
+        // In before its dependencies, so a cycle sees it as satisfied
+        state.selected.insert(key, pkg);
+
+        for (field, relations) in [("pre-depends on", &pkg.pre_depends), ("depends on", &pkg.depends)] {
+            goals.extend(relations.iter().flatten().map(|alternatives| Goal::Satisfy {
+                alternatives,
+                from: &pkg.architecture,
+                why: prefix(why.clone(), format!("{} {} {}", this, field, alternatives)),
+            }));
+        }
+        goals.push(Goal::Done(pkg));
+
+        goals.reverse();
+        Ok(goals)
+    }
+
+    ///
+    /// Conflicts and Breaks, both ways, against whatever would be present.
+    /// A conflicting installed package goes away if `pkg` replaces it, a broken
+    /// one gets upgraded to a version that isn't broken anymore (the goals returned)
+    ///
+    fn check_conflicts(&self, state: &mut State<'a>, pkg: &'a ControlFile, why: &Problem) -> Result<Vec<Goal<'a>>, Problem> {
+        let archs = &self.universe.archs;
+        let present = self.present(state).collect::<Vec<_>>();
+        let mut goals = vec![];
+
+        for (other, installed) in present {
+            let installed_ = if installed { " (installed)" } else { "" };
+
+            if other.package == pkg.package {
+                // `Multi-Arch: same` instances must all be at the very same version
+                if other.key() != pkg.key() && other.version != pkg.version {
+                    return Err(prefix(why.clone(), format!("must be at the same version as {}{}", self.describe(other), installed_)));
+                }
+                continue;
+            }
+
+            let conflicts = flatten(&pkg.conflicts).any(|relation| matches(relation, other, None, archs))
+                || flatten(&other.conflicts).any(|relation| matches(relation, pkg, None, archs));
+
+            if conflicts {
+                if installed && (self.removals || flatten(&pkg.replaces).any(|relation| matches(relation, other, None, archs))) {
+                    state.removed.push(other.key());
+                    continue;
+                }
+
+                return Err(prefix(why.clone(), format!("conflicts with {}{}", self.describe(other), installed_)));
+            }
+
+            let breaks = |a: &ControlFile, b: &ControlFile| flatten(&a.breaks).any(|relation| matches(relation, b, None, archs));
+
+            if breaks(pkg, other) || breaks(other, pkg) {
+                if installed {
+                    let upgrades = self.universe.available.names.get(&other.package)
+                        .into_iter()
+                        .flatten()
+                        .map(|&i| &self.universe.available.packages[i])
+                        .filter(|c| c.key() == other.key())
+                        .filter(|c| deb_version::compare_versions(&c.version, &other.version) == Ordering::Greater)
+                        .filter(|c| !breaks(pkg, c) && !breaks(c, pkg))
+                        .collect::<Vec<_>>();
+                    let upgrades = self.universe.candidates(upgrades, false);
+
+                    if !upgrades.is_empty() || self.removals {
+                        goals.push(Goal::Upgrade { pkg: other, upgrades, why: why.clone() });
+                        continue;
+                    }
+                }
+
+                return Err(prefix(why.clone(), format!("breaks {}{}", self.describe(other), installed_)));
+            }
+        }
+
+        Ok(goals)
+    }
+}
+
+fn prefix(mut why: Problem, problem: String) -> Problem {
+    why.push(problem);
+    why
+}
+
+///
+/// What was asked for: `pkg[:arch]`, optionally `=version` or `/suite` (suite or codename)
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub relation: Relation,
+    pub suite: Option<String>,
+}
+
+impl Request {
+    ///
+    /// `pkg` or `pkg:arch`, without the version nor the suite
+    ///
+    pub fn key(&self) -> String {
+        match &self.relation.arch {
+            Some(arch) => format!("{}:{}", self.relation.name, arch),
+            None => self.relation.name.clone(),
+        }
+    }
+
+    ///
+    /// Whether a version was picked, which is allowed to be a downgrade
+    ///
+    pub fn explicit(&self) -> bool {
+        self.relation.version.is_some() || self.suite.is_some()
+    }
+}
+
+impl FromStr for Request {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, version, suite) = match (s.split_once('='), s.split_once('/')) {
+            (Some((name, version)), _) => (name, Some(version), None),
+            (None, Some((name, suite))) => (name, None, Some(suite.to_owned())),
+            (None, None) => (s, None, None),
+        };
+
+        let relation = match version {
+            Some(version) => format!("{} (= {})", name, version).parse::<Relation>()?,
+            None => name.parse::<Relation>()?,
+        };
+
+        Ok(Self { relation, suite })
+    }
+}
+
+///
+/// Works out everything needed to install `names` (see `Request`), dependencies
+/// coming before whatever depends on them (`Pre-Depends` included)
+///
+pub fn resolve(universe: &Universe, names: &[&str], force: bool) -> Result<Transaction> {
+    let resolver = Resolver { universe, force, removals: false, budget: Cell::new(BUDGET) };
+    let mut state = State::default();
+
+    for name in names {
+        let request = name.parse::<Request>()?;
+        let relation = &request.relation;
+        let candidates = universe.available.matching(relation, &universe.archs.native, &universe.archs)
+            .into_iter()
+            .filter(|c| request.suite.as_ref().is_none_or(|suite| c.origin.as_ref()
+                .is_some_and(|origin| origin.suite == *suite || origin.codename == *suite)))
+            .collect::<Vec<_>>();
+
+        if candidates.is_empty() {
+            let what = match &request.suite {
+                Some(suite) => format!("{} in {}", relation, suite),
+                None => relation.to_string(),
+            };
+            anyhow::bail!(InstallError::Unresolvable { pkg: name.to_string(), why: vec![format!("nothing provides {}", what)] });
+        }
+
+        // The newest one, even if some older version is already installed
+        let candidates = universe.candidates(candidates, request.explicit())
+            .into_iter()
+            .filter(|c| universe.installed.get(&c.key()).is_none_or(|i| i.version != c.version))
+            .collect::<Vec<_>>();
+
+        if candidates.is_empty() {
+            anyhow::bail!(InstallError::Unresolvable { pkg: name.to_string(), why: vec![String::from("the installed version is already the candidate")] });
+        }
+
+        if let Err(why) = resolver.choose(&mut state, candidates, &vec![], None, vec![]) {
+            anyhow::bail!(InstallError::Unresolvable { pkg: name.to_string(), why });
+        }
+    }
+
+    Ok(Transaction {
+        install: state.order.into_iter().cloned().collect(),
+        remove: state.removed,
+        kept: vec![],
+    })
+}
+
+///
+/// Moves every installed package to its candidate when that's another version. A safe
+/// upgrade never removes anything, so what would need it is kept back; a `full` one lets
+/// whatever conflicts with or is broken by the upgrades go away
+///
+pub fn upgrade(universe: &Universe, full: bool) -> Result<Transaction> {
+    let resolver = Resolver { universe, force: false, removals: full, budget: Cell::new(BUDGET) };
+    let (mut state, mut kept) = (State::default(), vec![]);
+
+    for installed in universe.installed.packages.iter() {
+        let key = installed.key();
+        if state.selected.contains_key(&key) || state.removed.contains(&key) {
+            continue;
+        }
+
+        let available = universe.available.names.get(&installed.package)
+            .into_iter()
+            .flatten()
+            .map(|&i| &universe.available.packages[i])
+            .filter(|c| c.key() == key)
+            .collect();
+
+        // Anything ranking below the installed version isn't worth it
+        let candidates = universe.candidates(available, false)
+            .into_iter()
+            .take_while(|c| c.version != installed.version)
+            .collect::<Vec<_>>();
+
+        if candidates.is_empty() {
+            continue;
+        }
+
+        // Held packages stay at their version
+        if installed.status.is_some_and(|status| status.want == Want::Hold) {
+            kept.push(key);
+            continue;
+        }
+
+        let snapshot = state.clone();
+        match resolver.choose(&mut state, candidates, &vec![], None, vec![]) {
+            Ok(()) if full || state.removed.len() == snapshot.removed.len() => (),
+            _ => {
+                state = snapshot;
+                kept.push(key);
+            },
+        }
+    }
+
+    Ok(Transaction {
+        install: state.order.into_iter().cloned().collect(),
+        remove: state.removed,
+        kept,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{deb822::Paragraph, release::Origin};
+    use std::sync::Arc;
+    use super::*;
+
+    fn pkg(stanza: &str) -> ControlFile {
+        let arch = if stanza.contains("Architecture:") { "" } else { "\nArchitecture: all" };
+        let stanza = format!("{}{}\nMaintainer: opm\nDescription: test\n", stanza.trim(), arch);
+        ControlFile::from_paragraph(Paragraph::parse(&stanza).unwrap()).unwrap()
+    }
+
+    fn universe(available: Vec<ControlFile>, installed: Vec<ControlFile>) -> Universe {
+        Universe::new(available, installed, Architectures::new("amd64", &["i386".to_owned()]), Policy::default())
+    }
+
+    fn names(transaction: &Transaction) -> Vec<String> {
+        transaction.install.iter().map(|p| format!("{}:{} {}", p.package, p.architecture, p.version).replace(":all", "")).collect()
+    }
+
+    #[test]
+    fn order_test() {
+        let universe = universe(vec![
+            pkg("Package: a\nVersion: 1\nDepends: b, c\nPre-Depends: d"),
+            pkg("Package: b\nVersion: 1\nDepends: c"),
+            pkg("Package: c\nVersion: 1"),
+            pkg("Package: d\nVersion: 1"),
+        ], vec![]);
+
+        let transaction = resolve(&universe, &["a"], false).unwrap();
+        assert_eq!(names(&transaction), vec!["d 1", "c 1", "b 1", "a 1"]);
+    }
+
+    #[test]
+    fn alternatives_test() {
+        let universe = universe(vec![
+            pkg("Package: a\nVersion: 1\nDepends: b (>= 2) | c"),
+            pkg("Package: b\nVersion: 1"),
+            pkg("Package: c\nVersion: 1"),
+            pkg("Package: e\nVersion: 1\nDepends: b | c"),
+        ], vec![pkg("Package: b\nVersion: 1\nStatus: install ok installed")]);
+
+        // The installed b is too old, so c it is
+        assert_eq!(names(&resolve(&universe, &["a"], false).unwrap()), vec!["c 1", "a 1"]);
+        // Already installed alternatives win
+        assert_eq!(names(&resolve(&universe, &["e"], false).unwrap()), vec!["e 1"]);
+    }
+
+    #[test]
+    fn versions_test() {
+        let universe = universe(vec![
+            pkg("Package: a\nVersion: 1\nDepends: b (<< 3)"),
+            pkg("Package: b\nVersion: 3"),
+            pkg("Package: b\nVersion: 2"),
+            pkg("Package: b\nVersion: 1"),
+        ], vec![]);
+
+        assert_eq!(names(&resolve(&universe, &["a"], false).unwrap()), vec!["b 2", "a 1"]);
+        assert_eq!(names(&resolve(&universe, &["b"], false).unwrap()), vec!["b 3"]);
+    }
+
+    #[test]
+    fn provides_test() {
+        let universe = universe(vec![
+            pkg("Package: a\nVersion: 1\nDepends: mta"),
+            pkg("Package: b\nVersion: 1\nDepends: libfoo (>= 2)"),
+            pkg("Package: postfix\nVersion: 3\nProvides: mta"),
+            pkg("Package: foo-old\nVersion: 1\nProvides: libfoo"),
+            pkg("Package: foo-new\nVersion: 1\nProvides: libfoo (= 2.1)"),
+        ], vec![]);
+
+        assert_eq!(names(&resolve(&universe, &["a"], false).unwrap()), vec!["postfix 3", "a 1"]);
+        // An unversioned Provides can't satisfy a versioned dependency
+        assert_eq!(names(&resolve(&universe, &["b"], false).unwrap()), vec!["foo-new 1", "b 1"]);
+        assert_eq!(names(&resolve(&universe, &["mta"], false).unwrap()), vec!["postfix 3"]);
+    }
+
+    #[test]
+    fn conflicts_test() {
+        let universe = universe(vec![
+            pkg("Package: a\nVersion: 1\nDepends: b | c"),
+            pkg("Package: b\nVersion: 1\nConflicts: x"),
+            pkg("Package: c\nVersion: 1"),
+            pkg("Package: d\nVersion: 1\nConflicts: x\nReplaces: x"),
+            pkg("Package: e\nVersion: 1\nBreaks: y (<< 2)"),
+            pkg("Package: y\nVersion: 2"),
+            pkg("Package: f\nVersion: 1\nDepends: b"),
+        ], vec![
+            pkg("Package: x\nVersion: 1\nStatus: install ok installed"),
+            pkg("Package: y\nVersion: 1\nStatus: install ok installed"),
+        ]);
+
+        // b would conflict with x, backtrack to c
+        assert_eq!(names(&resolve(&universe, &["a"], false).unwrap()), vec!["c 1", "a 1"]);
+
+        let transaction = resolve(&universe, &["d"], false).unwrap();
+        assert_eq!(transaction.remove, vec!["x"]);
+
+        // y gets upgraded out of the way
+        assert_eq!(names(&resolve(&universe, &["e"], false).unwrap()), vec!["y 2", "e 1"]);
+
+        let err = resolve(&universe, &["f"], false).unwrap_err().to_string();
+        assert!(err.contains("f 1 depends on b"), "{}", err);
+        assert!(err.contains("conflicts with x 1 (installed)"), "{}", err);
+
+        assert!(resolve(&universe, &["f"], true).is_ok());
+    }
+
+    #[test]
+    fn backtrack_test() {
+        let universe = universe(vec![
+            pkg("Package: a\nVersion: 1\nDepends: b, c"),
+            pkg("Package: b\nVersion: 2"),
+            pkg("Package: b\nVersion: 1"),
+            pkg("Package: c\nVersion: 1\nConflicts: b (>= 2)"),
+            pkg("Package: d\nVersion: 1\nDepends: b, e"),
+            pkg("Package: e\nVersion: 1\nDepends: b (>= 3)"),
+        ], vec![]);
+
+        // The newest b is fine for b itself, not for its sibling c, so it's undone
+        assert_eq!(names(&resolve(&universe, &["a"], false).unwrap()), vec!["b 1", "c 1", "a 1"]);
+
+        let err = resolve(&universe, &["d"], false).unwrap_err().to_string();
+        assert!(err.contains("e 1 depends on b (>= 3)\n  b (>= 3) is not satisfied by b 2, b 1"), "{}", err);
+    }
+
+    #[test]
+    fn cycle_test() {
+        let universe = universe(vec![
+            pkg("Package: a\nVersion: 1\nDepends: b"),
+            pkg("Package: b\nVersion: 1\nDepends: a"),
+        ], vec![]);
+
+        assert_eq!(names(&resolve(&universe, &["a"], false).unwrap()), vec!["b 1", "a 1"]);
+    }
+
+    #[test]
+    fn missing_test() {
+        let universe = universe(vec![pkg("Package: a\nVersion: 1\nDepends: nope")], vec![]);
+        let err = resolve(&universe, &["a"], false).unwrap_err().to_string();
+        assert!(err.contains("nothing provides nope"), "{}", err);
+    }
+
+    #[test]
+    fn multi_arch_test() {
+        let universe = universe(vec![
+            pkg("Package: app\nVersion: 1\nArchitecture: i386\nDepends: libc, perl, python:any, tool:native"),
+            pkg("Package: app\nVersion: 1\nArchitecture: amd64\nDepends: libc"),
+            pkg("Package: libc\nVersion: 2\nArchitecture: amd64\nMulti-Arch: same"),
+            pkg("Package: libc\nVersion: 2\nArchitecture: i386\nMulti-Arch: same"),
+            pkg("Package: perl\nVersion: 5\nArchitecture: amd64\nMulti-Arch: foreign"),
+            pkg("Package: perl\nVersion: 5\nArchitecture: i386\nMulti-Arch: foreign"),
+            pkg("Package: python\nVersion: 3\nArchitecture: amd64\nMulti-Arch: allowed"),
+            pkg("Package: tool\nVersion: 1\nArchitecture: amd64"),
+            pkg("Package: tool\nVersion: 1\nArchitecture: i386"),
+            pkg("Package: other\nVersion: 1\nArchitecture: arm64"),
+        ], vec![
+            pkg("Package: libc\nVersion: 2\nArchitecture: amd64\nMulti-Arch: same\nStatus: install ok installed"),
+            pkg("Package: perl\nVersion: 5\nArchitecture: amd64\nMulti-Arch: foreign\nStatus: install ok installed"),
+        ]);
+
+        // Native by default
+        assert_eq!(names(&resolve(&universe, &["app"], false).unwrap()), vec!["app:amd64 1"]);
+        // The native perl and python do, libc must match the architecture
+        assert_eq!(names(&resolve(&universe, &["app:i386"], false).unwrap()), vec!["libc:i386 2", "python:amd64 3", "tool:amd64 1", "app:i386 1"]);
+        // Not enabled
+        assert!(resolve(&universe, &["other"], false).is_err());
+        assert!(resolve(&universe, &["other:arm64"], false).is_err());
+    }
+
+    fn from(mut pkg: ControlFile, suite: &str, not_automatic: bool) -> ControlFile {
+        pkg.origin = Some(Arc::new(Origin { suite: suite.to_owned(), not_automatic, ..Default::default() }));
+        pkg
+    }
+
+    #[test]
+    fn candidate_test() {
+        let universe = universe(vec![
+            from(pkg("Package: a\nVersion: 1"), "stable", false),
+            from(pkg("Package: a\nVersion: 2"), "experimental", true),
+            from(pkg("Package: b\nVersion: 1"), "stable", false),
+            from(pkg("Package: b\nVersion: 2"), "stable", false),
+        ], vec![
+            pkg("Package: b\nVersion: 3\nStatus: install ok installed"),
+        ]);
+
+        // NotAutomatic loses against a lower version
+        assert_eq!(names(&resolve(&universe, &["a"], false).unwrap()), vec!["a 1"]);
+        // Installed versions aren't downgraded
+        let err = resolve(&universe, &["b"], false).unwrap_err().to_string();
+        assert!(err.contains("already the candidate"), "{}", err);
+    }
+
+    #[test]
+    fn request_test() {
+        let universe = universe(vec![
+            from(pkg("Package: a\nVersion: 1"), "stable", false),
+            from(pkg("Package: a\nVersion: 2"), "experimental", true),
+            from(pkg("Package: a\nVersion: 3"), "stable", false),
+            from(pkg("Package: b\nVersion: 1"), "stable", false),
+        ], vec![
+            pkg("Package: b\nVersion: 2\nStatus: install ok installed"),
+        ]);
+
+        assert_eq!(names(&resolve(&universe, &["a=2"], false).unwrap()), vec!["a 2"]);
+        assert_eq!(names(&resolve(&universe, &["a/experimental"], false).unwrap()), vec!["a 2"]);
+        assert_eq!(names(&resolve(&universe, &["a/stable"], false).unwrap()), vec!["a 3"]);
+        // Asking for a version is a way of downgrading
+        assert_eq!(names(&resolve(&universe, &["b=1"], false).unwrap()), vec!["b 1"]);
+        assert!(resolve(&universe, &["a=4"], false).is_err());
+        assert!(resolve(&universe, &["a/unstable"], false).is_err());
+
+        let request = "a:i386=1.2-3".parse::<Request>().unwrap();
+        assert_eq!((request.key(), request.explicit()), (String::from("a:i386"), true));
+    }
+
+    #[test]
+    fn upgrade_test() {
+        let universe = universe(vec![
+            pkg("Package: a\nVersion: 2\nDepends: b (>= 2)"),
+            pkg("Package: b\nVersion: 2"),
+            pkg("Package: b\nVersion: 1"),
+            pkg("Package: c\nVersion: 2\nConflicts: d"),
+            pkg("Package: d\nVersion: 1"),
+            pkg("Package: e\nVersion: 1"),
+            pkg("Package: f\nVersion: 2"),
+        ], vec![
+            pkg("Package: a\nVersion: 1\nStatus: install ok installed"),
+            pkg("Package: b\nVersion: 1\nStatus: install ok installed"),
+            pkg("Package: c\nVersion: 1\nStatus: install ok installed"),
+            pkg("Package: d\nVersion: 1\nStatus: install ok installed"),
+            pkg("Package: e\nVersion: 2\nStatus: install ok installed"),
+            pkg("Package: f\nVersion: 1\nStatus: hold ok installed"),
+        ]);
+
+        // c would need d gone, e is newer than what's available, f is held
+        let safe = upgrade(&universe, false).unwrap();
+        assert_eq!(names(&safe), vec!["b 2", "a 2"]);
+        assert_eq!((safe.remove, safe.kept), (vec![], vec![String::from("c"), String::from("f")]));
+
+        let full = upgrade(&universe, true).unwrap();
+        assert_eq!(names(&full), vec!["b 2", "a 2", "c 2"]);
+        assert_eq!((full.remove, full.kept), (vec![String::from("d")], vec![String::from("f")]));
+    }
+}
@@ -1,83 +1,406 @@
 use anyhow::{self, Result};
-use crate::repos::errors::ScriptsError;
-use std::process::Command;
+use crate::repos::{config::Config, errors::{ConfigError, ScriptsError}};
+use std::{ffi::CString, fs::{self, File, OpenOptions}, io::{self, Read, Write}, mem, path::{Path, PathBuf}, str::FromStr, thread};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::os::unix::{ffi::OsStrExt, process::{CommandExt, ExitStatusExt}};
 
-use super::package::Info;
+use super::package::{ControlFile, Info};
+use super::journal::{Journal, Step};
+use super::status;
+
+//
+// Maintainer scripts, called the way dpkg does
+// https://www.debian.org/doc/debian-policy/ch-maintainerscripts.html
+//
 
 ///
-/// Pre/Post install/remove scripts execution
+/// How much maintainer scripts are trusted
 ///
-pub fn execute_install_pre(i: &Info) -> Result<()>{
-    if let Some(preinst) = &i.preinst {
-        print!("Running pre-install script ...");
-        let path = preinst.clone().into_os_string().into_string().unwrap();
-        match Command::new("sh").args(["-c", &path]).output() {
-            Ok(_) => (),
-            Err(e) => {
-                eprintln!("Failed to install the package due {}\nRemoving it ...", e);
-                execute_remove_pre(i)?;
-                execute_remove_pos(i)?;
-                anyhow::bail!(ScriptsError::PreInst);
-            }
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScriptMode {
+    ///
+    /// As dpkg does
+    ///
+    #[default]
+    Run,
+    ///
+    /// Shown first, running only once confirmed
+    ///
+    Confirm,
+    ///
+    /// In their own mount and network namespaces: no network, and only `config.root` is writable
+    ///
+    Sandbox,
+}
+
+impl FromStr for ScriptMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "run" => Ok(ScriptMode::Run),
+            "confirm" => Ok(ScriptMode::Confirm),
+            "sandbox" => Ok(ScriptMode::Sandbox),
+            _ => anyhow::bail!(ConfigError::UnexError { msg: format!("Unknown script mode {:?}, expected run, confirm or sandbox", s), err: None }),
         }
-        println!("Done");
     }
-    
-    Ok(())
 }
 
-pub fn execute_install_pos(i: &Info) -> Result<()>{
-    if let Some(postinst) = &i.postinst {
-        print!("Running post-install script ...");
-        let path = postinst.clone().into_os_string().into_string().unwrap();
-        match Command::new("sh").args(["-c", &path]).output() {
-            Ok(_) => (),
-            Err(e) => {
-                eprintln!("Failed to install the package due {}\nRemoving it ...", e);
-                execute_remove_pre(i)?;
-                execute_remove_pos(i)?;
-                anyhow::bail!(ScriptsError::PostInst);
-            }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Script {
+    Preinst,
+    Postinst,
+    Prerm,
+    Postrm,
+}
+
+impl Script {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Script::Preinst => "preinst",
+            Script::Postinst => "postinst",
+            Script::Prerm => "prerm",
+            Script::Postrm => "postrm",
         }
-        println!("Done");
     }
-    
-    Ok(())
+
+    fn describe(&self) -> &'static str {
+        match self {
+            Script::Preinst => "pre-installation",
+            Script::Postinst => "post-installation",
+            Script::Prerm => "pre-removal",
+            Script::Postrm => "post-removal",
+        }
+    }
+
+    fn path<'a>(&self, info: &'a Info) -> Option<&'a PathBuf> {
+        match self {
+            Script::Preinst => info.preinst.as_ref(),
+            Script::Postinst => info.postinst.as_ref(),
+            Script::Prerm => info.prerm.as_ref(),
+            Script::Postrm => info.postrm.as_ref(),
+        }
+    }
+}
+
+///
+/// Runs `script` of `pkg` (if it ships one) with `args` as `config.scripts` says, failing unless it exits with 0.
+/// Being journaled, it's logged first since what it does can't be undone
+///
+pub fn run(config: &Config, journal: Option<&mut Journal>, pkg: &ControlFile, info: &Info, script: Script, args: &[&str]) -> Result<()> {
+    let path = match script.path(info) {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    // Everything but the root is made read-only, with the root at / that's nothing
+    if config.scripts == ScriptMode::Sandbox && config.root.trim_end_matches('/').is_empty() {
+        anyhow::bail!(ScriptsError::Unsandboxed { pkg: pkg.key(), script: script.describe().to_owned() });
+    }
+
+    if config.scripts == ScriptMode::Confirm && !confirm(pkg, script, path, args)? {
+        anyhow::bail!(ScriptsError::Declined { pkg: pkg.key(), script: script.describe().to_owned() });
+    }
+
+    if let Some(journal) = journal {
+        journal.log(Step::ScriptRun { pkg: pkg.key(), script: script.name().to_owned() })?;
+    }
+
+    println!("Running {} {} ...", script.name(), args.join(" "));
+    let mut command = command(config, pkg, script, path);
+    command.args(args);
+    if config.scripts == ScriptMode::Sandbox {
+        sandbox(&mut command, config)?;
+    }
+
+    let why = match logged(&mut command, &log_path(config, &pkg.key()), script, args) {
+        Ok(status) if status.success() => return Ok(()),
+        Ok(status) => match (status.code(), status.signal()) {
+            (Some(code), _) => format!("returned error exit status {}", code),
+            (None, Some(signal)) => format!("was killed by signal {}", signal),
+            (None, None) => String::from("failed"),
+        },
+        Err(e) => format!("could not be executed ({})", e),
+    };
+
+    anyhow::bail!(ScriptsError::Failed { pkg: pkg.key(), script: script.describe().to_owned(), why })
+}
+
+///
+/// Runs a script while unwinding from an error, whose own failure only gets reported
+///
+pub fn unwind(config: &Config, pkg: &ControlFile, info: &Info, script: Script, args: &[&str]) -> bool {
+    match run(config, None, pkg, info, script, args) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("{}", e);
+            false
+        },
+    }
+}
+
+///
+/// Shows what's about to run, asking whether it should
+///
+fn confirm(pkg: &ControlFile, script: Script, path: &Path, args: &[&str]) -> Result<bool> {
+    println!("----- {} {} {} -----", pkg.key(), script.name(), args.join(" "));
+    match fs::read(path) {
+        Ok(contents) => println!("{}", String::from_utf8_lossy(&contents).trim_end()),
+        Err(e) => println!("(could not be read: {})", e),
+    }
+    println!("-----");
+    print!("Run it? [y/N] ");
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim().to_ascii_lowercase().starts_with('y'))
+}
+
+///
+/// Where the output of every script of `name` goes, next to its file list
+///
+pub fn log_path(config: &Config, name: &str) -> PathBuf {
+    Path::new(&config.info).join(format!("{}.log", name))
+}
+
+///
+/// Runs `command` showing its output while also appending it to `log`
+///
+fn logged(command: &mut Command, log: &Path, script: Script, args: &[&str]) -> io::Result<ExitStatus> {
+    let mut file = OpenOptions::new().create(true).append(true).open(log)?;
+    writeln!(file, "### {} {}", script.name(), args.join(" "))?;
+    let file = Arc::new(Mutex::new(file));
+
+    let mut child = command.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let tees = [
+        tee(child.stdout.take().map(|out| Box::new(out) as Box<dyn Read + Send>), Box::new(io::stdout()), file.clone()),
+        tee(child.stderr.take().map(|err| Box::new(err) as Box<dyn Read + Send>), Box::new(io::stderr()), file.clone()),
+    ];
+
+    let status = child.wait()?;
+    for tee in tees {
+        tee.join().unwrap_or(Ok(()))?;
+    }
+
+    let mut file = file.lock().unwrap();
+    match status.code() {
+        Some(code) => writeln!(file, "### exit status {}", code)?,
+        None => writeln!(file, "### killed by signal {}", status.signal().unwrap_or_default())?,
+    }
+
+    Ok(status)
 }
 
-pub fn execute_remove_pre(i: &Info) -> Result<()> {
-    if let Some(prerm) = &i.prerm {
-        print!("Running pre-remove script ...");
-        let path = prerm.clone().into_os_string().into_string().unwrap();
-        match Command::new("sh").args(["-c", &path]).output() {
-            Ok(_) => (),
-            Err(e) => {
-                println!();
-                eprintln!("Failed to remove the package due {}\nRemoving it ...", e);
-                anyhow::bail!(ScriptsError::PreRm);
+fn tee(from: Option<Box<dyn Read + Send>>, mut to: Box<dyn Write + Send>, log: Arc<Mutex<File>>) -> thread::JoinHandle<io::Result<()>> {
+    thread::spawn(move || {
+        let mut from = match from {
+            Some(from) => from,
+            None => return Ok(()),
+        };
+
+        let mut buf = [0; 8192];
+        loop {
+            match from.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => {
+                    to.write_all(&buf[..n])?;
+                    to.flush()?;
+                    log.lock().unwrap().write_all(&buf[..n])?;
+                },
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
             }
         }
-        println!("Done");
+    })
+}
+
+///
+/// Moves the script, once forked, into namespaces of its own: a network one with nothing in it and a mount one
+/// where everything is read-only but `config.root` (which can't be `/`)
+///
+fn sandbox(command: &mut Command, config: &Config) -> Result<()> {
+    let root = CString::new(Path::new(&config.root).as_os_str().as_bytes())?;
+
+    // SAFETY: neither call can fail
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let (uid_map, gid_map) = (format!("0 {} 1", uid), format!("0 {} 1", gid));
+
+    // SAFETY: only async-signal-safe calls happen between fork and exec, everything else was allocated before
+    unsafe {
+        command.pre_exec(move || {
+            // Opened before the mounts are copied, it still reaches the writable /proc once they're read-only
+            let proc = libc::open(c"/proc/self".as_ptr(), libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC);
+            check(proc)?;
+
+            // Without privileges it takes a user namespace first, being root only inside of it
+            if uid != 0 {
+                check(libc::unshare(libc::CLONE_NEWUSER))?;
+                map(proc, &uid_map, &gid_map)?;
+            }
+
+            check(libc::unshare(libc::CLONE_NEWNS | libc::CLONE_NEWNET))?;
+            // Nothing done here may reach the real mounts
+            check(libc::mount(std::ptr::null(), c"/".as_ptr(), std::ptr::null(), libc::MS_REC | libc::MS_PRIVATE, std::ptr::null()))?;
+
+            check(libc::mount(root.as_ptr(), root.as_ptr(), std::ptr::null(), libc::MS_BIND | libc::MS_REC, std::ptr::null()))?;
+            setattr(c"/".as_ptr(), libc::MOUNT_ATTR_RDONLY, 0)?;
+            setattr(root.as_ptr(), 0, libc::MOUNT_ATTR_RDONLY)?;
+
+            // Copied into a namespace of a user namespace of its own, the mounts get locked as they are:
+            // the script is only root in there, so it can't remount them or get back into the real ones
+            check(libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS))?;
+            map(proc, "0 0 1", "0 0 1")?;
+            check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+
+            Ok(())
+        });
     }
 
     Ok(())
 }
 
-pub fn execute_remove_pos(i: &Info) -> Result<()> {  
-    if let Some(postrm) = &i.postrm {
-        print!("Running post-remove script ...");
-        let path = postrm.clone().into_os_string().into_string().unwrap();
-        match Command::new("sh").args(["-c", &path]).output() {
-            Ok(_) => (),
-            Err(e) => {
-                println!();
-                eprintln!("Failed to remove the package due {}\nRemoving it ...", e);
-                anyhow::bail!(ScriptsError::PostRm);
-            }
+///
+/// Maps root of the user namespace just entered to `uid_map`/`gid_map` of the one it came from
+///
+unsafe fn map(proc: libc::c_int, uid_map: &str, gid_map: &str) -> io::Result<()> {
+    write(proc, c"setgroups", b"deny")?;
+    write(proc, c"uid_map", uid_map.as_bytes())?;
+    write(proc, c"gid_map", gid_map.as_bytes())
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+///
+/// Sets and clears mount attributes of everything under `path`
+///
+unsafe fn setattr(path: *const libc::c_char, set: u64, clear: u64) -> io::Result<()> {
+    let mut attr: libc::mount_attr = mem::zeroed();
+    attr.attr_set = set;
+    attr.attr_clr = clear;
+
+    let result = libc::syscall(libc::SYS_mount_setattr, libc::AT_FDCWD, path, libc::AT_RECURSIVE, &attr as *const libc::mount_attr, mem::size_of::<libc::mount_attr>());
+    check(result as libc::c_int)
+}
+
+unsafe fn write(dir: libc::c_int, path: &std::ffi::CStr, contents: &[u8]) -> io::Result<()> {
+    let fd = libc::openat(dir, path.as_ptr(), libc::O_WRONLY);
+    check(fd)?;
+
+    let written = libc::write(fd, contents.as_ptr() as *const libc::c_void, contents.len());
+    libc::close(fd);
+    check(if written < 0 { -1 } else { 0 })
+}
+
+///
+/// The environment dpkg gives its scripts. They aren't chrooted into `config.root`, so they get it as `DPKG_ROOT`
+///
+fn command(config: &Config, pkg: &ControlFile, script: Script, path: &Path) -> Command {
+    let mut command = Command::new(path);
+    command
+        .current_dir("/")
+        .env("DPKG_MAINTSCRIPT_PACKAGE", &pkg.package)
+        .env("DPKG_MAINTSCRIPT_PACKAGE_REFCOUNT", "1")
+        .env("DPKG_MAINTSCRIPT_ARCH", &pkg.architecture)
+        .env("DPKG_MAINTSCRIPT_NAME", script.name())
+        .env("DPKG_ROOT", config.root.trim_end_matches('/'));
+
+    // Sharing dpkg's database, whatever dpkg the scripts call must use it too (and not wait for our lock)
+    if config.use_pre_existing_db {
+        if let Some(admindir) = status::path(config).parent() {
+            command.env("DPKG_ADMINDIR", admindir);
         }
-        println!("Done");
+        command.env("DPKG_FRONTEND_LOCKED", "1");
     }
 
-    Ok(())
+    command
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn setup(name: &str) -> (PathBuf, Config, ControlFile) {
+        let dir = std::env::temp_dir().join(format!("opm-scripts-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut config = crate::repos::setup().unwrap();
+        config.info = dir.display().to_string();
+        let pkg = ControlFile::new(&config, "Package: opm-test\nVersion: 2.0\nArchitecture: amd64\nMaintainer: opm\nDescription: test").unwrap();
+
+        (dir, config, pkg)
+    }
+
+    fn script(dir: &Path, name: &str, contents: &str) -> Info {
+        let path = dir.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{}", contents)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        Info::load(dir).unwrap()
+    }
+
+    #[test]
+    fn run_test() {
+        let (dir, config, pkg) = setup("run");
+        let out = dir.join("out");
+        let info = script(&dir, "postinst", &format!("echo \"$DPKG_MAINTSCRIPT_NAME $DPKG_MAINTSCRIPT_PACKAGE:$DPKG_MAINTSCRIPT_ARCH $*\" > {}\necho configuring\n[ \"$1\" = configure ]\n", out.display()));
+
+        run(&config, None, &pkg, &info, Script::Postinst, &["configure", "1.0"]).unwrap();
+        assert_eq!(fs::read_to_string(&out).unwrap(), "postinst opm-test:amd64 configure 1.0\n");
+
+        // Exiting with anything but 0 is a failure, while a missing script has nothing to fail
+        let err = run(&config, None, &pkg, &info, Script::Postinst, &["abort-upgrade", "1.0"]).unwrap_err();
+        assert_eq!(err.to_string(), "opm-test post-installation script subprocess returned error exit status 1");
+        run(&config, None, &pkg, &info, Script::Prerm, &["remove"]).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(log_path(&config, "opm-test")).unwrap(),
+            "### postinst configure 1.0\nconfiguring\n### exit status 0\n### postinst abort-upgrade 1.0\nconfiguring\n### exit status 1\n"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sandbox_test() {
+        let (dir, mut config, pkg) = setup("sandbox");
+        let root = dir.join("root");
+        fs::create_dir_all(&root).unwrap();
+        config.root = root.display().to_string();
+        config.scripts = ScriptMode::Sandbox;
+
+        // Only the root can be written to, and there's no network but a loopback that's down
+        let info = script(&dir, "postinst", &format!(
+            "touch {root}/inside || exit 1\ntouch {dir}/outside && exit 2\n[ \"$(tail -n +3 /proc/net/dev | cut -d: -f1 | tr -d ' ')\" = lo ] || exit 3\n",
+            root = root.display(), dir = dir.display(),
+        ));
+
+        run(&config, None, &pkg, &info, Script::Postinst, &["configure", ""]).unwrap();
+        assert!(root.join("inside").exists());
+        assert!(!dir.join("outside").exists());
+
+        // Nor can it undo any of that, from its own mounts or through the real ones
+        let info = script(&dir, "postinst", &format!(
+            "mount -o remount,bind,rw / 2>/dev/null && exit 1\nmount -o remount,rw / 2>/dev/null && exit 2\nnsenter -t 1 -m touch {dir}/outside 2>/dev/null && exit 3\ntouch {dir}/outside 2>/dev/null && exit 4\nexit 0\n",
+            dir = dir.display(),
+        ));
+
+        run(&config, None, &pkg, &info, Script::Postinst, &["configure", ""]).unwrap();
+        assert!(!dir.join("outside").exists());
+
+        // With the root at /, there'd be nothing left read-only
+        config.root = String::from("/");
+        let err = run(&config, None, &pkg, &info, Script::Postinst, &["configure", ""]).unwrap_err();
+        assert_eq!(err.to_string(), "The post-installation script of opm-test can't be sandboxed with the root at /, all of it would stay writable (use --root or another scripts mode)");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,45 +2,136 @@ use anyhow::{self, Result};
 use crate::repos::errors::SignatureError;
 use sha2::{Sha256, Sha512, Digest};
 use sha1::Sha1;
-use super::package::DebPackage;
+use super::package::ControlFile;
 
-pub fn verify_sig<T: AsRef<[u8]>>(pkg: &DebPackage, data: T) -> Result<()> {
-    let control = &pkg.control;
-    
-    if !control.md5sum.is_empty() {
-        let md5 = format!("{:x}", md5::compute(&data));
-        if control.md5sum != md5 {
-            anyhow::bail!(SignatureError::MD5 { rs: control.md5sum.to_string(), ex: md5 });
+///
+/// Hashes a package as it comes in, with every algorithm its control file has a checksum for
+///
+pub struct Hasher<'a> {
+    control: &'a ControlFile,
+    size: u64,
+    md5: Option<md5::Context>,
+    sha1: Option<Sha1>,
+    sha256: Option<Sha256>,
+    sha512: Option<Sha512>,
+}
+
+impl<'a> Hasher<'a> {
+    ///
+    /// Fails unless there's at least a SHA256 (or SHA512) checksum to check against
+    ///
+    pub fn new(control: &'a ControlFile) -> Result<Self> {
+        if control.sha256.is_empty() && control.sha512.is_empty() {
+            anyhow::bail!(SignatureError::Weak(control.key()));
         }
+
+        Ok(Self {
+            control,
+            size: 0,
+            md5: (!control.md5sum.is_empty()).then(md5::Context::new),
+            sha1: (!control.sha1.is_empty()).then(Sha1::new),
+            sha256: (!control.sha256.is_empty()).then(Sha256::new),
+            sha512: (!control.sha512.is_empty()).then(Sha512::new),
+        })
     }
-    
-    if !control.sha1.is_empty() {
-        let mut sha1 = Sha1::new();
-        sha1.update(&data);
-        let sha1 = format!("{:x}", sha1.finalize());
-        if *control.sha1 != sha1 {
-            anyhow::bail!(SignatureError::SHA1 { rs: control.md5sum.to_string(), ex: sha1 });
+
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.size += chunk.len() as u64;
+        if let Some(md5) = self.md5.as_mut() {
+            md5.consume(chunk);
         }
-    }
-    
-    if !control.sha256.is_empty() {
-        let mut sha256 = Sha256::new();
-        sha256.update(&data);
-        let sha256 = format!("{:x}", sha256.finalize());
-        if *control.sha256 != sha256 {
-            anyhow::bail!(SignatureError::SHA256 { rs: control.md5sum.to_string(), ex: sha256 })
+        if let Some(sha1) = self.sha1.as_mut() {
+            sha1.update(chunk);
+        }
+        if let Some(sha256) = self.sha256.as_mut() {
+            sha256.update(chunk);
+        }
+        if let Some(sha512) = self.sha512.as_mut() {
+            sha512.update(chunk);
         }
     }
 
-    if !control.sha512.is_empty() {
-        let mut sha512 = Sha512::new();
-        sha512.update(&data);
-        let sha512 = format!("{:x}", sha512.finalize());
-        if *control.sha512 != sha512 {
-            anyhow::bail!(SignatureError::SHA512 { rs: control.md5sum.to_string(), ex: sha512 })
+    ///
+    /// Checks everything hashed so far against the control file
+    ///
+    pub fn verify(self) -> Result<()> {
+        let control = self.control;
+
+        if !control.size.is_empty() && control.size.parse::<u64>().ok() != Some(self.size) {
+            anyhow::bail!(SignatureError::Size { rs: self.size, ex: control.size.to_string() });
+        }
+
+        if let Some(md5) = self.md5 {
+            let md5 = format!("{:x}", md5.compute());
+            if !control.md5sum.eq_ignore_ascii_case(&md5) {
+                anyhow::bail!(SignatureError::MD5 { rs: md5, ex: control.md5sum.to_string() });
+            }
+        }
+
+        if let Some(sha1) = self.sha1 {
+            let sha1 = format!("{:x}", sha1.finalize());
+            if !control.sha1.eq_ignore_ascii_case(&sha1) {
+                anyhow::bail!(SignatureError::SHA1 { rs: sha1, ex: control.sha1.to_string() });
+            }
+        }
+
+        if let Some(sha256) = self.sha256 {
+            let sha256 = format!("{:x}", sha256.finalize());
+            if !control.sha256.eq_ignore_ascii_case(&sha256) {
+                anyhow::bail!(SignatureError::SHA256 { rs: sha256, ex: control.sha256.to_string() });
+            }
         }
+
+        if let Some(sha512) = self.sha512 {
+            let sha512 = format!("{:x}", sha512.finalize());
+            if !control.sha512.eq_ignore_ascii_case(&sha512) {
+                anyhow::bail!(SignatureError::SHA512 { rs: sha512, ex: control.sha512.to_string() });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repos::deb::deb822::Paragraph;
+
+    fn verify(control: &ControlFile, data: &[u8]) -> Result<()> {
+        let mut hasher = Hasher::new(control)?;
+        hasher.update(data);
+        hasher.verify()
     }
 
+    #[test]
+    fn hasher_test() {
+        let stanza = format!("Package: opm\nVersion: 1.0\nArchitecture: all\nMaintainer: opm\nDescription: test\nMD5sum: {:x}\n", md5::compute(b"opm"));
+        let mut control = ControlFile::from_paragraph(Paragraph::parse(&stanza).unwrap()).unwrap();
+        // MD5 alone isn't enough
+        assert!(verify(&control, b"opm").unwrap_err().to_string().contains("no SHA256 or SHA512"));
 
-    Ok(())
-}
\ No newline at end of file
+        control.sha256 = String::from("f8ea02ab82e2b2a9bc5ffb6c4ea2a0c0e0e5c3e58b2e3a2f0e3a7a5f7d3c2b1a");
+
+        let err = verify(&control, b"opm").unwrap_err().to_string();
+        assert!(err.ends_with(&format!("expected {:?}", control.sha256)), "{}", err);
+
+        control.sha256 = format!("{:x}", Sha256::digest(b"opm"));
+        verify(&control, b"opm").unwrap();
+
+        // Hex digests are the same in either case
+        control.sha256 = format!("{:X}", Sha256::digest(b"opm"));
+        control.md5sum = control.md5sum.to_uppercase();
+        verify(&control, b"opm").unwrap();
+
+        control.size = String::from("4");
+        assert!(verify(&control, b"opm").unwrap_err().to_string().contains("Mismatched Size got 3 bytes"));
+        control.size = String::from("3");
+
+        // Chunks hash the same as a whole
+        let mut hasher = Hasher::new(&control).unwrap();
+        hasher.update(b"op");
+        hasher.update(b"m");
+        hasher.verify().unwrap();
+    }
+}
@@ -1,36 +1,365 @@
-use std::fs;
-use std::io::Error;
+use anyhow::Result;
+use std::{fs, path::{Path, PathBuf}};
+
+use super::deb822::{self, Paragraph};
+use crate::repos::errors::SourcesError;
+
+//
+// APT sources, both the one-line (`.list`) and the deb822 (`.sources`) formats
+// https://manpages.debian.org/bookworm/apt/sources.list.5.en.html
+//
 
 const DEB_REPOS: &str = "/etc/apt/sources.list";
+const DEB_REPOS_DIR: &str = "/etc/apt/sources.list.d";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceKind {
+    ///
+    /// `deb`, binary packages
+    ///
+    Binary,
+    ///
+    /// `deb-src`, source packages
+    ///
+    Source,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceOptions {
+    ///
+    /// `arch=`/`Architectures:`, only fetch those instead of every configured one
+    ///
+    pub architectures: Vec<String>,
+    ///
+    /// `trusted=yes`/`Trusted: yes`, skips checking its signature and hashes
+    ///
+    pub trusted: bool,
+    ///
+    /// `signed-by=`/`Signed-By:`, keyring paths, fingerprints or (deb822 only) an armored key
+    ///
+    pub signed_by: Option<String>,
+}
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DebianSource {
+    pub kind: SourceKind,
+    ///
+    /// Always ending with a `/`
+    ///
     pub url: String,
+    ///
+    /// The suite, or the path of a flat repository (ending with a `/`)
+    ///
     pub distribution: String,
-    pub components: Vec<String>
+    ///
+    /// Empty for flat repositories
+    ///
+    pub components: Vec<String>,
+    pub options: SourceOptions,
 }
 
 impl DebianSource {
-    pub fn new() -> Result<Vec<DebianSource>, Error> {
-        let contents = fs::read_to_string(DEB_REPOS)?;
-        let mut v: Vec<Self> = Vec::new();
+    ///
+    /// Every enabled source of `/etc/apt/sources.list` and `/etc/apt/sources.list.d`
+    ///
+    pub fn new() -> Result<Vec<DebianSource>> {
+        let mut files = vec![PathBuf::from(DEB_REPOS)];
+        if let Ok(entries) = fs::read_dir(DEB_REPOS_DIR) {
+            let mut entries = entries.flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == "list" || ext == "sources"))
+                .collect::<Vec<_>>();
 
-        for d in contents.lines() {
-            if !d.contains('#') && d.starts_with("deb") && !d.contains("cdrom") {
-                let split = d.split(' ').collect::<Vec<&str>>().iter()
-                    .map(|x| x.to_string()).collect::<Vec<String>>();
-                v.push(
-                    DebianSource {
-                        url: split[1].clone(),
-                        distribution: split[2].clone(),
-                        components: Vec::from(&split[3..])
-                    }
-                );
-            }
+            entries.sort();
+            files.extend(entries);
+        }
+
+        let mut v: Vec<Self> = Vec::new();
+        for file in files.iter().filter(|file| file.is_file()) {
+            v.extend(Self::load(file)?);
         }
 
         Ok (
             v
         )
     }
-}
\ No newline at end of file
+
+    pub fn load(file: &Path) -> Result<Vec<DebianSource>> {
+        let contents = fs::read_to_string(file)?;
+        let name = file.to_string_lossy();
+
+        if file.extension().is_some_and(|ext| ext == "sources") {
+            Self::parse_sources(&name, &contents)
+        } else {
+            Self::parse_list(&name, &contents)
+        }
+    }
+
+    ///
+    /// One-line format: `deb [ option=value ... ] uri suite [component ...]`
+    ///
+    pub fn parse_list(file: &str, contents: &str) -> Result<Vec<DebianSource>> {
+        let mut v = vec![];
+
+        for (i, line) in contents.lines().enumerate() {
+            let malformed = |why: &str| anyhow::anyhow!(SourcesError::Malformed { file: file.to_owned(), line: i + 1, why: why.to_owned() });
+
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (kind, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let kind = match kind {
+                "deb" => SourceKind::Binary,
+                "deb-src" => SourceKind::Source,
+                kind => return Err(malformed(&format!("unknown type {:?}", kind))),
+            };
+
+            let mut options = SourceOptions::default();
+            let mut rest = rest.trim_start();
+            if let Some(bracket) = rest.strip_prefix('[') {
+                let (list, after) = bracket.split_once(']').ok_or_else(|| malformed("unterminated options"))?;
+
+                for option in list.split_whitespace() {
+                    let (name, value) = option.split_once('=').ok_or_else(|| malformed(&format!("bad option {:?}", option)))?;
+                    // `arch+=` adds to the list and `arch-=` removes from it, close enough to `=` for us
+                    if !name.ends_with('-') {
+                        options.set(name.trim_end_matches('+'), &value.replace(',', " "));
+                    }
+                }
+
+                rest = after;
+            }
+
+            let mut fields = rest.split_whitespace();
+            let (url, distribution) = match (fields.next(), fields.next()) {
+                (Some(url), Some(distribution)) => (url, distribution),
+                _ => return Err(malformed("missing the uri or the suite")),
+            };
+
+            v.push(Self::with(kind, url, distribution, fields.map(|c| c.to_owned()).collect(), options).map_err(|e| malformed(&e))?);
+        }
+
+        Ok(v)
+    }
+
+    ///
+    /// deb822 format, each paragraph being the combination of its `Types`, `URIs` and `Suites`
+    ///
+    pub fn parse_sources(file: &str, contents: &str) -> Result<Vec<DebianSource>> {
+        let mut v = vec![];
+
+        for stanza in deb822::split(contents) {
+            let line = contents[..stanza.as_ptr() as usize - contents.as_ptr() as usize].matches('\n').count() + 1;
+            let malformed = |why: &str| anyhow::anyhow!(SourcesError::Malformed { file: file.to_owned(), line, why: why.to_owned() });
+
+            let paragraph = Paragraph::parse(stanza)?;
+            let words = |name: &str| paragraph.get(name).unwrap_or_default().split_whitespace().map(|w| w.to_owned()).collect::<Vec<_>>();
+
+            // Only comments
+            if paragraph.get("Types").is_none() && paragraph.get("URIs").is_none() {
+                continue;
+            }
+
+            if paragraph.get("Enabled").is_some_and(|enabled| enabled.eq_ignore_ascii_case("no")) {
+                continue;
+            }
+
+            let mut options = SourceOptions::default();
+            for name in ["Architectures", "Trusted"] {
+                if let Some(value) = paragraph.get(name) {
+                    options.set(name, value);
+                }
+            }
+
+            if let Some(value) = paragraph.get("Signed-By") {
+                options.signed_by = Some(multiline(value));
+            }
+
+            let (types, urls, suites, components) = (words("Types"), words("URIs"), words("Suites"), words("Components"));
+            if types.is_empty() || urls.is_empty() || suites.is_empty() {
+                return Err(malformed("Types, URIs and Suites are mandatory"));
+            }
+
+            for kind in types.iter() {
+                let kind = match kind.as_str() {
+                    "deb" => SourceKind::Binary,
+                    "deb-src" => SourceKind::Source,
+                    kind => return Err(malformed(&format!("unknown type {:?}", kind))),
+                };
+
+                for url in urls.iter() {
+                    for suite in suites.iter() {
+                        v.push(Self::with(kind, url, suite, components.clone(), options.clone()).map_err(|e| malformed(&e))?);
+                    }
+                }
+            }
+        }
+
+        Ok(v)
+    }
+
+    fn with(kind: SourceKind, url: &str, distribution: &str, components: Vec<String>, options: SourceOptions) -> Result<Self, String> {
+        let source = DebianSource {
+            kind,
+            url: if url.ends_with('/') { url.to_owned() } else { format!("{}/", url) },
+            distribution: distribution.to_owned(),
+            components,
+            options,
+        };
+
+        match (source.is_flat(), source.components.is_empty()) {
+            (true, false) => Err(String::from("flat repositories can't have components")),
+            (false, true) => Err(String::from("missing the components")),
+            _ => Ok(source),
+        }
+    }
+
+    ///
+    /// `deb uri path/`, without a `dists` tree
+    ///
+    pub fn is_flat(&self) -> bool {
+        self.distribution.ends_with('/')
+    }
+
+    ///
+    /// Where the `InRelease` and the paths it lists are
+    ///
+    pub fn dist_url(&self) -> String {
+        if self.is_flat() {
+            format!("{}{}", self.url, self.distribution.trim_start_matches("./"))
+        } else {
+            format!("{}dists/{}/", self.url, self.distribution)
+        }
+    }
+
+    ///
+    /// Paths (relative to `dist_url`, without compression suffix) of the binary indices of `arch`
+    ///
+    pub fn indices(&self, arch: &str) -> Vec<String> {
+        if self.is_flat() {
+            vec![String::from("Packages")]
+        } else {
+            self.components.iter().map(|component| format!("{}/binary-{}/Packages", component, arch)).collect()
+        }
+    }
+
+    ///
+    /// The name APT gives the local copy of `path` (relative to `dist_url`)
+    ///
+    pub fn file_name(&self, path: &str) -> String {
+        uri_to_file_name(&format!("{}{}", self.dist_url(), path))
+    }
+}
+
+impl SourceOptions {
+    fn set(&mut self, name: &str, value: &str) {
+        match name.to_ascii_lowercase().as_str() {
+            "arch" | "architectures" => self.architectures = value.split_whitespace().map(|a| a.to_owned()).collect(),
+            "trusted" => self.trusted = value.eq_ignore_ascii_case("yes"),
+            "signed-by" => self.signed_by = Some(value.to_owned()),
+            _ => (),
+        }
+    }
+}
+
+///
+/// Continuation lines without their leading space, ` .` standing for an empty line
+///
+fn multiline(value: &str) -> String {
+    value.lines()
+        .map(|line| match line.trim() {
+            "." => "",
+            line => line,
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_owned()
+}
+
+///
+/// APT's `URItoFileName`: no scheme nor credentials, `_` escaped and `/` turned into `_`
+///
+pub fn uri_to_file_name(uri: &str) -> String {
+    let uri = uri.split_once("://").map_or(uri, |(_, rest)| rest);
+    let uri = match (uri.find('@'), uri.find('/')) {
+        (Some(at), Some(slash)) if at < slash => &uri[at + 1..],
+        (Some(at), None) => &uri[at + 1..],
+        _ => uri,
+    };
+
+    uri.replace('_', "%5f").replace('/', "_")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn list_test() {
+        let list = "# deb http://commented.org/ stable main
+deb http://deb.debian.org/debian bookworm main contrib # trailing comment
+deb [ arch=amd64,i386 signed-by=/usr/share/keyrings/opm.gpg ] https://opm.org/apt stable main
+deb-src [trusted=yes] http://deb.debian.org/debian bookworm main
+deb http://flat.org/repo ./
+";
+        let sources = DebianSource::parse_list("test.list", list).unwrap();
+
+        assert_eq!(sources.len(), 4);
+        assert_eq!(sources[0].url, "http://deb.debian.org/debian/");
+        assert_eq!(sources[0].components, vec!["main", "contrib"]);
+        assert_eq!(sources[0].dist_url(), "http://deb.debian.org/debian/dists/bookworm/");
+        assert_eq!(sources[1].options.architectures, vec!["amd64", "i386"]);
+        assert_eq!(sources[1].options.signed_by.as_deref(), Some("/usr/share/keyrings/opm.gpg"));
+        assert!(sources[2].kind == SourceKind::Source && sources[2].options.trusted);
+        assert!(sources[3].is_flat());
+        assert_eq!(sources[3].dist_url(), "http://flat.org/repo/");
+        assert_eq!(sources[3].indices("amd64"), vec!["Packages"]);
+
+        assert!(DebianSource::parse_list("bad.list", "deb http://opm.org/\n").is_err());
+        assert!(DebianSource::parse_list("bad.list", "deb http://opm.org/ stable\n").is_err());
+        assert!(DebianSource::parse_list("bad.list", "deb [arch=amd64 http://opm.org/ stable main\n").is_err());
+    }
+
+    #[test]
+    fn sources_test() {
+        let sources = "# Header comment
+
+Types: deb deb-src
+# http://snapshot.debian.org/archive/debian/20250520T000000Z
+URIs: http://deb.debian.org/debian
+Suites: bookworm bookworm-updates
+Components: main
+Signed-By: /usr/share/keyrings/debian-archive-keyring.gpg
+
+Types: deb
+URIs: https://deb.nodesource.com/node_20.x
+Suites: nodistro
+Components: main
+Architectures: amd64
+Signed-By:
+ -----BEGIN PGP PUBLIC KEY BLOCK-----
+ .
+ mDMEZ
+ -----END PGP PUBLIC KEY BLOCK-----
+
+Enabled: no
+Types: deb
+URIs: http://disabled.org
+Suites: stable
+Components: main
+";
+        let sources = DebianSource::parse_sources("test.sources", sources).unwrap();
+
+        assert_eq!(sources.len(), 5);
+        assert_eq!(sources.iter().filter(|s| s.kind == SourceKind::Binary).count(), 3);
+        assert_eq!(sources[1].distribution, "bookworm-updates");
+        assert_eq!(sources[4].options.architectures, vec!["amd64"]);
+        assert_eq!(sources[4].options.signed_by.as_deref(), Some("-----BEGIN PGP PUBLIC KEY BLOCK-----\n\nmDMEZ\n-----END PGP PUBLIC KEY BLOCK-----"));
+        assert_eq!(sources[4].file_name("main/binary-amd64/Packages"), "deb.nodesource.com_node%5f20.x_dists_nodistro_main_binary-amd64_Packages");
+
+        assert!(DebianSource::parse_sources("bad.sources", "Types: deb\nURIs: http://opm.org\n").is_err());
+    }
+}
@@ -0,0 +1,395 @@
+use anyhow::Result;
+use std::{fmt::{self, Display}, fs, io::Write, path::{Path, PathBuf}, str::FromStr};
+
+use super::deb822::{self, Paragraph};
+use crate::repos::{config::Config, errors::StatusError};
+
+//
+// The status database, dpkg's `/var/lib/dpkg/status` or opm's own `config.db`
+// https://man7.org/linux/man-pages/man5/dpkg.5.html
+//
+
+///
+/// The database file in use
+///
+pub fn path(config: &Config) -> PathBuf {
+    match config.use_pre_existing_db {
+        true => PathBuf::from(super::database::DEBIAN_DATABASE),
+        false => PathBuf::from(&config.db),
+    }
+}
+
+///
+/// What's wanted of a package, the selection state
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Want {
+    Unknown,
+    Install,
+    ///
+    /// Left as it is, upgrades skip it
+    ///
+    Hold,
+    Deinstall,
+    Purge,
+}
+
+///
+/// `reinstreq`: it's broken and has to be reinstalled before anything else happens to it
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    Ok,
+    Reinstreq,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    NotInstalled,
+    ///
+    /// Removed, but its conffiles are still around
+    ///
+    ConfigFiles,
+    HalfInstalled,
+    Unpacked,
+    HalfConfigured,
+    TriggersAwaited,
+    TriggersPending,
+    Installed,
+}
+
+const WANTS: [(Want, &str); 5] = [
+    (Want::Unknown, "unknown"),
+    (Want::Install, "install"),
+    (Want::Hold, "hold"),
+    (Want::Deinstall, "deinstall"),
+    (Want::Purge, "purge"),
+];
+
+const FLAGS: [(Flag, &str); 2] = [(Flag::Ok, "ok"), (Flag::Reinstreq, "reinstreq")];
+
+const STATES: [(State, &str); 8] = [
+    (State::NotInstalled, "not-installed"),
+    (State::ConfigFiles, "config-files"),
+    (State::HalfInstalled, "half-installed"),
+    (State::Unpacked, "unpacked"),
+    (State::HalfConfigured, "half-configured"),
+    (State::TriggersAwaited, "triggers-awaited"),
+    (State::TriggersPending, "triggers-pending"),
+    (State::Installed, "installed"),
+];
+
+///
+/// The `Status` field: `<want> <flag> <state>`, e.g.: `install ok installed`
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    pub want: Want,
+    pub flag: Flag,
+    pub state: State,
+}
+
+impl Status {
+    pub fn new(want: Want, state: State) -> Self {
+        Self { want, flag: Flag::Ok, state }
+    }
+
+    ///
+    /// Whether its files are on the system, configured or not
+    ///
+    pub fn is_installed(&self) -> bool {
+        !matches!(self.state, State::NotInstalled | State::ConfigFiles)
+    }
+}
+
+fn word<T: Copy + PartialEq>(table: &[(T, &'static str)], value: T) -> &'static str {
+    table.iter().find(|(v, _)| *v == value).map(|(_, word)| *word).unwrap_or_default()
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", word(&WANTS, self.want), word(&FLAGS, self.flag), word(&STATES, self.state))
+    }
+}
+
+impl FromStr for Status {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        fn lookup<T: Copy>(table: &[(T, &str)], word: &str, status: &str) -> Result<T> {
+            match table.iter().find(|(_, w)| *w == word) {
+                Some((value, _)) => Ok(*value),
+                None => anyhow::bail!(StatusError::Invalid(status.to_owned())),
+            }
+        }
+
+        match s.split_whitespace().collect::<Vec<_>>()[..] {
+            [want, flag, state] => Ok(Self {
+                want: lookup(&WANTS, want, s)?,
+                flag: lookup(&FLAGS, flag, s)?,
+                state: lookup(&STATES, state, s)?,
+            }),
+            _ => anyhow::bail!(StatusError::Invalid(s.to_owned())),
+        }
+    }
+}
+
+///
+/// The value of the single-line `field` of the raw paragraph `stanza`
+///
+fn field<'a>(stanza: &'a str, field: &str) -> Option<&'a str> {
+    // Continuation lines start with a space, so they never match
+    stanza.lines()
+        .find_map(|line| line.strip_prefix(field).and_then(|rest| rest.strip_prefix(':')))
+        .map(|value| value.trim())
+}
+
+///
+/// Whether the raw paragraph `stanza` is the one of `key` (`pkg` or `pkg:arch`)
+///
+pub fn is_stanza_of(stanza: &str, key: &str) -> bool {
+    let (name, arch) = match key.split_once(':') {
+        Some((name, arch)) => (name, Some(arch)),
+        None => (key, None),
+    };
+
+    field(stanza, "Package") == Some(name) && arch.is_none_or(|arch| field(stanza, "Architecture") == Some(arch))
+}
+
+///
+/// The database held under its lock, every paragraph kept verbatim.
+/// Nothing reaches the disk until `commit`
+///
+#[derive(Debug)]
+pub struct StatusDb {
+    path: PathBuf,
+    stanzas: Vec<String>,
+    _lock: fs::File,
+}
+
+impl StatusDb {
+    ///
+    /// Reads the database at `path`, waiting for whoever else is changing it
+    ///
+    pub fn open(path: &Path) -> Result<Self> {
+        let lock = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(Self::lock_path(path))?;
+        lock.lock()?;
+
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let stanzas = deb822::split(&contents)
+            .into_iter()
+            .map(|stanza| stanza.trim_end().to_owned())
+            .collect();
+
+        Ok(Self { path: path.to_path_buf(), stanzas, _lock: lock })
+    }
+
+    fn lock_path(path: &Path) -> PathBuf {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(".lock");
+        path.with_file_name(name)
+    }
+
+    ///
+    /// The paragraph of `key` (`pkg` or `pkg:arch`)
+    ///
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.stanzas.iter().find(|stanza| is_stanza_of(stanza, key)).map(|stanza| stanza.as_str())
+    }
+
+    ///
+    /// Takes out the paragraph of `key` and only that one
+    ///
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let index = self.stanzas.iter().position(|stanza| is_stanza_of(stanza, key))?;
+        Some(self.stanzas.remove(index))
+    }
+
+    ///
+    /// Changes the `Status` of `key`, every other field staying as it is.
+    /// Returns whether it's there at all
+    ///
+    pub fn set_status(&mut self, key: &str, status: Status) -> Result<bool> {
+        self.update(key, |paragraph| paragraph.set("Status", &status.to_string()))
+    }
+
+    ///
+    /// Edits the paragraph of `key` in place. Returns whether it's there at all
+    ///
+    pub fn update<F: FnOnce(&mut Paragraph)>(&mut self, key: &str, edit: F) -> Result<bool> {
+        match self.stanzas.iter_mut().find(|stanza| is_stanza_of(stanza, key)) {
+            Some(stanza) => {
+                let mut paragraph = Paragraph::parse(stanza)?;
+                edit(&mut paragraph);
+                *stanza = paragraph.to_string().trim_end().to_owned();
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    ///
+    /// Replaces the paragraph of the same package and architecture where it stands,
+    /// or adds it at the end if there's none
+    ///
+    pub fn set(&mut self, stanza: &str) {
+        let stanza = stanza.trim_matches('\n').to_owned();
+        let key = format!("{}:{}", field(&stanza, "Package").unwrap_or_default(), field(&stanza, "Architecture").unwrap_or_default());
+
+        match self.stanzas.iter_mut().find(|s| is_stanza_of(s, &key)) {
+            Some(existing) => *existing = stanza,
+            None => self.stanzas.push(stanza),
+        }
+    }
+
+    ///
+    /// Writes the database next to the current one, then swaps them with a single rename,
+    /// so a crash leaves either the old or the new one but never half of it
+    ///
+    pub fn commit(self) -> Result<()> {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".opm-new");
+        let new = self.path.with_file_name(name);
+
+        let mut file = fs::File::create(&new)?;
+        for stanza in self.stanzas.iter() {
+            write!(file, "{}\n\n", stanza)?;
+        }
+        file.sync_all()?;
+
+        if let Ok(meta) = fs::metadata(&self.path) {
+            fs::set_permissions(&new, meta.permissions())?;
+        }
+
+        fs::rename(&new, &self.path)?;
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::File::open(dir)?.sync_all()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/status");
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("opm-status-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("status");
+        fs::copy(FIXTURE, &path).unwrap();
+        path
+    }
+
+    fn packages(path: &Path) -> Vec<String> {
+        deb822::split(&fs::read_to_string(path).unwrap())
+            .into_iter()
+            .map(|stanza| format!("{}:{}", field(stanza, "Package").unwrap(), field(stanza, "Architecture").unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn status_test() {
+        let status = "hold ok installed".parse::<Status>().unwrap();
+        assert_eq!(status, Status { want: Want::Hold, flag: Flag::Ok, state: State::Installed });
+        assert!(status.is_installed());
+
+        let status = "deinstall reinstreq half-configured".parse::<Status>().unwrap();
+        assert_eq!(status.to_string(), "deinstall reinstreq half-configured");
+        assert!(status.is_installed());
+        assert!(!Status::new(Want::Deinstall, State::ConfigFiles).is_installed());
+
+        assert!("install ok".parse::<Status>().is_err());
+        assert!("install ok removed".parse::<Status>().is_err());
+    }
+
+    #[test]
+    fn set_status_test() {
+        let path = scratch("set-status");
+
+        let mut db = StatusDb::open(&path).unwrap();
+        assert!(db.set_status("libc6:i386", Status::new(Want::Deinstall, State::ConfigFiles)).unwrap());
+        assert!(!db.set_status("nope", Status::new(Want::Purge, State::NotInstalled)).unwrap());
+        db.commit().unwrap();
+
+        // Only the Status line changed
+        let fixture = fs::read_to_string(FIXTURE).unwrap();
+        let i386 = fixture.find("Architecture: i386").unwrap();
+        let status = i386 - fixture[..i386].rfind("Status: install ok installed").unwrap();
+        let mut expected = fixture.clone();
+        expected.replace_range(i386 - status..i386 - status + "Status: install ok installed".len(), "Status: deinstall ok config-files");
+        assert_eq!(fs::read_to_string(&path).unwrap(), expected);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn remove_test() {
+        let path = scratch("remove");
+
+        let mut db = StatusDb::open(&path).unwrap();
+        // `lib` is a prefix of the others, yet only its own paragraph goes
+        assert!(db.remove("lib").unwrap().contains("It must never be mistaken for libc6."));
+        assert!(db.remove("lib").is_none());
+        db.commit().unwrap();
+        assert_eq!(packages(&path), vec!["libc6:amd64", "libc6:i386", "libc6-dev:amd64", "zlib1g:amd64"]);
+
+        let mut db = StatusDb::open(&path).unwrap();
+        db.remove("libc6:i386").unwrap();
+        db.commit().unwrap();
+        assert_eq!(packages(&path), vec!["libc6:amd64", "libc6-dev:amd64", "zlib1g:amd64"]);
+
+        // Everything after what was removed is still there, byte for byte
+        let contents = fs::read_to_string(&path).unwrap();
+        let fixture = fs::read_to_string(FIXTURE).unwrap();
+        let tail = &fixture[fixture.find("Package: libc6-dev").unwrap()..];
+        assert!(contents.ends_with(tail), "{}", contents);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn set_test() {
+        let path = scratch("set");
+
+        let mut db = StatusDb::open(&path).unwrap();
+        let stanza = db.get("libc6:amd64").unwrap().replace("Version: 2.36-9+deb12u10", "Version: 2.36-9+deb12u11");
+        db.set(&stanza);
+        db.set("Package: new\nStatus: install ok installed\nArchitecture: all\nVersion: 1\n");
+        db.commit().unwrap();
+
+        assert_eq!(packages(&path), vec!["lib:amd64", "libc6:amd64", "libc6:i386", "libc6-dev:amd64", "zlib1g:amd64", "new:all"]);
+
+        let db = StatusDb::open(&path).unwrap();
+        assert!(db.get("libc6:amd64").unwrap().contains("Version: 2.36-9+deb12u11"));
+        assert!(db.get("libc6:i386").unwrap().contains("Version: 2.36-9+deb12u10"));
+        // Multiline fields survive untouched
+        assert!(db.get("libc6").unwrap().contains("Conffiles:\n /etc/ld.so.conf.d/x86_64-linux-gnu.conf d4e7a7b88a71b5ffd9e2644e71a0cfab\n"));
+        assert!(db.get("libc6-dev").unwrap().contains("Description: GNU C Library: Development Libraries and Header Files\n .\n"));
+        drop(db);
+
+        // Unchanged databases are written back as they were read
+        StatusDb::open(&path).unwrap().commit().unwrap();
+        let mut db = StatusDb::open(&path).unwrap();
+        db.remove("new");
+        db.set(&stanza.replace("2.36-9+deb12u11", "2.36-9+deb12u10"));
+        db.commit().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), fs::read_to_string(FIXTURE).unwrap());
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,328 @@
+use anyhow::Result;
+use std::{fs, path::{Path, PathBuf}};
+
+use crate::repos::{config::Config, errors::TriggersError};
+use super::cache;
+use super::deb822::Paragraph;
+use super::package::{ControlFile, Info};
+use super::scripts::{self, Script};
+use super::status::{self, State, Status, StatusDb, Want};
+
+//
+// dpkg triggers: packages say what they're interested in through the `triggers` control file,
+// and get their postinst called with `triggered` once something activates it
+// https://git.dpkg.org/cgit/dpkg/dpkg.git/tree/doc/triggers.txt
+//
+
+///
+/// A line of a `triggers` control file. Names starting with `/` are file triggers,
+/// activated by any package shipping something under that path
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Directive {
+    ///
+    /// `interest`, `interest-await` or `interest-noawait`
+    ///
+    Interest { name: String, wait: bool },
+    ///
+    /// `activate`, `activate-await` or `activate-noawait`
+    ///
+    Activate { name: String, wait: bool },
+}
+
+///
+/// Every directive of the `triggers` control file `file`
+///
+pub fn parse(file: &str, contents: &str) -> Result<Vec<Directive>> {
+    let mut directives = vec![];
+
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let malformed = |why: &str| TriggersError::Malformed { file: file.to_owned(), line: n + 1, why: why.to_owned() };
+        let (directive, name) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [directive, name] => (directive, name.to_owned()),
+            _ => anyhow::bail!(malformed("expected a directive and a trigger name")),
+        };
+
+        directives.push(match directive {
+            "interest" | "interest-await" => Directive::Interest { name, wait: true },
+            "interest-noawait" => Directive::Interest { name, wait: false },
+            "activate" | "activate-await" => Directive::Activate { name, wait: true },
+            "activate-noawait" => Directive::Activate { name, wait: false },
+            _ => anyhow::bail!(malformed(&format!("unknown directive '{}'", directive))),
+        });
+    }
+
+    Ok(directives)
+}
+
+///
+/// The directives of the control files `info`, if they have any
+///
+fn directives(info: &Info) -> Result<Vec<Directive>> {
+    match &info.triggers {
+        Some(path) => parse(&path.display().to_string(), &fs::read_to_string(path)?),
+        None => Ok(vec![]),
+    }
+}
+
+///
+/// The names in the space separated `field` of `paragraph`
+///
+fn names(paragraph: &Paragraph, field: &str) -> Vec<String> {
+    paragraph.get(field)
+        .map(|value| value.split_whitespace().map(str::to_owned).collect())
+        .unwrap_or_default()
+}
+
+fn set_names(paragraph: &mut Paragraph, field: &str, names: &[String]) {
+    match names.is_empty() {
+        true => paragraph.remove(field),
+        false => paragraph.set(field, &names.join(" ")),
+    }
+}
+
+///
+/// Activates what `pkg` triggers: the `activate` directives of its control files `info`,
+/// and the file triggers any of `paths` (what it just unpacked or removed) falls under.
+/// Interested packages are left triggers-pending; returns the ones `pkg` has to await
+///
+pub fn activate(config: &Config, pkg: &str, info: &Info, paths: &[PathBuf]) -> Result<Vec<String>> {
+    let activated = directives(info)?
+        .into_iter()
+        .filter_map(|directive| match directive {
+            Directive::Activate { name, wait } => Some((name, wait)),
+            Directive::Interest { .. } => None,
+        })
+        .collect::<Vec<_>>();
+
+    let mut db = StatusDb::open(&status::path(config))?;
+    let mut awaited = vec![];
+
+    // Only configured packages can be triggered, the rest get their postinst called anyway
+    for other in cache::db_entries(config) {
+        let (key, status) = (other.control.key(), other.control.status);
+        if key == pkg || status.is_some_and(|status| !matches!(status.state, State::Installed | State::TriggersAwaited | State::TriggersPending)) {
+            continue;
+        }
+
+        let mut pending = vec![];
+        let mut wait = false;
+        for directive in directives(&Info::installed(config, &key)?)? {
+            let (name, interest) = match directive {
+                Directive::Interest { name, wait } => (name, wait),
+                Directive::Activate { .. } => continue,
+            };
+
+            let explicit = activated.iter().find(|(n, _)| *n == name).map(|(_, wait)| *wait);
+            let file = name.starts_with('/') && paths.iter().any(|path| path.starts_with(Path::new(&name)));
+
+            if explicit.is_some() || file {
+                // Either side can say there's no need to wait
+                wait |= interest && explicit.unwrap_or(true);
+                pending.push(name);
+            }
+        }
+
+        if pending.is_empty() {
+            continue;
+        }
+
+        println!("Activating triggers of {}: {}", key, pending.join(" "));
+        let want = status.map_or(Want::Install, |status| status.want);
+        db.update(&key, |paragraph| {
+            let mut names = names(paragraph, "Triggers-Pending");
+            for name in pending {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+
+            set_names(paragraph, "Triggers-Pending", &names);
+            paragraph.set("Status", &Status::new(want, State::TriggersPending).to_string());
+        })?;
+
+        if wait {
+            awaited.push(key);
+        }
+    }
+
+    db.commit()?;
+    Ok(awaited)
+}
+
+///
+/// The triggers of `pkg` still waiting to be processed, like the ones its postinst failed on
+///
+pub fn pending(config: &Config, pkg: &str) -> Result<Vec<String>> {
+    match cache::get_stanza(config, pkg)? {
+        Some(stanza) => Ok(names(&Paragraph::parse(&stanza)?, "Triggers-Pending")),
+        None => Ok(vec![]),
+    }
+}
+
+///
+/// Keeps what the database `stanza` of a package had pending or awaited in its new `paragraph`
+///
+pub fn carry(stanza: &str, paragraph: &mut Paragraph) -> Result<()> {
+    let old = Paragraph::parse(stanza)?;
+    for field in ["Triggers-Pending", "Triggers-Awaited"] {
+        set_names(paragraph, field, &names(&old, field));
+    }
+
+    Ok(())
+}
+
+///
+/// Leaves `pkg` awaiting the triggers of `awaited` to be processed
+///
+pub fn wait(config: &Config, pkg: &str, awaited: &[String]) -> Result<()> {
+    if awaited.is_empty() {
+        return Ok(());
+    }
+
+    let mut db = StatusDb::open(&status::path(config))?;
+    let want = cache::find_package(config, pkg)
+        .and_then(|pkg| pkg.control.status)
+        .map_or(Want::Install, |status| status.want);
+
+    db.update(pkg, |paragraph| {
+        set_names(paragraph, "Triggers-Awaited", awaited);
+        paragraph.set("Status", &Status::new(want, State::TriggersAwaited).to_string());
+    })?;
+    db.commit()
+}
+
+///
+/// Calls `postinst triggered` for every triggers-pending package, at the end of a transaction so
+/// each one runs only once however many packages activated it
+///
+pub fn process(config: &Config) -> Result<()> {
+    while let Some(pkg) = cache::db_entries(config)
+        .into_iter()
+        .find(|pkg| pkg.control.status.is_some_and(|status| status.state == State::TriggersPending))
+    {
+        let (key, want) = (pkg.control.key(), pkg.control.status.map_or(Want::Install, |status| status.want));
+        let paragraph = &pkg.control.paragraph;
+        let pending = names(paragraph, "Triggers-Pending");
+
+        println!("Processing triggers for {} ...", key);
+        if let Err(e) = scripts::run(config, None, &pkg.control, &Info::installed(config, &key)?, Script::Postinst, &["triggered", &pending.join(" ")]) {
+            cache::set_status(config, &key, Status::new(want, State::HalfConfigured))?;
+            return Err(e);
+        }
+
+        processed(config, &pkg.control, want)?;
+    }
+
+    Ok(())
+}
+
+///
+/// Clears what `pkg` had pending, and lets go of whoever was awaiting it
+///
+fn processed(config: &Config, pkg: &ControlFile, want: Want) -> Result<()> {
+    let key = pkg.key();
+    let mut db = StatusDb::open(&status::path(config))?;
+
+    let state = match names(&pkg.paragraph, "Triggers-Awaited").is_empty() {
+        true => State::Installed,
+        false => State::TriggersAwaited,
+    };
+    db.update(&key, |paragraph| {
+        paragraph.remove("Triggers-Pending");
+        paragraph.set("Status", &Status::new(want, state).to_string());
+    })?;
+
+    for other in cache::db_entries(config) {
+        let mut awaited = names(&other.control.paragraph, "Triggers-Awaited");
+        if !awaited.contains(&key) {
+            continue;
+        }
+
+        awaited.retain(|name| *name != key);
+        let status = match other.control.status {
+            Some(status) if awaited.is_empty() && status.state == State::TriggersAwaited => Status::new(status.want, State::Installed),
+            Some(status) => status,
+            None => Status::new(Want::Install, State::Installed),
+        };
+
+        db.update(&other.control.key(), |paragraph| {
+            set_names(paragraph, "Triggers-Awaited", &awaited);
+            paragraph.set("Status", &status.to_string());
+        })?;
+    }
+
+    db.commit()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn parse_test() {
+        let contents = "# comment\ninterest /usr/share/man\ninterest-noawait ldconfig # trailing\n\nactivate-await update-menus\nactivate-noawait ldconfig\n";
+        assert_eq!(parse("triggers", contents).unwrap(), vec![
+            Directive::Interest { name: String::from("/usr/share/man"), wait: true },
+            Directive::Interest { name: String::from("ldconfig"), wait: false },
+            Directive::Activate { name: String::from("update-menus"), wait: true },
+            Directive::Activate { name: String::from("ldconfig"), wait: false },
+        ]);
+
+        let err = parse("triggers", "interest\n").unwrap_err();
+        assert_eq!(err.to_string(), "Malformed triggers at triggers:1 :: expected a directive and a trigger name");
+        assert!(parse("triggers", "activate-now ldconfig\n").is_err());
+    }
+
+    #[test]
+    fn process_test() {
+        let dir = std::env::temp_dir().join(format!("opm-triggers-{}", std::process::id()));
+        let info = dir.join("info");
+        fs::create_dir_all(info.join("opm-man")).unwrap();
+        fs::create_dir_all(info.join("opm-doc")).unwrap();
+
+        let mut config = crate::repos::setup().unwrap();
+        config.info = info.display().to_string();
+        config.db = dir.join("db").display().to_string();
+        fs::write(&config.db, concat!(
+            "Package: opm-man\nStatus: install ok installed\nVersion: 1.0\nArchitecture: all\nMaintainer: opm\nDescription: test\n\n",
+            "Package: opm-doc\nStatus: install ok installed\nVersion: 1.0\nArchitecture: all\nMaintainer: opm\nDescription: test\n",
+        )).unwrap();
+
+        // opm-man is interested in what opm-doc ships, and records what it gets called with
+        let out = dir.join("out");
+        fs::write(info.join("opm-man/triggers"), "interest /usr/share/man\ninterest-noawait opm-index\n").unwrap();
+        fs::write(info.join("opm-man/postinst"), format!("#!/bin/sh\necho \"$*\" >> {}\n", out.display())).unwrap();
+        fs::set_permissions(info.join("opm-man/postinst"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(info.join("opm-doc/triggers"), "activate opm-index\n").unwrap();
+
+        let doc = Info::installed(&config, "opm-doc").unwrap();
+        let awaited = activate(&config, "opm-doc", &doc, &[PathBuf::from("/usr/share/man/man1/opm.1.gz")]).unwrap();
+        assert_eq!(awaited, vec!["opm-man"]);
+        wait(&config, "opm-doc", &awaited).unwrap();
+
+        let state = |name: &str| cache::find_package(&config, name).unwrap().control.status.unwrap().state;
+        assert_eq!(state("opm-man"), State::TriggersPending);
+        assert_eq!(state("opm-doc"), State::TriggersAwaited);
+
+        process(&config).unwrap();
+        assert_eq!(fs::read_to_string(&out).unwrap(), "triggered /usr/share/man opm-index\n");
+        assert_eq!(state("opm-man"), State::Installed);
+        assert_eq!(state("opm-doc"), State::Installed);
+
+        let db = fs::read_to_string(&config.db).unwrap();
+        assert!(!db.contains("Triggers-"));
+
+        // Nothing it cares about, nothing to do
+        activate(&config, "opm-doc", &Info::default(), &[PathBuf::from("/usr/share/doc/opm")]).unwrap();
+        assert_eq!(state("opm-man"), State::Installed);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
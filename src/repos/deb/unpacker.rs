@@ -0,0 +1,323 @@
+use anyhow::Result;
+use std::{fmt::{self, Display}, fs, io::Read, path::{Component, Path, PathBuf}};
+use std::os::unix::fs::{symlink, PermissionsExt};
+use tar::{Archive, EntryType};
+
+use super::extract::keep_owner;
+
+//
+// Unpacking archives nobody vouched for: every entry is checked before anything gets written,
+// so a hostile package can't place files outside of where it's unpacked (or sneak in what the policy forbids)
+//
+
+///
+/// What happens to an entry that isn't what a package should ship
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    ///
+    /// The whole package is refused
+    ///
+    Reject,
+    ///
+    /// Only the entry is left out, or made harmless when it can be (leading `/` or setuid bits taken away)
+    ///
+    Strip,
+    Allow,
+}
+
+///
+/// The `Policy` for each kind of entry a hostile archive may have
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct UnpackPolicy {
+    ///
+    /// Paths starting with `/` instead of `./`
+    ///
+    pub absolute: Policy,
+    ///
+    /// Paths going up with `..`, which never get out of the package even when allowed
+    ///
+    pub parent: Policy,
+    ///
+    /// Symlinks pointing above the root they're installed in
+    ///
+    pub symlinks: Policy,
+    ///
+    /// Character and block devices, and FIFOs
+    ///
+    pub devices: Policy,
+    ///
+    /// Files with the setuid or setgid bits
+    ///
+    pub setuid: Policy,
+}
+
+impl Default for UnpackPolicy {
+    fn default() -> Self {
+        Self {
+            absolute: Policy::Strip,
+            parent: Policy::Reject,
+            symlinks: Policy::Reject,
+            devices: Policy::Reject,
+            setuid: Policy::Allow,
+        }
+    }
+}
+
+///
+/// An entry the policy didn't allow, and what was done about it
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Refusal {
+    pub path: String,
+    pub why: String,
+    pub policy: Policy,
+}
+
+impl Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.path, self.why)
+    }
+}
+
+///
+/// `path` without `.` and `..`, or none if it goes above where it starts. Also says whether it had any `..`
+///
+fn normalize(path: &Path) -> (Option<PathBuf>, bool) {
+    let mut result = PathBuf::new();
+    let mut parent = false;
+
+    for component in path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => (),
+            Component::ParentDir => {
+                parent = true;
+                if !result.pop() {
+                    return (None, true);
+                }
+            },
+            Component::Normal(name) => result.push(name),
+        }
+    }
+
+    (Some(result), parent)
+}
+
+///
+/// Whether the symlink at `rel` pointing to `target` gets above the root once installed.
+/// Absolute targets start from that root, never from the host's `/`
+///
+fn escapes(rel: &Path, target: &Path) -> bool {
+    let mut depth = match target.has_root() {
+        true => 0,
+        false => rel.parent().map_or(0, |parent| parent.components().count()),
+    };
+
+    for component in target.components() {
+        match component {
+            Component::ParentDir if depth == 0 => return true,
+            Component::ParentDir => depth -= 1,
+            Component::Normal(_) => depth += 1,
+            _ => (),
+        }
+    }
+
+    false
+}
+
+///
+/// The first directory of `rel` that's a symlink in `dst`
+///
+fn through<'a>(dst: &Path, rel: &'a Path) -> Option<&'a Path> {
+    rel.ancestors()
+        .skip(1)
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .find(|dir| fs::symlink_metadata(dst.join(dir)).is_ok_and(|meta| meta.file_type().is_symlink()))
+}
+
+///
+/// Unpacks every entry of `archive` into `dst` as `policy` says, keeping modes and (when allowed to) owners.
+/// Nothing is ever written outside of `dst`; returns whatever was refused or stripped
+///
+pub fn unpack<R: Read>(archive: &mut Archive<R>, dst: &Path, policy: &UnpackPolicy) -> Result<Vec<Refusal>> {
+    archive.set_preserve_permissions(true);
+    let mut refused = vec![];
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let raw = entry.path()?.into_owned();
+        let kind = entry.header().entry_type();
+
+        let mut check = |policy: Policy, why: String| {
+            if policy != Policy::Allow {
+                refused.push(Refusal { path: raw.display().to_string(), why, policy });
+            }
+            policy
+        };
+
+        if raw.has_root() && check(policy.absolute, String::from("absolute path")) == Policy::Reject {
+            continue;
+        }
+
+        let rel = match normalize(&raw) {
+            (None, _) => {
+                check(Policy::Reject, String::from("goes outside the package"));
+                continue;
+            },
+            (Some(_), true) if check(policy.parent, String::from("'..' in its path")) != Policy::Allow => continue,
+            (Some(rel), _) => rel,
+        };
+        // The `./` every data.tar starts with
+        if rel.as_os_str().is_empty() {
+            continue;
+        }
+
+        // A symlink it unpacked before would take it anywhere
+        if let Some(link) = through(dst, &rel) {
+            check(Policy::Reject, format!("goes through the symlink {}", link.display()));
+            continue;
+        }
+
+        let mut mode = entry.header().mode()? & 0o7777;
+        let link = entry.link_name()?.map(|link| link.into_owned());
+        match kind {
+            EntryType::Symlink => {
+                let target = link.clone().unwrap_or_default();
+                if escapes(&rel, &target) && check(policy.symlinks, format!("links outside the root to {}", target.display())) != Policy::Allow {
+                    continue;
+                }
+            },
+            EntryType::Link => {
+                // Hard links only ever go to what the package itself has, and never through its symlinks
+                match link.as_deref().map(normalize) {
+                    Some((Some(target), false)) if through(dst, &target).is_none() && fs::symlink_metadata(dst.join(&target)).is_ok_and(|meta| meta.is_file()) => (),
+                    _ => {
+                        check(Policy::Reject, String::from("hard link to something outside the package"));
+                        continue;
+                    },
+                }
+            },
+            EntryType::Char | EntryType::Block | EntryType::Fifo if check(policy.devices, String::from("device node")) != Policy::Allow => continue,
+            EntryType::Regular | EntryType::Continuous if mode & 0o6000 != 0 => {
+                match check(policy.setuid, format!("setuid/setgid mode {:o}", mode)) {
+                    Policy::Reject => continue,
+                    Policy::Strip => mode &= !0o6000,
+                    Policy::Allow => (),
+                }
+            },
+            _ => (),
+        }
+
+        let path = dst.join(&rel);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Whatever is there already gets replaced, never written through
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if !meta.is_dir() => fs::remove_file(&path)?,
+            Ok(_) if kind != EntryType::Directory => fs::remove_dir_all(&path)?,
+            _ => (),
+        }
+
+        let (uid, gid) = (entry.header().uid()? as u32, entry.header().gid()? as u32);
+        match kind {
+            EntryType::Symlink => symlink(link.unwrap_or_default(), &path)?,
+            // Same file as its target, which already has its owner and mode
+            EntryType::Link => {
+                fs::hard_link(dst.join(normalize(&link.unwrap_or_default()).0.unwrap_or_default()), &path)?;
+                continue;
+            },
+            _ => {
+                entry.unpack(&path)?;
+            },
+        }
+
+        // chown clears the setuid/setgid bits, so the mode goes last
+        keep_owner(&path, uid, gid)?;
+        if matches!(kind, EntryType::Regular | EntryType::Continuous | EntryType::Directory) {
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+        }
+    }
+
+    Ok(refused)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn normalize_test() {
+        assert_eq!(normalize(Path::new("./usr/bin/opm")), (Some(PathBuf::from("usr/bin/opm")), false));
+        assert_eq!(normalize(Path::new("/usr/bin/../lib")), (Some(PathBuf::from("usr/lib")), true));
+        assert_eq!(normalize(Path::new("./usr/../../etc")), (None, true));
+    }
+
+    #[test]
+    fn escapes_test() {
+        let rel = Path::new("usr/lib/opm/link");
+        assert!(!escapes(rel, Path::new("../../share/opm")));
+        assert!(!escapes(rel, Path::new("/etc/opm.conf")));
+        assert!(!escapes(rel, Path::new("../../../etc")));
+        assert!(escapes(rel, Path::new("../../../../etc")));
+        assert!(escapes(rel, Path::new("/../etc")));
+    }
+
+    #[test]
+    fn hostile_test() {
+        use std::os::unix::fs::MetadataExt;
+        use super::super::extract::{extract, Package};
+
+        let dir = std::env::temp_dir().join(format!("opm-hostile-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut config = crate::repos::setup().unwrap();
+        config.tmp = dir.display().to_string();
+
+        let fixture = |name: &str| format!("{}/tests/fixtures/hostile/{}.deb", env!("CARGO_MANIFEST_DIR"), name);
+        let unpack = |config: &crate::repos::config::Config, name: &str| {
+            let _ = fs::remove_dir_all(dir.join(name));
+            extract(config, &fixture(name), name).map(|Package(_, _, data)| PathBuf::from(data.data_path))
+        };
+        let refused = |config: &crate::repos::config::Config, name: &str, why: &str| {
+            let err = unpack(config, name).err().unwrap().to_string();
+            assert!(err.starts_with(&format!("Refusing to unpack {}", fixture(name))) && err.contains(why), "{}", err);
+        };
+
+        // What's refused no matter what
+        let strict = |config: &crate::repos::config::Config| {
+            refused(config, "escape", "goes outside the package");
+            refused(config, "through", "goes through the symlink usr/share/opm-hostile");
+            refused(config, "hardlink", "hard link to something outside the package");
+            refused(config, "hardlink-symlink", "./usr/share/opm-shadow (hard link to something outside the package)");
+        };
+
+        // The defaults take the leading `/` away and let setuid through, but nothing else
+        strict(&config);
+        assert!(unpack(&config, "absolute").unwrap().join("usr/share/opm-hostile").is_file());
+        assert_eq!(unpack(&config, "setuid").unwrap().join("usr/share/opm-hostile").metadata().unwrap().mode() & 0o7777, 0o4755);
+        refused(&config, "parent", "'..' in its path");
+        refused(&config, "symlink", "links outside the root to ../../../../etc");
+        refused(&config, "device", "usr/share/opm-null (device node), ./usr/share/opm-fifo (device node)");
+
+        config.unpack = UnpackPolicy { absolute: Policy::Reject, parent: Policy::Strip, symlinks: Policy::Strip, devices: Policy::Strip, setuid: Policy::Strip };
+        strict(&config);
+        refused(&config, "absolute", "absolute path");
+        assert!(!unpack(&config, "parent").unwrap().join("usr/share/opm-hostile").exists());
+        assert!(fs::symlink_metadata(unpack(&config, "symlink").unwrap().join("usr/share/opm-hostile")).is_err());
+        assert!(fs::read_dir(unpack(&config, "device").unwrap().join("usr/share")).unwrap().next().is_none());
+        assert_eq!(unpack(&config, "setuid").unwrap().join("usr/share/opm-hostile").metadata().unwrap().mode() & 0o7777, 0o755);
+
+        // Allowed, `..` still stays inside the package
+        config.unpack = UnpackPolicy { absolute: Policy::Allow, parent: Policy::Allow, symlinks: Policy::Allow, devices: Policy::Allow, setuid: Policy::Allow };
+        strict(&config);
+        assert!(unpack(&config, "parent").unwrap().join("usr/share/opm-hostile").is_file());
+        assert_eq!(fs::read_link(unpack(&config, "symlink").unwrap().join("usr/share/opm-hostile")).unwrap(), Path::new("../../../../etc"));
+
+        assert!(!Path::new("/tmp/escape").exists() && !dir.join("opm-hostile").exists() && !std::env::temp_dir().join("opm-hostile").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -6,11 +6,12 @@ use std::{
     io::{ErrorKind, prelude::*},
     fs,
     path::Path,
-    str
+    str,
+    time::{SystemTime, UNIX_EPOCH},
 };
 use futures::{future, StreamExt};
-use super::sources::DebianSource;
-use crate::repos::config::Config;
+use super::{arch::Architectures, journal, pkgcache::PackageCache, release::{self, Release}, sources::{DebianSource, SourceKind}};
+use crate::repos::{config::Config, errors::ReleaseError};
 
 fn unpack(filename: &str, data: &[u8], bytes: &mut Vec<u8>) {
     if filename.ends_with(".gz") {
@@ -23,6 +24,9 @@ fn unpack(filename: &str, data: &[u8], bytes: &mut Vec<u8>) {
 }
 
 pub fn clear(config: &Config) -> Result<()> {
+    // Wiping the tmp dir would take the backups of an interrupted transaction with it
+    journal::settled(config)?;
+
     match fs::remove_dir_all(&config.cache){
         Ok(_) => (),
         Err(e) => match e.kind() {
@@ -55,72 +59,156 @@ pub fn clear(config: &Config) -> Result<()> {
     Ok(())
 }
 
-pub async fn update(config: &mut Config, repos: &[DebianSource]) -> Result<()> {
-    let (mut cache, mut rls) = (vec![], vec![]);
+pub async fn update(config: &mut Config, repos: &[DebianSource], insecure: bool) -> Result<()> {
+    let archs = Architectures::from_config(config);
+    let mut sources = vec![];
     let spinner_style = ProgressStyle::default_spinner()
         .template("{spinner} {prefix}");
 
     let mp = MultiProgress::new();
-    for (i, source) in repos.iter().enumerate() {
-        for perm in source.components.iter() {
+    // Source packages aren't handled (yet)
+    for (i, source) in repos.iter().filter(|source| source.kind == SourceKind::Binary).enumerate() {
+        let rls_bar = mp.add(ProgressBar::new(0));
+        rls_bar.set_style(spinner_style.clone());
+
+        let indices = indices(source, &archs);
+        let mut cache_bars = vec![];
+        for _ in indices.iter() {
             let cache_bar = mp.add(ProgressBar::new(0));
             cache_bar.set_style(spinner_style.clone());
-
-            let rls_bar = mp.add(ProgressBar::new(0));
-            rls_bar.set_style(spinner_style.clone());
-            
-            cache.push(update_cache(config, &source.url, &source.distribution, perm, cache_bar, i));
-            rls.push(update_releases(config, &source.url, &source.distribution, perm, rls_bar, i));
+            cache_bars.push(cache_bar);
         }
+
+        sources.push(update_source(config, source, indices, insecure, rls_bar, cache_bars, i));
     }
     let handle = tokio::task::spawn_blocking(move || mp.join().unwrap());
 
-    future::join_all(rls).await;
-    future::join_all(cache).await;
-    
+    let mut errors = future::join_all(sources).await
+        .into_iter()
+        .filter_map(|result| result.err())
+        .collect::<Vec<_>>();
+
     handle.await?;
 
+    // Built right away instead of on the first lookup
+    if let Err(e) = PackageCache::open(config) {
+        errors.push(e);
+    }
+
+    // One broken source shouldn't hide the others
+    match errors.len() {
+        0 => Ok(()),
+        _ => {
+            errors.iter().skip(1).for_each(|e| eprintln!("{}", e));
+            Err(errors.remove(0))
+        }
+    }
+}
+
+///
+/// `(path, arch)` of every binary index of `source`, relative to its `dist_url`
+///
+fn indices(source: &DebianSource, archs: &Architectures) -> Vec<(String, String)> {
+    let archs = match source.options.architectures.is_empty() {
+        true => archs.indices().into_iter().map(|arch| arch.to_owned()).collect(),
+        false => source.options.architectures.iter().cloned().chain(std::iter::once(String::from("all"))).collect::<Vec<_>>(),
+    };
+
+    if source.is_flat() {
+        return source.indices("").into_iter().map(|path| (path, String::new())).collect();
+    }
+
+    archs.iter()
+        .flat_map(|arch| source.indices(arch).into_iter().map(move |path| (path, arch.clone())))
+        .collect()
+}
+
+///
+/// Fetches the `InRelease` of `source` and, once it's trusted, every index it lists
+///
+async fn update_source(config: &Config, source: &DebianSource, indices: Vec<(String, String)>, insecure: bool,
+                       rls_bar: ProgressBar, cache_bars: Vec<ProgressBar>, counter: usize) -> Result<()> {
+    let release_file = format!("{}InRelease", source.dist_url());
+    let response = reqwest::get(&release_file).await.and_then(|r| r.error_for_status())?;
+    let content = download(response, &release_file, &rls_bar, counter).await?;
+    rls_bar.finish_and_clear();
+
+    let content = String::from_utf8(content).map_err(|_| ReleaseError::Invalid(format!("{:?} is not text", release_file)))
+        .map_err(|e| anyhow::anyhow!(e))?;
+
+    let release = if insecure || source.options.trusted {
+        eprintln!("Warning: not checking the signature and hashes of {}", release_file);
+        None
+    } else {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let release = Release::verify(&release_file, &content, &release::keyring(source)?, now)?;
+
+        // Going back to an older (signed, still valid) Release would bring back what was fixed since
+        let previous = Release::load(&Path::new(&config.rls).join(source.file_name("InRelease"))).ok();
+        if let (Some(date), Some(previous)) = (release.date()?, previous.and_then(|previous| previous.date().ok().flatten())) {
+            if date < previous {
+                anyhow::bail!(ReleaseError::Older { release: release_file, date, previous });
+            }
+        }
+
+        let names = [release.paragraph.get("Suite"), release.paragraph.get("Codename")];
+        if !source.is_flat() && !names.contains(&Some(source.distribution.as_str())) {
+            eprintln!("Warning: conflicting distribution in {} (expected {})", release_file, source.distribution);
+        }
+
+        Some(release)
+    };
+
+    let rls = Path::new(&config.rls).join(source.file_name("InRelease"));
+    tokio::fs::write(rls, &content).await?;
+
+    let cache = indices.into_iter()
+        .zip(cache_bars)
+        .map(|((path, arch), bar)| update_cache(config, source, release.as_ref(), path, arch, bar, counter));
+
+    future::join_all(cache).await.into_iter().collect::<Result<Vec<_>>>()?;
     Ok(())
 }
 
-async fn update_cache(config: &Config, url: &str, dist: &str, perm: &str, pb: ProgressBar, counter: usize) -> Result<()> {
+async fn update_cache(config: &Config, source: &DebianSource, release: Option<&Release>, index: String, arch: String, pb: ProgressBar, counter: usize) -> Result<()> {
     // Binary packages ONLY for now
-    let pkgcache = format!("{}dists/{}/{}/binary-amd64/Packages.xz", url, dist, perm);
-    let response = match reqwest::get(&pkgcache).await {
-        Ok(r) => Some(r),
-        Err(_) => {
-            let pkgcache = format!("{}dists/{}/{}/binary-amd64/Packages.gz", url, dist, perm);
-            match reqwest::get(&pkgcache).await {
-                Ok(r) => Some(r),
-                Err(e) => {
-                    eprintln!("Could not get the package at {} due {}", pkgcache, e);
-                    None
-                }
+    let variants = [format!("{}.xz", index), format!("{}.gz", index)];
+
+    let candidates = match release {
+        Some(release) => match variants.iter().find(|path| release.get(path).is_some()) {
+            Some(path) => vec![path],
+            // Most repositories fold `all` into every other index
+            None if arch == "all" => vec![],
+            None => {
+                pb.finish_and_clear();
+                anyhow::bail!(ReleaseError::NotListed { release: release.name.clone(), index });
             }
-        }
+        },
+        None => variants.iter().collect(),
     };
 
-    let url = str::replace(url, "http://", "");
-    let url = str::replace(&url, "/", "_");    
-    
-    if let Some(response) = response {
-        let size = response.content_length().unwrap_or_default();
-        pb.set_length(size);
-        pb.set_prefix(format!("{}: {} [{}]", counter+1, pkgcache, HumanBytes(size)));
-
-        let (mut stream, mut downloaded) = (response.bytes_stream(), 0u64);
-        
-        let mut content = Vec::with_capacity(size as usize);
-        let pkg = Path::new(&config.cache).join(format!("{}dists_{}_{}_binary-amd64_Packages", url, dist, perm));
-
-        while let Some(item) = stream.next().await {
-            let chunk = item?;
-            Write::write(&mut content, &chunk)?;
-            let progress = std::cmp::min(downloaded + chunk.len() as u64, size);
-            downloaded = progress;
-            pb.set_position(progress);
+    let mut response = None;
+    for path in candidates {
+        let pkgcache = format!("{}{}", source.dist_url(), path);
+        match reqwest::get(&pkgcache).await.and_then(|r| r.error_for_status()) {
+            Ok(r) => {
+                response = Some((path, pkgcache, r));
+                break;
+            },
+            Err(_) if arch == "all" => (),
+            Err(e) => eprintln!("Could not get the package at {} due {}", pkgcache, e),
         }
-        
+    }
+
+    if let Some((path, pkgcache, response)) = response {
+        let content = download(response, &pkgcache, &pb, counter).await?;
+        pb.finish_and_clear();
+
+        if let Some(release) = release {
+            release.check(path, &content)?;
+        }
+
+        let pkg = Path::new(&config.cache).join(source.file_name(&index));
         let mut bytes = Vec::new();
         unpack(&pkgcache, content.as_ref(), &mut bytes);
         let mut bytes: &[u8] = bytes.as_ref();
@@ -128,29 +216,20 @@ async fn update_cache(config: &Config, url: &str, dist: &str, perm: &str, pb: Pr
             let mut pkg = tokio::fs::File::create(pkg).await.unwrap();
             tokio::io::copy(&mut bytes, &mut pkg).await.unwrap();
         }
-
+    } else {
         pb.finish_and_clear()
     }
-    
+
     Ok(())
 }
 
-async fn update_releases(config: &Config, url: &str, dist: &str, perm: &str, pb: ProgressBar, counter: usize) -> Result<()> {
-
-    let release_file = format!("{}dists/{}/InRelease", url, dist);
-
-    let url = str::replace(url, "http://", "");
-    let url = str::replace(&url, "/", "_");
-    
-    let response = reqwest::get(&release_file).await?;
+async fn download(response: reqwest::Response, name: &str, pb: &ProgressBar, counter: usize) -> Result<Vec<u8>> {
     let size = response.content_length().unwrap_or_default();
     pb.set_length(size);
-    pb.set_prefix(format!("{}: {} [{}]", counter+1, release_file, HumanBytes(size)));
-    
-    let (mut stream, mut downloaded) = (response.bytes_stream(), 0u64);
+    pb.set_prefix(format!("{}: {} [{}]", counter+1, name, HumanBytes(size)));
 
+    let (mut stream, mut downloaded) = (response.bytes_stream(), 0u64);
     let mut content = Vec::with_capacity(size as usize);
-    let rls = Path::new(&config.rls).join(format!("{}dists_{}_{}_binary-amd64_InRelease", url, dist, perm));
 
     while let Some(item) = stream.next().await {
         let chunk = item?;
@@ -159,11 +238,6 @@ async fn update_releases(config: &Config, url: &str, dist: &str, perm: &str, pb:
         downloaded = progress;
         pb.set_position(progress);
     }
-    
-    
-    let mut dest = tokio::fs::File::create(rls).await.unwrap();
-    tokio::io::copy(&mut content.as_ref(), &mut dest).await.unwrap();
 
-    pb.finish_and_clear();
-    Ok(())
+    Ok(content)
 }
@@ -9,7 +9,10 @@ pub enum InstallError {
     WrongVersion     { pkg: String, reqv: String, curv: String },
     UnexError        { msg: String, err: Option<Error> },
     AlreadyInstalled ( String ),
+    FileConflict     { pkg: String, other: String, path: String },
+    OutsideRoot      ( String ),
     Breaks           ( String ),
+    Unresolvable     { pkg: String, why: Vec<String> },
     NetworkingError  { err: Error },
     UnexInterrupt    { err: Error },
     UserInterrupt,
@@ -22,12 +25,17 @@ pub enum RemoveError {
     UnexError        { msg: String, err: Option<Error> },
 }
 
+#[derive(Debug)]
+pub enum ExtractError {
+    Invalid          { deb: String, why: String },
+    Hostile          { deb: String, refused: Vec<String> },
+}
+
 #[derive(Debug)]
 pub enum ScriptsError {
-    PreInst,
-    PostInst,
-    PreRm,
-    PostRm,
+    Failed { pkg: String, script: String, why: String },
+    Declined { pkg: String, script: String },
+    Unsandboxed { pkg: String, script: String },
 }
 
 #[derive(Debug)]
@@ -36,6 +44,63 @@ pub enum SignatureError {
     SHA1   { rs: String, ex: String },
     SHA256 { rs: String, ex: String },
     SHA512 { rs: String, ex: String },
+    Size   { rs: u64, ex: String },
+    Weak   ( String ),
+}
+
+#[derive(Debug)]
+pub enum ReleaseError {
+    Invalid       ( String ),
+    BadSignature  { release: String, why: String },
+    Untrusted     { release: String, keys: Vec<String> },
+    NotListed     { release: String, index: String },
+    Mismatch      { index: String, expected: String, got: String },
+    Expired       { release: String, since: u64 },
+    NotYetValid   { release: String, date: u64 },
+    Older         { release: String, date: u64, previous: u64 },
+}
+
+#[derive(Debug)]
+pub enum SourcesError {
+    Malformed     { file: String, line: usize, why: String },
+}
+
+#[derive(Debug)]
+pub enum TriggersError {
+    Malformed { file: String, line: usize, why: String },
+}
+
+#[derive(Debug)]
+pub enum DiversionError {
+    Malformed     { file: String, why: String },
+    Clash         { path: String, existing: String },
+    NotDiverted   ( String ),
+    Exists        ( String ),
+}
+
+#[derive(Debug)]
+pub enum AlternativesError {
+    Malformed     { file: String, why: String },
+    NotFound      ( String ),
+    NoSuchChoice  { name: String, path: String },
+    Missing       ( String ),
+    LinkMismatch  { name: String, link: String, wanted: String },
+    InvalidName   ( String ),
+}
+
+#[derive(Debug)]
+pub enum LockError {
+    Held          { path: String, pid: Option<i32> },
+}
+
+#[derive(Debug)]
+pub enum StatusError {
+    Invalid       ( String ),
+}
+
+#[derive(Debug)]
+pub enum PreferencesError {
+    Malformed     { file: String, why: String },
 }
 
 #[derive(Debug)]
@@ -43,6 +108,11 @@ pub enum ConfigError {
     UnexError     { msg: String, err: Option<Error> },
 }
 
+#[derive(Debug)]
+pub enum JournalError {
+    Pending       ( String ),
+}
+
 #[derive(Debug)]
 pub enum CacheError {
     NotFoundError { pkg: String, cache: String },
@@ -62,7 +132,10 @@ impl Display for InstallError {
             InstallError::BrokenPackage { pkg, err } => write!(f, "Perhaps {:?} is broken due some missing files :: error {:?}", pkg, err),
             InstallError::AlreadyInstalled ( pkg ) => write!(f, "{:?} is already installed on the newest version", pkg),
             InstallError::UnexError { msg, err }  => write!(f, "Unexpected Error {:?} :: {:?}", msg, err),
+            InstallError::FileConflict { pkg, other, path } => write!(f, "{} is trying to overwrite {:?}, which is also in package {}", pkg, path, other),
+            InstallError::OutsideRoot ( path ) => write!(f, "{:?} leads outside of the root through a symlink", path),
             InstallError::Breaks ( pkg ) => write!(f, "Package {:?} can break others", pkg),
+            InstallError::Unresolvable { pkg, why } => write!(f, "Could not resolve the dependencies of {:?}:\n  {}", pkg, why.join("\n  ")),
             InstallError::WrongVersion { pkg, reqv, curv } => write!(f, "Package \"{}({})\" does not satisfy \"{}({})\"", pkg, curv, pkg, reqv),
             InstallError::NetworkingError { err } => write!(f, "Networking Error :: {:?}", err),
             InstallError::UserInterrupt => write!(f, "Installation was interrupted by the user"),
@@ -80,13 +153,104 @@ impl Display for RemoveError {
     }
 }
 
+impl Display for ExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtractError::Invalid { deb, why } => write!(f, "{} is not a valid .deb :: {}", deb, why),
+            ExtractError::Hostile { deb, refused } => write!(f, "Refusing to unpack {} :: {}", deb, refused.join(", ")),
+        }
+    }
+}
+
 impl Display for SignatureError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             SignatureError::MD5 { rs, ex } => write!(f, "Mismatched MD5 Hash got {:?} expected {:?}", rs, ex),
             SignatureError::SHA1 { rs, ex } => write!(f, "Mismatched SHA1 Hash got {:?} expected {:?}", rs, ex),
             SignatureError::SHA256 { rs, ex } => write!(f, "Mismatched SHA256 Hash got {:?} expected {:?}", rs, ex),
-            SignatureError::SHA512 { rs, ex } => write!(f, "Mismatched SHA512 Hash got {:?} expected {}", rs, ex),
+            SignatureError::SHA512 { rs, ex } => write!(f, "Mismatched SHA512 Hash got {:?} expected {:?}", rs, ex),
+            SignatureError::Size { rs, ex } => write!(f, "Mismatched Size got {} bytes expected {:?}", rs, ex),
+            SignatureError::Weak ( pkg ) => write!(f, "{:?} has no SHA256 or SHA512 checksum, MD5 and SHA1 alone can be forged", pkg),
+        }
+    }
+}
+
+impl Display for ReleaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReleaseError::Invalid ( why ) => write!(f, "Invalid OpenPGP data :: {}", why),
+            ReleaseError::BadSignature { release, why } => write!(f, "The signature of {:?} is not valid :: {}", release, why),
+            ReleaseError::Untrusted { release, keys } => write!(f, "{:?} is not signed by a trusted key (signed by {})", release, keys.join(", ")),
+            ReleaseError::NotListed { release, index } => write!(f, "{:?} is not listed in {:?}", index, release),
+            ReleaseError::Mismatch { index, expected, got } => write!(f, "{:?} does not match its Release entry, got {} expected {}", index, got, expected),
+            ReleaseError::Expired { release, since } => write!(f, "{:?} has expired (valid until {} seconds since the epoch)", release, since),
+            ReleaseError::NotYetValid { release, date } => write!(f, "{:?} is not valid yet (dated {} seconds since the epoch), check the system clock", release, date),
+            ReleaseError::Older { release, date, previous } => write!(f, "{:?} is older than the one already there ({} < {} seconds since the epoch)", release, date, previous),
+        }
+    }
+}
+
+impl Display for SourcesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourcesError::Malformed { file, line, why } => write!(f, "Malformed source at {}:{} :: {}", file, line, why),
+        }
+    }
+}
+
+impl Display for TriggersError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TriggersError::Malformed { file, line, why } => write!(f, "Malformed triggers at {}:{} :: {}", file, line, why),
+        }
+    }
+}
+
+impl Display for DiversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiversionError::Malformed { file, why } => write!(f, "Malformed diversions at {} :: {}", file, why),
+            DiversionError::Clash { path, existing } => write!(f, "Diverting {} clashes with the {}", path, existing),
+            DiversionError::NotDiverted ( path ) => write!(f, "{} is not diverted", path),
+            DiversionError::Exists ( path ) => write!(f, "Not renaming over {}, it already exists", path),
+        }
+    }
+}
+
+impl Display for AlternativesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AlternativesError::Malformed { file, why } => write!(f, "Malformed alternatives at {} :: {}", file, why),
+            AlternativesError::NotFound ( name ) => write!(f, "No alternatives for {}", name),
+            AlternativesError::NoSuchChoice { name, path } => write!(f, "{} is not an alternative for {}", path, name),
+            AlternativesError::Missing ( path ) => write!(f, "Alternative path {} doesn't exist", path),
+            AlternativesError::LinkMismatch { name, link, wanted } => write!(f, "The link of {} is {}, not {}", name, link, wanted),
+            AlternativesError::InvalidName ( name ) => write!(f, "{:?} is not a valid alternative name", name),
+        }
+    }
+}
+
+impl Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::Held { path, pid: Some(pid) } => write!(f, "Could not get the lock {}, it is held by process {} (use --wait-lock to wait for it)", path, pid),
+            LockError::Held { path, pid: None } => write!(f, "Could not get the lock {}, it is held by another process (use --wait-lock to wait for it)", path),
+        }
+    }
+}
+
+impl Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatusError::Invalid(status) => write!(f, "Invalid package status {:?}", status),
+        }
+    }
+}
+
+impl Display for PreferencesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PreferencesError::Malformed { file, why } => write!(f, "Malformed preferences at {} :: {}", file, why),
         }
     }
 }
@@ -108,13 +272,20 @@ impl Display for CacheError {
     }
 }
 
+impl Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JournalError::Pending ( journal ) => write!(f, "A previous transaction was interrupted, run `opm recover` first (journal at {:?})", journal),
+        }
+    }
+}
+
 impl Display for ScriptsError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            ScriptsError::PreInst => write!(f, "Could not execute PREINST the script"),
-            ScriptsError::PostInst => write!(f, "Could not execute POSTINST the script"),
-            ScriptsError::PreRm => write!(f, "Could not execute PRERM the script"),
-            ScriptsError::PostRm => write!(f, "Could not execute POSTRM the script"),
+            ScriptsError::Failed { pkg, script, why } => write!(f, "{} {} script subprocess {}", pkg, script, why),
+            ScriptsError::Declined { pkg, script } => write!(f, "Running the {} script of {} was declined", script, pkg),
+            ScriptsError::Unsandboxed { pkg, script } => write!(f, "The {} script of {} can't be sandboxed with the root at /, all of it would stay writable (use --root or another scripts mode)", script, pkg),
         }
     }
 }
\ No newline at end of file
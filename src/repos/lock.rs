@@ -0,0 +1,164 @@
+use anyhow::Result;
+use std::{fs::{self, File}, io, os::unix::io::AsRawFd, path::{Path, PathBuf}, thread, time::{Duration, Instant}};
+
+use super::{config::Config, errors::LockError};
+
+//
+// Keeps two package managers (two opm runs, or opm and dpkg/apt) from changing the system at once.
+// fcntl record locks, as dpkg and apt use, go away by themselves when the process dies
+//
+
+const DPKG_LOCK_FRONTEND: &str = "/var/lib/dpkg/lock-frontend";
+const DPKG_LOCK: &str = "/var/lib/dpkg/lock";
+
+///
+/// How long to keep trying while someone else holds a lock
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wait {
+    No,
+    For(Duration),
+    Forever,
+}
+
+///
+/// Every lock a mutating command needs, released once dropped
+///
+#[derive(Debug)]
+pub struct Lock {
+    _files: Vec<File>,
+}
+
+impl Lock {
+    ///
+    /// `<install_dir>/lock`, then dpkg's frontend and database locks when sharing its database
+    ///
+    pub fn acquire(config: &Config, wait: Wait) -> Result<Self> {
+        let mut paths = vec![config.os_info.install_dir.join("lock")];
+        if config.use_pre_existing_db {
+            paths.extend([PathBuf::from(DPKG_LOCK_FRONTEND), PathBuf::from(DPKG_LOCK)]);
+        }
+
+        let deadline = match wait {
+            Wait::For(duration) => Some(Instant::now() + duration),
+            _ => None,
+        };
+
+        let mut files = vec![];
+        for path in paths.iter() {
+            files.push(lock(path, wait, deadline)?);
+        }
+
+        Ok(Self { _files: files })
+    }
+}
+
+fn flock(kind: libc::c_short) -> libc::flock {
+    // SAFETY: `flock` is plain old data, all zeroes being a valid value
+    let mut flock: libc::flock = unsafe { std::mem::zeroed() };
+    flock.l_type = kind;
+    flock.l_whence = libc::SEEK_SET as libc::c_short;
+    flock
+}
+
+///
+/// A write lock over the whole of `path`
+///
+fn lock(path: &Path, wait: Wait, deadline: Option<Instant>) -> Result<File> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path)?;
+
+    loop {
+        let cmd = if wait == Wait::Forever { libc::F_SETLKW } else { libc::F_SETLK };
+        let wanted = flock(libc::F_WRLCK as libc::c_short);
+
+        // SAFETY: `file` stays open during the call and `wanted` outlives it
+        if unsafe { libc::fcntl(file.as_raw_fd(), cmd, &wanted) } == 0 {
+            return Ok(file);
+        }
+
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EAGAIN) | Some(libc::EACCES) => (),
+            Some(libc::EINTR) => continue,
+            _ => return Err(err.into()),
+        }
+
+        if deadline.is_none_or(|deadline| Instant::now() >= deadline) {
+            anyhow::bail!(LockError::Held { path: path.to_string_lossy().into_owned(), pid: holder(&file) });
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+///
+/// The PID of whoever holds the lock on `file`, if it can be told
+///
+fn holder(file: &File) -> Option<i32> {
+    let mut held = flock(libc::F_WRLCK as libc::c_short);
+
+    // SAFETY: as above, `held` gets filled in by the kernel
+    match unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETLK, &mut held) } {
+        0 if held.l_type != libc::F_UNLCK as libc::c_short && held.l_pid > 0 => Some(held.l_pid),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lock_test() {
+        let dir = std::env::temp_dir().join(format!("opm-lock-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("lock");
+
+        // fcntl locks never conflict within a process, so the holder is a child that only makes
+        // async-signal-safe calls, with everything it needs set up before the fork
+        let file = fs::OpenOptions::new().create(true).truncate(false).write(true).open(&path).unwrap();
+        let wanted = flock(libc::F_WRLCK as libc::c_short);
+        let hold = libc::timespec { tv_sec: 1, tv_nsec: 500_000_000 };
+        let mut pipe = [0; 2];
+
+        // SAFETY: the child sticks to fcntl, write, nanosleep and _exit before it's gone
+        let child = unsafe {
+            assert_eq!(libc::pipe(pipe.as_mut_ptr()), 0);
+            match libc::fork() {
+                0 => {
+                    let locked = libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &wanted) == 0;
+                    libc::write(pipe[1], [locked as u8].as_ptr().cast(), 1);
+                    libc::nanosleep(&hold, std::ptr::null_mut());
+                    libc::_exit(0);
+                },
+                pid => pid,
+            }
+        };
+        assert!(child > 0);
+
+        let mut locked = [0u8];
+        // SAFETY: `locked` has room for the single byte read
+        assert_eq!(unsafe { libc::read(pipe[0], locked.as_mut_ptr().cast(), 1) }, 1);
+        assert_eq!(locked[0], 1);
+
+        let err = lock(&path, Wait::No, None).unwrap_err().to_string();
+        assert!(err.contains(&format!("process {}", child)), "{}", err);
+
+        // Until the holder is done
+        let wait = Duration::from_secs(10);
+        lock(&path, Wait::For(wait), Some(Instant::now() + wait)).unwrap();
+
+        // SAFETY: plain syscalls on what this test owns
+        unsafe {
+            assert_eq!(libc::waitpid(child, std::ptr::null_mut(), 0), child);
+            libc::close(pipe[0]);
+            libc::close(pipe[1]);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -4,14 +4,23 @@ mod deb;
 // General modules
 mod commands;
 
-pub use commands::install::install;
+pub use commands::install::{install, upgrade};
 pub use commands::search::{search, list_installed};
 pub use commands::remove::remove;
+pub use commands::query::{files, owner, policy};
+pub use commands::recover::recover;
+pub use commands::alternatives::alternatives;
+pub use commands::divert::divert;
 pub use commands::update::{clear, update};
 pub use setup::{setup, roll_back};
+pub use deb::conffiles::ConfChoice;
+pub use deb::scripts::ScriptMode;
+pub use deb::alternatives::Action as AlternativesAction;
+pub use deb::diversions::Action as DivertAction;
 pub mod os_fingerprint;
 
 pub mod config;
 pub mod packages;
 pub mod errors;
+pub mod lock;
 pub mod setup;
\ No newline at end of file
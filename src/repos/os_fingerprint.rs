@@ -3,16 +3,16 @@ use std::path::PathBuf;
 
 use super::packages::PackageFormat;
 
-///
-/// Distro fingerprint files
-///
+//
+// Distro fingerprint files
+//
 
 const DEBIAN: &str = "/etc/issue";      // Check if have "Debian GNU/Linux"
 const ARCH: &str = "/etc/arch-release"; // Check if exists
 
-///
-/// Default Installation dir
-///
+//
+// Default Installation dir
+//
 
 const UNIX_INSTALL_DIR: &str = "/opt/opm/";
 // const WIN_INSTALL_DIR: &str = "C:\\OPM";
@@ -43,9 +43,9 @@ pub struct OsInfo {
 
 impl OS {
     fn get_os() -> Result<OS> {
-        if cfg!(linux) || cfg!(unix) {
+        if cfg!(target_os = "linux") || cfg!(unix) {
             Ok(OS::Linux(Distro::get_distro()?))
-        } else if cfg!(macos) {
+        } else if cfg!(target_os = "macos") {
             Ok(OS::Mac)
         } else if cfg!(windows) {
             Ok(OS::Windows)
@@ -1,6 +1,6 @@
-///
-/// Package Formats
-/// 
+//
+// Package Formats
+// 
 
 use anyhow::Result;
 use super::os_fingerprint::{OS, Distro};
@@ -36,13 +36,13 @@ impl PackageFormat {
     }
 }
 
-impl ToString for PackageFormat {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for PackageFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            PackageFormat::Deb => DEB.to_owned(),
-            PackageFormat::Rpm => RPM.to_owned(),
-            PackageFormat::Pkg => PKG.to_owned(),
-            _ => UNKNOWN.to_owned(),
+            PackageFormat::Deb => write!(f, "{}", DEB),
+            PackageFormat::Rpm => write!(f, "{}", RPM),
+            PackageFormat::Pkg => write!(f, "{}", PKG),
+            _ => write!(f, "{}", UNKNOWN),
         }
     }
 }